@@ -0,0 +1,8 @@
+MEMORY
+{
+  /* First flash sector, see ../firmware/src/boot.rs */
+  FLASH : ORIGIN = 0x00000000, LENGTH = 16K
+  RAM   : ORIGIN = 0x20000000, LENGTH = 256K
+}
+
+_stack_start = ORIGIN(RAM) + LENGTH(RAM);
@@ -0,0 +1,122 @@
+#![feature(asm)]
+#![no_std]
+#![no_main]
+
+// Swaps firmware images between the flash slots and starts slot A,
+// see firmware/src/boot.rs for the layout and the boot state.
+
+use cortex_m_rt::entry;
+
+#[path = "../../firmware/src/boot.rs"]
+mod boot;
+#[path = "../../firmware/src/board/eeprom.rs"]
+mod eeprom;
+#[path = "../../firmware/src/board/flash.rs"]
+mod flash;
+#[allow(dead_code)]
+#[path = "../../firmware/src/board/watchdog.rs"]
+mod watchdog;
+
+use boot::{State, SECTOR_SIZE, SLOT_A, SLOT_B, SLOT_SIZE};
+
+/// Watchdog timeout of trial images in system clock cycles, about
+/// 36 s at 120 MHz. The reset follows a second timeout.
+const WATCHDOG_LOAD: u32 = 0xffff_ffff;
+
+/// Nothing to start, or flash and EEPROM failures. Recovery needs
+/// JTAG.
+fn halt() -> ! {
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    halt()
+}
+
+fn load_state() -> State {
+    let mut words = [0; boot::STATE_WORDS];
+    eeprom::read(boot::STATE_BLOCK, 0, &mut words);
+    State::decode(&words)
+}
+
+fn store_state(state: &State) {
+    // A swap could not resume without its state
+    if eeprom::write(boot::STATE_BLOCK, 0, &state.encode()).is_err() {
+        halt();
+    }
+}
+
+/// Erase the sector at `to` and copy the sector at `from`
+fn copy_sector(from: u32, to: u32) {
+    let words = unsafe {
+        core::slice::from_raw_parts(from as *const u32, (SECTOR_SIZE / 4) as usize)
+    };
+    if flash::erase_sector(to).is_err() || flash::program(to, words).is_err() {
+        halt();
+    }
+}
+
+fn crc_matches(address: u32, length: u32, crc: u32) -> bool {
+    let image = flash::read(address, length.min(SLOT_SIZE));
+    boot::crc32(image) == crc && boot::is_image(image)
+}
+
+/// Start the image in slot A, watched on trial
+fn start(trial: bool) -> ! {
+    if !boot::is_image(flash::read(SLOT_A, 8)) {
+        halt();
+    }
+    if trial {
+        watchdog::start(WATCHDOG_LOAD);
+    }
+    unsafe {
+        let scb = &*cortex_m::peripheral::SCB::ptr();
+        scb.vtor.write(SLOT_A);
+        let stack_pointer = *(SLOT_A as *const u32);
+        let reset = *((SLOT_A + 4) as *const u32);
+        asm!("msr msp, $0\n\tbx $1" :: "r"(stack_pointer), "r"(reset) :: "volatile");
+    }
+    halt()
+}
+
+#[entry]
+fn main() -> ! {
+    // Without the boot state slot A may hold a half swapped image
+    if eeprom::init().is_err() {
+        halt();
+    }
+    let mut state = load_state();
+    loop {
+        state = match state {
+            State::Confirmed | State::RolledBack =>
+                start(false),
+            State::Pending { length, crc } if crc_matches(SLOT_B, length, crc) =>
+                State::Swapping { sector: 0, step: 0, rollback: false, length, crc },
+            // Corrupt upload, keep the current image
+            State::Pending { .. } =>
+                State::Confirmed,
+            // The new image was not confirmed before this reset
+            State::Trial =>
+                State::Swapping { sector: 0, step: 0, rollback: true, length: 0, crc: 0 },
+            State::Swapping { sector, step, length, crc, .. } => {
+                let (from, to) = boot::swap_step(sector, step);
+                copy_sector(from, to);
+                match state.after_swap_step() {
+                    State::Trial if crc_matches(SLOT_A, length, crc) => {
+                        store_state(&State::Trial);
+                        start(true);
+                    }
+                    // Damaged while swapping
+                    State::Trial =>
+                        State::Swapping { sector: 0, step: 0, rollback: true, length: 0, crc: 0 },
+                    next =>
+                        next,
+                }
+            }
+        };
+        store_state(&state);
+    }
+}
@@ -3,7 +3,10 @@ extern crate walkdir;
 use std::env;
 use std::io::Write;
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
 
 fn linker_script() {
     // Put the linker script somewhere the linker can find it
@@ -17,6 +20,85 @@ fn linker_script() {
     println!("cargo:rerun-if-changed=memory.x");
 }
 
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+        .map(|output| output.trim().to_owned())
+}
+
+/// `YYYY-MM-DD hh:mm:ss UTC`
+fn format_date(timestamp: u64) -> String {
+    let (days, seconds) = (timestamp / 86400, timestamp % 86400);
+    // Civil date from days since 1970-01-01, in 400-year eras
+    // starting on March 1st
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60
+    )
+}
+
+/// Git commit and build date for the `id` command
+fn build_info() {
+    let commit = match git(&["rev-parse", "--short=8", "HEAD"]) {
+        Some(commit) => {
+            let dirty = Command::new("git")
+                .args(&["diff-index", "--quiet", "HEAD", "--"])
+                .status()
+                .map(|status| !status.success())
+                .unwrap_or(false);
+            if dirty { format!("{}-dirty", commit) } else { commit }
+        }
+        None => "unknown".to_owned(),
+    };
+    println!("cargo:rustc-env=GIT_COMMIT={}", commit);
+
+    // Set for reproducible builds
+    let timestamp = env::var("SOURCE_DATE_EPOCH").ok()
+        .and_then(|timestamp| timestamp.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+        });
+    println!("cargo:rustc-env=BUILD_DATE={}", format_date(timestamp));
+
+    // Update with commits and source changes
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    if let Some(git_dir) = git(&["rev-parse", "--git-dir"]) {
+        let git_dir = Path::new(&git_dir);
+        // A commit changes the branch ref and the reflog, not HEAD
+        let mut files = vec![
+            git_dir.join("HEAD"),
+            git_dir.join("index"),
+            git_dir.join("logs/HEAD"),
+        ];
+        // `refs/heads/<branch>`, or just `HEAD` when detached
+        if let Some(head) = git(&["rev-parse", "--symbolic-full-name", "HEAD"]) {
+            if head.starts_with("refs/") {
+                files.push(git_dir.join(head));
+            }
+        }
+        // Missing files would make cargo rerun this on every build
+        for file in files.iter().filter(|file| file.exists()) {
+            println!("cargo:rerun-if-changed={}", file.display());
+        }
+    }
+    for entry in WalkDir::new("src").into_iter().filter_map(|entry| entry.ok()) {
+        println!("cargo:rerun-if-changed={}", entry.path().display());
+    }
+}
+
 fn main() {
     linker_script();
+    build_info();
 }
@@ -1,6 +1,7 @@
 MEMORY
 {
-  FLASH : ORIGIN = 0x00000000, LENGTH = 512K
+  /* Slot A, after the bootloader, see src/boot.rs */
+  FLASH : ORIGIN = 0x00004000, LENGTH = 240K
   RAM   : ORIGIN = 0x20000000, LENGTH = 256K
 }
 
@@ -4,7 +4,7 @@ use super::checksum::{ChecksumMode, Checksum};
 use super::AdcError;
 use super::{
     regs, regs::RegisterData,
-    Input, RefSource, PostFilter, DigitalFilterOrder,
+    Input, RefSource, PostFilter, DigitalFilterOrder, Sample,
 };
 
 /// AD7172-2 implementation
@@ -14,6 +14,8 @@ pub struct Adc<SPI: Transfer<u8>, NSS: OutputPin> {
     spi: SPI,
     nss: NSS,
     checksum_mode: ChecksumMode,
+    data_stat: bool,
+    nss_hold: bool,
 }
 
 impl<SPI: Transfer<u8>, NSS: OutputPin> Adc<SPI, NSS> {
@@ -22,6 +24,8 @@ impl<SPI: Transfer<u8>, NSS: OutputPin> Adc<SPI, NSS> {
         let mut adc = Adc {
             spi, nss,
             checksum_mode: ChecksumMode::Off,
+            data_stat: false,
+            nss_hold: false,
         };
         adc.reset()?;
 
@@ -50,16 +54,33 @@ impl<SPI: Transfer<u8>, NSS: OutputPin> Adc<SPI, NSS> {
         })
     }
 
+    /// Enable the internal 2.5V reference, required for
+    /// `RefSource::Internal`
+    pub fn set_internal_reference(&mut self, enable: bool) -> Result<(), AdcError<SPI::Error>> {
+        self.update_reg(&regs::AdcMode, |data| {
+            data.set_ref_en(enable);
+        })
+    }
+
     pub fn setup_channel(
         &mut self, index: u8, in_pos: Input, in_neg: Input
+    ) -> Result<(), AdcError<SPI::Error>> {
+        self.setup_channel_with_ref(index, in_pos, in_neg, RefSource::External, false)
+    }
+
+    /// Like `setup_channel()` but with a choice of reference and
+    /// output coding
+    pub fn setup_channel_with_ref(
+        &mut self, index: u8, in_pos: Input, in_neg: Input,
+        ref_source: RefSource, bipolar: bool
     ) -> Result<(), AdcError<SPI::Error>> {
         self.update_reg(&regs::SetupCon { index }, |data| {
-            data.set_bipolar(false);
+            data.set_bipolar(bipolar);
             data.set_refbuf_pos(true);
             data.set_refbuf_neg(true);
             data.set_ainbuf_pos(true);
             data.set_ainbuf_neg(true);
-            data.set_ref_sel(RefSource::External);
+            data.set_ref_sel(ref_source);
         })?;
         self.update_reg(&regs::FiltCon { index }, |data| {
             data.set_enh_filt_en(true);
@@ -78,6 +99,32 @@ impl<SPI: Transfer<u8>, NSS: OutputPin> Adc<SPI, NSS> {
         Ok(())
     }
 
+    /// Switch the reference of a setup
+    pub fn set_ref_source(&mut self, index: u8, ref_source: RefSource) -> Result<(), AdcError<SPI::Error>> {
+        self.update_reg(&regs::SetupCon { index }, |data| {
+            data.set_ref_sel(ref_source);
+        })
+    }
+
+    /// Add a previously set up channel to the sequencer, switching
+    /// its inputs
+    pub fn enable_channel(
+        &mut self, index: u8, in_pos: Input, in_neg: Input
+    ) -> Result<(), AdcError<SPI::Error>> {
+        self.update_reg(&regs::Channel { index }, |data| {
+            data.set_enabled(true);
+            data.set_a_in_pos(in_pos);
+            data.set_a_in_neg(in_neg);
+        })
+    }
+
+    /// Remove a channel from the sequencer
+    pub fn disable_channel(&mut self, index: u8) -> Result<(), AdcError<SPI::Error>> {
+        self.update_reg(&regs::Channel { index }, |data| {
+            data.set_enabled(false);
+        })
+    }
+
     pub fn get_postfilter(&mut self, index: u8) -> Result<Option<PostFilter>, AdcError<SPI::Error>> {
         self.read_reg(&regs::FiltCon { index })
             .map(|data| {
@@ -113,10 +160,63 @@ impl<SPI: Transfer<u8>, NSS: OutputPin> Adc<SPI, NSS> {
             })
     }
 
-    /// Get data
-    pub fn read_data(&mut self) -> Result<i32, AdcError<SPI::Error>> {
-        self.read_reg(&regs::Data)
-            .map(|data| data.data())
+    /// Append the status register to data register reads so
+    /// that the channel and error flags are read together with
+    /// the conversion result.
+    pub fn set_data_stat(&mut self, enable: bool) -> Result<(), AdcError<SPI::Error>> {
+        self.update_reg(&regs::IfMode, |data| {
+            data.set_data_stat(enable);
+        })?;
+        self.data_stat = enable;
+        Ok(())
+    }
+
+    /// Read the next conversion result, if any
+    ///
+    /// Takes a single transaction if `set_data_stat()` is enabled.
+    pub fn read_sample(&mut self) -> Result<Option<Sample>, AdcError<SPI::Error>> {
+        // The data register is read with the status in DATA_STAT
+        // mode, after it otherwise
+        let (status, data) = if self.data_stat {
+            let data_status = self.read_reg(&regs::DataStatus)?;
+            (data_status.status(), Some(data_status.data()))
+        } else {
+            (self.read_reg(&regs::Status)?, None)
+        };
+        if !status.ready() {
+            return Ok(None);
+        }
+        let data = match data {
+            Some(data) => data,
+            None => self.read_reg(&regs::Data)?,
+        };
+        Ok(Some(Sample {
+            channel: status.channel(),
+            data: data.data(),
+            raw: data.raw(),
+            status,
+        }))
+    }
+
+    /// Keep NSS asserted between transactions so that DOUT/RDY
+    /// signals when a conversion is ready
+    pub fn set_nss_hold(&mut self, hold: bool) {
+        self.nss_hold = hold;
+        if hold {
+            let _ = self.nss.set_low();
+        } else {
+            let _ = self.nss.set_high();
+        }
+    }
+
+    /// Deassert NSS, which resets the serial interface after a
+    /// corrupted transaction. NSS is asserted again with
+    /// `set_nss_hold()`.
+    pub fn resync(&mut self) {
+        let _ = self.nss.set_high();
+        if self.nss_hold {
+            let _ = self.nss.set_low();
+        }
     }
 
     fn read_reg<R: regs::Register>(&mut self, reg: &R) -> Result<R::Data, AdcError<SPI::Error>> {
@@ -168,7 +268,9 @@ impl<SPI: Transfer<u8>, NSS: OutputPin> Adc<SPI, NSS> {
         let mut buf = [0xFFu8; 8];
         let _ = self.nss.set_low();
         let result = self.spi.transfer(&mut buf);
-        let _ = self.nss.set_high();
+        if !self.nss_hold {
+            let _ = self.nss.set_high();
+        }
         result?;
         Ok(())
     }
@@ -194,7 +296,9 @@ impl<SPI: Transfer<u8>, NSS: OutputPin> Adc<SPI, NSS> {
             (Err(e), _) =>
                 Err(e),
         };
-        let _ = self.nss.set_high();
+        if !self.nss_hold {
+            let _ = self.nss.set_high();
+        }
 
         result
     }
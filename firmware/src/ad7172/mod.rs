@@ -18,6 +18,48 @@ impl<SPI> From<SPI> for AdcError<SPI> {
     }
 }
 
+/// Conversion result
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+    channel: u8,
+    data: i32,
+    raw: u32,
+    /// Status register read with the data
+    status: regs::status::Data,
+}
+
+impl Sample {
+    /// Channel slot that was converted
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    /// Signed conversion result
+    pub fn data(&self) -> i32 {
+        self.data
+    }
+
+    /// Unsigned 24-bit conversion result
+    pub fn raw(&self) -> u32 {
+        self.raw
+    }
+
+    /// Overrange, underrange or modulator error
+    pub fn adc_error(&self) -> bool {
+        self.status.adc_error()
+    }
+
+    /// SPI CRC error
+    pub fn crc_error(&self) -> bool {
+        self.status.crc_error()
+    }
+
+    /// Register contents changed since configuration
+    pub fn reg_error(&self) -> bool {
+        self.status.reg_error()
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 #[repr(u8)]
 pub enum Input {
@@ -24,8 +24,9 @@ macro_rules! def_reg {
                 $addr
             }
         }
-        mod $reg {
+        pub mod $reg {
             /// Register contents
+            #[derive(Clone, Copy, Debug)]
             pub struct Data(pub [u8; $size]);
             impl super::RegisterData for Data {
                 /// Generate zeroed register contents
@@ -47,7 +48,8 @@ macro_rules! def_reg {
                 $addr + self.index
             }
         }
-        mod $reg {
+        pub mod $reg {
+            #[derive(Clone, Copy, Debug)]
             pub struct Data(pub [u8; $size]);
             impl super::RegisterData for Data {
                 fn empty() -> Self {
@@ -138,13 +140,30 @@ impl status::Data {
     reg_bit!(reg_error, 0,4, "Register error");
 }
 
+def_reg!(AdcMode, adc_mode, 0x01, 2);
+impl adc_mode::Data {
+    reg_bit!(ref_en, set_ref_en, 0, 7, "Enable internal 2.5V reference");
+    reg_bit!(sing_cyc, set_sing_cyc, 0, 5, "Single cycle conversion");
+    reg_bits!(delay, set_delay, 0, 0..=2, "Delay after channel switch");
+    reg_bits!(mode, set_mode, 1, 4..=6, "Operating mode");
+    reg_bits!(clocksel, set_clocksel, 1, 2..=3, "Clock source");
+}
+
 def_reg!(IfMode, if_mode, 0x02, 2);
 impl if_mode::Data {
     reg_bits!(crc, set_crc, 1, 2..=3, ChecksumMode, "SPI checksum mode");
+    reg_bit!(data_stat, set_data_stat, 1, 6, "Append status register to data register reads");
 }
 
 def_reg!(Data, data, 0x04, 3);
 impl data::Data {
+    /// Unsigned 24-bit conversion result
+    pub fn raw(&self) -> u32 {
+        (u32::from(self.0[0]) << 16) |
+        (u32::from(self.0[1]) << 8) |
+        u32::from(self.0[2])
+    }
+
     pub fn data(&self) -> i32 {
         let raw =
             (u32::from(self.0[0]) << 16) |
@@ -158,6 +177,20 @@ impl data::Data {
     }
 }
 
+// Data register read with `IfMode::data_stat` enabled
+def_reg!(DataStatus, data_status, 0x04, 4);
+impl data_status::Data {
+    /// Data register contents
+    pub fn data(&self) -> data::Data {
+        data::Data([self.0[0], self.0[1], self.0[2]])
+    }
+
+    /// Appended status register contents
+    pub fn status(&self) -> status::Data {
+        status::Data([self.0[3]])
+    }
+}
+
 def_reg!(GpioCon, gpio_con, 0x06, 2);
 impl gpio_con::Data {
     reg_bit!(sync_en, set_sync_en, 0, 3, "Enables the SYNC/ERROR pin as a sync input");
@@ -0,0 +1,69 @@
+use cortex_m::asm::delay;
+use tm4c129x;
+
+// EEDONE
+const DONE_WORKING: u32 = 1 << 0;
+// EESUPP
+const SUPP_PRETRY: u32 = 1 << 3;
+const SUPP_ERETRY: u32 = 1 << 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// Previous program or erase did not complete
+    Retry,
+    /// Write failed
+    Write(u32),
+}
+
+fn wait_done() -> u32 {
+    let eeprom = unsafe { &*tm4c129x::EEPROM::ptr() };
+    loop {
+        let done = eeprom.eedone.read().bits();
+        if done & DONE_WORKING == 0 {
+            return done;
+        }
+    }
+}
+
+/// Power up the EEPROM module. Call once after `board::init()`.
+pub fn init() -> Result<(), Error> {
+    let sysctl = unsafe { &*tm4c129x::SYSCTL::ptr() };
+    let eeprom = unsafe { &*tm4c129x::EEPROM::ptr() };
+
+    sysctl.rcgceeprom.modify(|_, w| w.r0().bit(true));
+    // At least 6 cycles before accessing registers
+    delay(16);
+    while !sysctl.preeprom.read().r0().bit() {}
+    wait_done();
+
+    let supp = eeprom.eesupp.read().bits();
+    if supp & (SUPP_PRETRY | SUPP_ERETRY) != 0 {
+        return Err(Error::Retry);
+    }
+    Ok(())
+}
+
+/// Read consecutive words starting at `offset` in `block`
+pub fn read(block: u32, offset: u32, buf: &mut [u32]) {
+    let eeprom = unsafe { &*tm4c129x::EEPROM::ptr() };
+    eeprom.eeblock.write(|w| unsafe { w.bits(block) });
+    eeprom.eeoffset.write(|w| unsafe { w.bits(offset) });
+    for word in buf.iter_mut() {
+        *word = eeprom.eerdwrinc.read().bits();
+    }
+}
+
+/// Write consecutive words starting at `offset` in `block`
+pub fn write(block: u32, offset: u32, data: &[u32]) -> Result<(), Error> {
+    let eeprom = unsafe { &*tm4c129x::EEPROM::ptr() };
+    eeprom.eeblock.write(|w| unsafe { w.bits(block) });
+    eeprom.eeoffset.write(|w| unsafe { w.bits(offset) });
+    for word in data {
+        eeprom.eerdwrinc.write(|w| unsafe { w.bits(*word) });
+        let done = wait_done();
+        if done != 0 {
+            return Err(Error::Write(done));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use tm4c129x;
+
+// FMC
+const FMC_WRITE: u32 = 1 << 0;
+const FMC_ERASE: u32 = 1 << 1;
+// BOOTCFG
+const BOOTCFG_KEY: u32 = 1 << 4;
+// FCRIS, FCMISC
+const FCRIS_ACCESS: u32 = 1 << 0;
+const FCRIS_VOLTAGE: u32 = 1 << 9;
+const FCRIS_INVALID_DATA: u32 = 1 << 10;
+const FCRIS_ERASE: u32 = 1 << 11;
+const FCRIS_PROGRAM: u32 = 1 << 13;
+const FCRIS_ERRORS: u32 = FCRIS_ACCESS | FCRIS_VOLTAGE | FCRIS_INVALID_DATA |
+    FCRIS_ERASE | FCRIS_PROGRAM;
+// FLASHCONF
+const FLASHCONF_CLRTV: u32 = 1 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// FCRIS error flags
+    Erase(u32),
+    Program(u32),
+}
+
+/// Memory write key of FMC
+fn key() -> u32 {
+    let flashctl = unsafe { &*tm4c129x::FLASH_CTRL::ptr() };
+    if flashctl.bootcfg.read().bits() & BOOTCFG_KEY != 0 {
+        0xa442 << 16
+    } else {
+        0x71d5 << 16
+    }
+}
+
+/// Run a flash operation, returns its error flags
+fn execute(command: u32) -> u32 {
+    let flashctl = unsafe { &*tm4c129x::FLASH_CTRL::ptr() };
+    flashctl.fcmisc.write(|w| unsafe { w.bits(FCRIS_ERRORS) });
+    flashctl.fmc.write(|w| unsafe { w.bits(key() | command) });
+    while flashctl.fmc.read().bits() & command != 0 {}
+    // Drop stale prefetch buffer contents
+    flashctl.flashconf.modify(|r, w| unsafe { w.bits(r.bits() | FLASHCONF_CLRTV) });
+    flashctl.fcris.read().bits() & FCRIS_ERRORS
+}
+
+/// Erase the 16 KB sector at `address`
+pub fn erase_sector(address: u32) -> Result<(), Error> {
+    let flashctl = unsafe { &*tm4c129x::FLASH_CTRL::ptr() };
+    flashctl.fma.write(|w| unsafe { w.bits(address) });
+    match execute(FMC_ERASE) {
+        0 => Ok(()),
+        errors => Err(Error::Erase(errors)),
+    }
+}
+
+/// Program words starting at `address` in an erased sector
+pub fn program(address: u32, words: &[u32]) -> Result<(), Error> {
+    let flashctl = unsafe { &*tm4c129x::FLASH_CTRL::ptr() };
+    for (i, word) in words.iter().enumerate() {
+        // Erased already
+        if *word == !0 {
+            continue;
+        }
+        flashctl.fma.write(|w| unsafe { w.bits(address + 4 * i as u32) });
+        flashctl.fmd.write(|w| unsafe { w.bits(*word) });
+        match execute(FMC_WRITE) {
+            0 => {}
+            errors => return Err(Error::Program(errors)),
+        }
+    }
+    Ok(())
+}
+
+/// Flash contents at `address`
+pub fn read(address: u32, len: u32) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(address as *const u8, len as usize) }
+}
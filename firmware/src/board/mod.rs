@@ -2,7 +2,13 @@ use cortex_m;
 use tm4c129x;
 
 pub mod gpio;
+pub mod eeprom;
+pub mod flash;
+pub mod watchdog;
+#[cfg(not(feature = "hardware-spi"))]
 pub mod softspi;
+#[cfg(feature = "hardware-spi")]
+pub mod ssi;
 pub mod systick;
 pub mod pwm;
 
@@ -73,6 +79,23 @@ pub fn init() {
         uart_0.lcrh.write(|w| w.wlen()._8().fen().bit(true));
         uart_0.ctl.write(|w| w.rxe().bit(true).txe().bit(true).uarten().bit(true));
 
+        // Set up SSI1 for the ADC: PB5=CLK, PE4=XDAT0 (MOSI),
+        // PE5=XDAT1 (MISO). PB4 (CSn) remains a GPIO.
+        #[cfg(feature = "hardware-spi")]
+        {
+            let gpio_b = unsafe { &*tm4c129x::GPIO_PORTB_AHB::ptr() };
+            gpio_b.den.modify(|r, w| w.den().bits(r.den().bits() | (1 << 5)));
+            gpio_b.afsel.modify(|r, w| w.afsel().bits(r.afsel().bits() | (1 << 5)));
+            gpio_b.pctl.modify(|_, w| unsafe { w.pmc5().bits(15) });
+            let gpio_e = unsafe { &*tm4c129x::GPIO_PORTE_AHB::ptr() };
+            gpio_e.den.modify(|r, w| w.den().bits(r.den().bits() | 0b11_0000));
+            gpio_e.afsel.modify(|r, w| w.afsel().bits(r.afsel().bits() | 0b11_0000));
+            gpio_e.pctl.modify(|_, w| unsafe { w.pmc4().bits(15).pmc5().bits(15) });
+
+            sysctl.rcgcssi.modify(|_, w| w.r1().bit(true));
+            while !sysctl.prssi.read().r1().bit() {}
+        }
+
         // Set up PWMs
         let gpio_m = unsafe { &*tm4c129x::GPIO_PORTM::ptr() };
         // Output
@@ -144,3 +167,22 @@ pub fn get_mac_address() -> [u8; 6] {
     [userreg0 as u8, (userreg0 >> 8) as u8, (userreg0 >> 16) as u8,
      userreg1 as u8, (userreg1 >> 8) as u8, (userreg1 >> 16) as u8]
 }
+
+/// SYSCTL UNIQUEID0..3, not in the register definitions
+const UNIQUEID0: usize = 0x400f_ef20;
+
+/// 128-bit device identifier, UNIQUEID0 first
+pub fn get_serial() -> [u32; 4] {
+    let mut serial = [0; 4];
+    for (i, word) in serial.iter_mut().enumerate() {
+        *word = unsafe { core::ptr::read_volatile((UNIQUEID0 + 4 * i) as *const u32) };
+    }
+    serial
+}
+
+/// System reset through the NVIC
+pub fn reset() -> ! {
+    // VECTKEY, SYSRESETREQ
+    unsafe { (*cortex_m::peripheral::SCB::ptr()).aircr.write(0x05fa_0004) };
+    loop {}
+}
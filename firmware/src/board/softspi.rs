@@ -111,13 +111,13 @@ impl<SCK: OutputPin, MOSI: OutputPin, MISO: InputPin> FullDuplex<u8> for SoftSpi
     }
 }
 
-pub struct SyncSoftSpi<'d, SCK: OutputPin, MOSI: OutputPin, MISO: InputPin, D: FnMut()> {
+pub struct SyncSoftSpi<SCK: OutputPin, MOSI: OutputPin, MISO: InputPin, D: FnMut()> {
     spi: SoftSpi<SCK, MOSI, MISO>,
-    delay: &'d mut D,
+    delay: D,
 }
 
-impl<'d, SCK: OutputPin, MOSI: OutputPin, MISO: InputPin, D: FnMut()> SyncSoftSpi<'d, SCK, MOSI, MISO, D> {
-    pub fn new(spi: SoftSpi<SCK, MOSI, MISO>, delay: &'d mut D) -> Self {
+impl<SCK: OutputPin, MOSI: OutputPin, MISO: InputPin, D: FnMut()> SyncSoftSpi<SCK, MOSI, MISO, D> {
+    pub fn new(spi: SoftSpi<SCK, MOSI, MISO>, delay: D) -> Self {
         SyncSoftSpi { spi, delay }
     }
 
@@ -129,13 +129,13 @@ impl<'d, SCK: OutputPin, MOSI: OutputPin, MISO: InputPin, D: FnMut()> SyncSoftSp
             match f(&mut self.spi) {
                 Ok(r) => return Ok(r),
                 Err(nb::Error::Other(e)) => return Err(e),
-                Err(WouldBlock) => self.spi.run(self.delay),
+                Err(WouldBlock) => self.spi.run(&mut self.delay),
             }
         }
     }
 }
 
-impl<'d, SCK: OutputPin, MOSI: OutputPin, MISO: InputPin, D: FnMut()> Transfer<u8> for SyncSoftSpi<'d, SCK, MOSI, MISO, D> {
+impl<SCK: OutputPin, MOSI: OutputPin, MISO: InputPin, D: FnMut()> Transfer<u8> for SyncSoftSpi<SCK, MOSI, MISO, D> {
     // TODO: proper type
     type Error = ();
     fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
@@ -0,0 +1,62 @@
+use embedded_hal::blocking::spi::Transfer;
+use tm4c129x;
+
+/// SSI peripheral clock: sysclk / (CPSDVSR * (1 + SCR))
+const CPSDVSR: u32 = 2;
+/// 120 MHz / (2 * (1 + 11)) = 5 MHz
+const SCR: u32 = 11;
+
+// SSICR0
+const CR0_DSS_8BIT: u32 = 0x7;
+const CR0_SPO: u32 = 1 << 6;
+const CR0_SPH: u32 = 1 << 7;
+const CR0_SCR_SHIFT: u32 = 8;
+// SSICR1
+const CR1_SSE: u32 = 1 << 1;
+// SSISR
+const SR_TNF: u32 = 1 << 1;
+const SR_RNE: u32 = 1 << 2;
+
+/// Mode3 SPI master on SSI1
+///
+/// Pins are muxed in `board::init()`. Chip select is left to the
+/// user as a GPIO.
+pub struct Ssi1;
+
+impl Ssi1 {
+    pub fn new() -> Self {
+        let ssi = unsafe { &*tm4c129x::SSI1::ptr() };
+        // Disable while configuring, master mode
+        ssi.cr1.write(|w| unsafe { w.bits(0) });
+        // Clock source: sysclk
+        ssi.cc.write(|w| unsafe { w.bits(0) });
+        ssi.cpsr.write(|w| unsafe { w.bits(CPSDVSR) });
+        // FRF=0: Freescale SPI frame format
+        ssi.cr0.write(|w| unsafe {
+            w.bits((SCR << CR0_SCR_SHIFT) | CR0_SPH | CR0_SPO | CR0_DSS_8BIT)
+        });
+        ssi.cr1.write(|w| unsafe { w.bits(CR1_SSE) });
+
+        // Drain stale input
+        while ssi.sr.read().bits() & SR_RNE != 0 {
+            let _ = ssi.dr.read();
+        }
+        Ssi1
+    }
+}
+
+impl Transfer<u8> for Ssi1 {
+    // Same as `SyncSoftSpi` so that `ad7172::Adc` errors are the
+    // same with either backend
+    type Error = ();
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        let ssi = unsafe { &*tm4c129x::SSI1::ptr() };
+        for b in words.iter_mut() {
+            while ssi.sr.read().bits() & SR_TNF == 0 {}
+            ssi.dr.write(|w| unsafe { w.bits(u32::from(*b)) });
+            while ssi.sr.read().bits() & SR_RNE == 0 {}
+            *b = ssi.dr.read().bits() as u8;
+        }
+        Ok(words)
+    }
+}
@@ -0,0 +1,41 @@
+use tm4c129x;
+
+const UNLOCK: u32 = 0x1acc_e551;
+// WDTCTL
+const CTL_INTEN: u32 = 1 << 0;
+const CTL_RESEN: u32 = 1 << 1;
+// RCGCWD
+const WATCHDOG0: u32 = 1 << 0;
+
+/// Reset on the second timeout of `load` system clock cycles. Once
+/// started, the watchdog keeps running until the next reset.
+pub fn start(load: u32) {
+    let sysctl = unsafe { &*tm4c129x::SYSCTL::ptr() };
+    let watchdog = unsafe { &*tm4c129x::WATCHDOG0::ptr() };
+
+    sysctl.rcgcwd.modify(|r, w| unsafe { w.bits(r.bits() | WATCHDOG0) });
+    while sysctl.prwd.read().bits() & WATCHDOG0 == 0 {}
+
+    watchdog.lock.write(|w| unsafe { w.bits(UNLOCK) });
+    watchdog.load.write(|w| unsafe { w.bits(load) });
+    watchdog.ctl.write(|w| unsafe { w.bits(CTL_INTEN | CTL_RESEN) });
+    // Any other value locks
+    watchdog.lock.write(|w| unsafe { w.bits(0) });
+}
+
+pub fn is_running() -> bool {
+    let sysctl = unsafe { &*tm4c129x::SYSCTL::ptr() };
+    sysctl.rcgcwd.read().bits() & WATCHDOG0 != 0
+}
+
+/// Restart the timeout if the watchdog is running
+pub fn feed() {
+    if !is_running() {
+        return;
+    }
+    let watchdog = unsafe { &*tm4c129x::WATCHDOG0::ptr() };
+    watchdog.lock.write(|w| unsafe { w.bits(UNLOCK) });
+    // Clears the timeout interrupt and reloads
+    watchdog.icr.write(|w| unsafe { w.bits(0) });
+    watchdog.lock.write(|w| unsafe { w.bits(0) });
+}
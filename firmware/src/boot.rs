@@ -0,0 +1,230 @@
+// Flash layout and boot state, shared with the bootloader
+//
+// The bootloader occupies the first flash sector and starts the
+// image in slot A. Updates are written to slot B. On the next boot
+// the bootloader swaps the slots sector by sector through the
+// scratch sector and starts the new image on trial, with the
+// watchdog running. An image that has not been confirmed by the
+// next reset is swapped back.
+//
+// The boot state lives in an EEPROM block so that a swap resumes
+// where it stopped after a power loss.
+
+use core::fmt;
+
+pub const SECTOR_SIZE: u32 = 0x4000;
+pub const SLOT_A: u32 = 0x0000_4000;
+pub const SLOT_B: u32 = 0x0004_0000;
+pub const SLOT_SIZE: u32 = 0x0003_c000;
+pub const SLOT_SECTORS: u32 = SLOT_SIZE / SECTOR_SIZE;
+pub const SCRATCH: u32 = 0x0007_c000;
+
+const RAM_START: u32 = 0x2000_0000;
+const RAM_END: u32 = 0x2004_0000;
+
+/// EEPROM block of the boot state
+pub const STATE_BLOCK: u32 = 2;
+const STATE_MAGIC: u32 = 0x746f_6f62;
+/// Magic, kind and the fields of `State::Swapping`
+pub const STATE_WORDS: usize = 7;
+
+/// Steps of swapping one sector
+pub const SWAP_STEPS: u32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    /// Slot A holds a confirmed image
+    Confirmed,
+    /// Slot B holds an uploaded image, to be swapped in
+    Pending { length: u32, crc: u32 },
+    /// Step `step` of sector `sector` is next. `length` and `crc`
+    /// are those of the image swapped in, unless rolling back.
+    Swapping { sector: u32, step: u32, rollback: bool, length: u32, crc: u32 },
+    /// Slot A holds a new image that has not confirmed itself
+    Trial,
+    /// A trial image was not confirmed and was swapped back
+    RolledBack,
+}
+
+impl State {
+    pub fn decode(words: &[u32; STATE_WORDS]) -> Self {
+        if words[0] != STATE_MAGIC {
+            return State::Confirmed;
+        }
+        match words[1] {
+            1 => State::Pending { length: words[2], crc: words[3] },
+            2 => State::Swapping {
+                sector: words[2],
+                step: words[3],
+                rollback: words[4] != 0,
+                length: words[5],
+                crc: words[6],
+            },
+            3 => State::Trial,
+            4 => State::RolledBack,
+            _ => State::Confirmed,
+        }
+    }
+
+    pub fn encode(&self) -> [u32; STATE_WORDS] {
+        let mut words = [0; STATE_WORDS];
+        words[0] = STATE_MAGIC;
+        match *self {
+            State::Confirmed => {}
+            State::Pending { length, crc } => {
+                words[1] = 1;
+                words[2] = length;
+                words[3] = crc;
+            }
+            State::Swapping { sector, step, rollback, length, crc } => {
+                words[1] = 2;
+                words[2] = sector;
+                words[3] = step;
+                words[4] = rollback as u32;
+                words[5] = length;
+                words[6] = crc;
+            }
+            State::Trial => words[1] = 3,
+            State::RolledBack => words[1] = 4,
+        }
+        words
+    }
+
+    /// State after the next swap step, `Trial` or `RolledBack` after
+    /// the last one
+    pub fn after_swap_step(&self) -> Self {
+        match *self {
+            State::Swapping { sector, step, rollback, length, crc } => {
+                let (sector, step) = if step + 1 < SWAP_STEPS {
+                    (sector, step + 1)
+                } else {
+                    (sector + 1, 0)
+                };
+                if sector < SLOT_SECTORS {
+                    State::Swapping { sector, step, rollback, length, crc }
+                } else if rollback {
+                    State::RolledBack
+                } else {
+                    State::Trial
+                }
+            }
+            state => state,
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            State::Confirmed =>
+                write!(fmt, "confirmed"),
+            State::Pending { length, .. } =>
+                write!(fmt, "pending ({} bytes in slot B)", length),
+            State::Swapping { sector, .. } =>
+                write!(fmt, "swapping (sector {})", sector),
+            State::Trial =>
+                write!(fmt, "trial"),
+            State::RolledBack =>
+                write!(fmt, "rolled back"),
+        }
+    }
+}
+
+/// Sector to erase and copy to in step `step` of swapping `sector`:
+/// A to scratch, B to A, scratch to B
+pub fn swap_step(sector: u32, step: u32) -> (u32, u32) {
+    let offset = sector * SECTOR_SIZE;
+    match step {
+        0 => (SLOT_A + offset, SCRATCH),
+        1 => (SLOT_B + offset, SLOT_A + offset),
+        _ => (SCRATCH, SLOT_B + offset),
+    }
+}
+
+/// Initial stack pointer in RAM and reset vector in slot A
+pub fn is_image(image: &[u8]) -> bool {
+    if image.len() < 8 {
+        return false;
+    }
+    let word = |i: usize| {
+        u32::from(image[i]) | u32::from(image[i + 1]) << 8 |
+            u32::from(image[i + 2]) << 16 | u32::from(image[i + 3]) << 24
+    };
+    let (stack_pointer, reset) = (word(0), word(4));
+    stack_pointer > RAM_START && stack_pointer <= RAM_END &&
+        reset >= SLOT_A && reset < SLOT_A + SLOT_SIZE
+}
+
+/// CRC-32 (IEEE 802.3) as computed by zlib
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn encode_decode() {
+        let states = [
+            State::Confirmed,
+            State::Pending { length: 1234, crc: 0xdead_beef },
+            State::Swapping { sector: 3, step: 2, rollback: true, length: 0, crc: 0 },
+            State::Trial,
+            State::RolledBack,
+        ];
+        for state in states.iter() {
+            assert_eq!(State::decode(&state.encode()), *state);
+        }
+        // Erased EEPROM
+        assert_eq!(State::decode(&[!0; STATE_WORDS]), State::Confirmed);
+    }
+
+    #[test]
+    fn swap_sequence() {
+        let mut state = State::Swapping { sector: 0, step: 0, rollback: false, length: 8, crc: 1 };
+        let mut steps = 0;
+        while let State::Swapping { .. } = state {
+            state = state.after_swap_step();
+            steps += 1;
+        }
+        assert_eq!(steps, SLOT_SECTORS * SWAP_STEPS);
+        assert_eq!(state, State::Trial);
+
+        let state = State::Swapping { sector: SLOT_SECTORS - 1, step: 2, rollback: true, length: 0, crc: 0 };
+        assert_eq!(state.after_swap_step(), State::RolledBack);
+    }
+
+    #[test]
+    fn layout() {
+        assert_eq!(SLOT_A + SLOT_SIZE, SLOT_B);
+        assert_eq!(SLOT_B + SLOT_SIZE, SCRATCH);
+        assert_eq!(swap_step(1, 1), (SLOT_B + SECTOR_SIZE, SLOT_A + SECTOR_SIZE));
+    }
+
+    #[test]
+    fn image_check() {
+        let mut image = [0u8; 8];
+        image[..4].copy_from_slice(&RAM_END.to_le_bytes());
+        image[4..].copy_from_slice(&(SLOT_A + 0x401).to_le_bytes());
+        assert!(is_image(&image));
+        // Linked for address 0
+        image[4..].copy_from_slice(&0x401u32.to_le_bytes());
+        assert!(!is_image(&image));
+        assert!(!is_image(&[0xff; 8]));
+    }
+}
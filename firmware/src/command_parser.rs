@@ -1,15 +1,18 @@
 use core::fmt;
+use core::ops::Range;
 use nom::{
     IResult,
     branch::alt,
     bytes::complete::{is_a, tag, take_while1},
     character::{is_digit, complete::{char, one_of}},
-    combinator::{complete, map, opt, value},
-    sequence::{preceded, separated_pair},
+    combinator::{complete, map, map_opt, map_res, opt, value, verify},
+    sequence::{preceded, separated_pair, terminated, tuple},
     multi::{fold_many0, fold_many1},
     error::ErrorKind,
 };
 use lexical_core as lexical;
+use super::CHANNELS;
+use super::tec::TecPin;
 
 
 #[derive(Clone, Debug, PartialEq)]
@@ -17,7 +20,9 @@ pub enum Error {
     Parser(ErrorKind),
     Incomplete,
     UnexpectedInput(u8),
-    ParseNumber(lexical::Error)
+    ParseNumber(lexical::Error),
+    /// Parameter assigned more than once
+    DuplicateParameter,
 }
 
 impl<'t> From<nom::Err<(&'t [u8], ErrorKind)>> for Error {
@@ -56,21 +61,210 @@ impl fmt::Display for Error {
                 "parsing number: ".fmt(fmt)?;
                 (e as &dyn core::fmt::Debug).fmt(fmt)
             }
+            Error::DuplicateParameter =>
+                "parameter assigned more than once".fmt(fmt),
+        }
+    }
+}
+
+/// Selection of channels: `<channel>`, `<first>-<last>` or `all`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channels {
+    One(usize),
+    /// Inclusive
+    Range(usize, usize),
+    All,
+}
+
+impl Channels {
+    pub fn iter(&self) -> Range<usize> {
+        match *self {
+            Channels::One(channel) => channel..(channel + 1),
+            Channels::Range(first, last) => first..(last + 1),
+            Channels::All => 0..CHANNELS,
+        }
+    }
+
+    pub fn single(&self) -> Option<usize> {
+        match *self {
+            Channels::One(channel) => Some(channel),
+            _ => None,
         }
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ShowCommand {
-    Input,
+    Input(Channels),
     Reporting,
+    ReportSettings,
+    Format,
+    Pwm(Channels),
+    Pid(Channels),
+    SteinhartHart(Channels),
+    PostFilter(Channels),
+    Stats(Channels),
+    Telemetry,
+    Board,
+    Locks,
+    DeviceName,
+    Identity,
+    Update,
+    Modbus,
+}
+
+/// Output format of queries
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            OutputFormat::Text => "text",
+            OutputFormat::Json => "json",
+        }.fmt(fmt)
+    }
+}
+
+/// Format of a `history` download
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistoryFormat {
+    Text,
+    Json,
+    /// Fixed-size records
+    Binary,
+}
+
+/// UDP destination of telemetry packets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Endpoint {
+    pub addr: [u8; 4],
+    pub port: u16,
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(
+            fmt, "{}.{}.{}.{}:{}",
+            self.addr[0], self.addr[1], self.addr[2], self.addr[3], self.port
+        )
+    }
+}
+
+/// Value in a report line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportField {
+    Time,
+    Temperature,
+    Raw,
+    Resistance,
     Pwm,
+    /// Control error, target - temperature
+    Error,
+    /// P, I and D terms and their sum before clamping
     Pid,
-    SteinhartHart,
-    PostFilter,
+    /// Integral or output limit reached
+    Saturation,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+pub const REPORT_FIELDS: usize = 8;
+
+impl ReportField {
+    /// In the order of a report line
+    pub const ALL: [ReportField; REPORT_FIELDS] = [
+        ReportField::Time,
+        ReportField::Temperature,
+        ReportField::Raw,
+        ReportField::Resistance,
+        ReportField::Pwm,
+        ReportField::Error,
+        ReportField::Pid,
+        ReportField::Saturation,
+    ];
+}
+
+impl fmt::Display for ReportField {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            ReportField::Time => "time",
+            ReportField::Raw => "raw",
+            ReportField::Resistance => "resistance",
+            ReportField::Temperature => "temp",
+            ReportField::Pwm => "pwm",
+            ReportField::Error => "error",
+            ReportField::Pid => "pid",
+            ReportField::Saturation => "saturation",
+        }.fmt(fmt)
+    }
+}
+
+/// Set of `ReportField`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReportFields {
+    /// Indexed by `ReportField`
+    bits: u8,
+}
+
+impl ReportFields {
+    pub const fn empty() -> Self {
+        ReportFields { bits: 0 }
+    }
+
+    pub fn with(self, field: ReportField) -> Self {
+        ReportFields { bits: self.bits | (1 << field as u8) }
+    }
+
+    pub fn contains(&self, field: ReportField) -> bool {
+        self.bits & (1 << field as u8) != 0
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = ReportField> + 'a {
+        ReportField::ALL.iter()
+            .cloned()
+            .filter(move |field| self.contains(*field))
+    }
+}
+
+/// Fields of a report line before they were selectable
+impl Default for ReportFields {
+    fn default() -> Self {
+        ReportFields::empty()
+            .with(ReportField::Time)
+            .with(ReportField::Temperature)
+            .with(ReportField::Raw)
+            .with(ReportField::Pwm)
+    }
+}
+
+impl fmt::Display for ReportFields {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        for (i, field) in self.iter().enumerate() {
+            if i > 0 {
+                " ".fmt(fmt)?;
+            }
+            field.fmt(fmt)?;
+        }
+        Ok(())
+    }
+}
+
+/// Per-session settings of `report mode on`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportSetting {
+    Channels(Channels),
+    /// Report every n-th sample
+    Decimation(u16),
+    /// Minimum time between reports of a channel, ms
+    Interval(u16),
+    /// Average over the skipped samples
+    Average(bool),
+    Fields(ReportFields),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PidParameter {
     Target,
     KP,
@@ -82,8 +276,75 @@ pub enum PidParameter {
     IntegralMax,
 }
 
+pub const PID_PARAMETERS: usize = 8;
+
+impl PidParameter {
+    pub const ALL: [PidParameter; PID_PARAMETERS] = [
+        PidParameter::Target,
+        PidParameter::KP,
+        PidParameter::KI,
+        PidParameter::KD,
+        PidParameter::OutputMin,
+        PidParameter::OutputMax,
+        PidParameter::IntegralMin,
+        PidParameter::IntegralMax,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PidParameter::Target => "target",
+            PidParameter::KP => "kp",
+            PidParameter::KI => "ki",
+            PidParameter::KD => "kd",
+            PidParameter::OutputMin => "output_min",
+            PidParameter::OutputMax => "output_max",
+            PidParameter::IntegralMin => "integral_min",
+            PidParameter::IntegralMax => "integral_max",
+        }
+    }
+}
+
+/// PID parameters to be set in one step
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PidAssignments {
+    /// Indexed by `PidParameter`
+    values: [Option<f32>; PID_PARAMETERS],
+}
+
+impl PidAssignments {
+    pub fn new() -> Self {
+        PidAssignments {
+            values: [None; PID_PARAMETERS],
+        }
+    }
+
+    pub fn assign(&mut self, parameter: PidParameter, value: f32) -> Result<(), Error> {
+        let slot = &mut self.values[parameter as usize];
+        if slot.is_some() {
+            return Err(Error::DuplicateParameter);
+        }
+        *slot = Some(value);
+        Ok(())
+    }
+
+    pub fn get(&self, parameter: PidParameter) -> Option<f32> {
+        self.values[parameter as usize]
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (PidParameter, f32)> + 'a {
+        PidParameter::ALL.iter()
+            .filter_map(move |parameter| self.get(*parameter).map(|value| (*parameter, value)))
+    }
+}
+
+impl fmt::Display for PidParameter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        self.name().fmt(fmt)
+    }
+}
+
 /// Steinhart-Hart equation parameter
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ShParameter {
     A,
     B,
@@ -91,19 +352,50 @@ pub enum ShParameter {
     ParallelR,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl fmt::Display for ShParameter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            ShParameter::A => "a",
+            ShParameter::B => "b",
+            ShParameter::C => "c",
+            ShParameter::ParallelR => "parallel_r",
+        }.fmt(fmt)
+    }
+}
+
+/// Parameter read by `<command> <channel> <parameter>?`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Parameter {
+    Pid(PidParameter),
+    SteinhartHart(ShParameter),
+    Pwm(TecPin),
+    PostFilterRate,
+}
+
+impl fmt::Display for Parameter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Parameter::Pid(parameter) => parameter.fmt(fmt),
+            Parameter::SteinhartHart(parameter) => parameter.fmt(fmt),
+            Parameter::Pwm(pin) => pin.fmt(fmt),
+            Parameter::PostFilterRate => "rate".fmt(fmt),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct PwmConfig {
     pub width: u16,
     pub total: u16,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PwmMode {
     Manual(PwmConfig),
     Pid,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PwmSetup {
     ISet(PwmMode),
     MaxIPos(PwmConfig),
@@ -111,29 +403,147 @@ pub enum PwmSetup {
     MaxV(PwmConfig),
 }
 
+/// Maximum password length
+pub const PASSWORD_LEN: usize = 32;
+
+/// Password as entered, not displayed by `Debug`
+#[derive(Clone, PartialEq)]
+pub struct Password {
+    buf: [u8; PASSWORD_LEN],
+    len: usize,
+}
+
+impl Password {
+    pub fn new(input: &[u8]) -> Self {
+        let mut buf = [0; PASSWORD_LEN];
+        buf[..input.len()].copy_from_slice(input);
+        Password { buf, len: input.len() }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        "Password(***)".fmt(fmt)
+    }
+}
+
+/// Maximum device name length
+pub const DEVICE_NAME_LEN: usize = 48;
+
+/// Letters, digits and `-`, usable as a host name
+pub fn is_valid_device_name(name: &[u8]) -> bool {
+    name.len() > 0 && name.len() <= DEVICE_NAME_LEN &&
+        name.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'-') &&
+        name[0] != b'-' && name[name.len() - 1] != b'-'
+}
+
+/// Name announced over mDNS
+#[derive(Clone, Copy, PartialEq)]
+pub struct DeviceName {
+    buf: [u8; DEVICE_NAME_LEN],
+    len: usize,
+}
+
+impl DeviceName {
+    /// `None` unless `is_valid_device_name()`
+    pub fn new(input: &[u8]) -> Option<Self> {
+        if !is_valid_device_name(input) {
+            return None;
+        }
+        let mut buf = [0; DEVICE_NAME_LEN];
+        buf[..input.len()].copy_from_slice(input);
+        Some(DeviceName { buf, len: input.len() })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Validated to be ASCII
+        core::str::from_utf8(self.as_bytes()).unwrap()
+    }
+}
+
+impl fmt::Debug for DeviceName {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        self.as_str().fmt(fmt)
+    }
+}
+
+impl fmt::Display for DeviceName {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        self.as_str().fmt(fmt)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Command {
     Quit,
+    /// Gain read-write access
+    Auth(Password),
+    /// Change the password for read-write access
+    SetPassword(Password),
     Show(ShowCommand),
     Reporting(bool),
-    Pwm {
+    Report(ReportSetting),
+    Format(OutputFormat),
+    /// Read a single parameter
+    Get {
         channel: usize,
+        parameter: Parameter,
+    },
+    Pwm {
+        channels: Channels,
         setup: PwmSetup,
     },
     Pid {
-        channel: usize,
+        channels: Channels,
         parameter: PidParameter,
         value: f32,
     },
+    /// Set several PID parameters at once
+    PidAssign {
+        channels: Channels,
+        assignments: PidAssignments,
+    },
     SteinhartHart {
-        channel: usize,
+        channels: Channels,
         parameter: ShParameter,
         value: f32,
     },
     PostFilter {
-        channel: usize,
+        channels: Channels,
         rate: f32,
     },
+    /// Take exclusive write access to a channel
+    Lock(usize),
+    Unlock(usize),
+    /// Receive configuration change and fault events
+    SubscribeEvents(bool),
+    /// Command reference, optionally for one command
+    Help(Option<&'static str>),
+    /// Restart statistics
+    StatsReset(Channels),
+    /// Stream samples over UDP, or stop
+    Telemetry(Option<Endpoint>),
+    /// Rename the device for mDNS
+    SetDeviceName(DeviceName),
+    /// Keep the firmware that runs on trial after an update
+    Confirm,
+    /// Allow Modbus clients to write registers and coils
+    ModbusWrite(bool),
+    /// Download buffered samples, after time `since`, in `format` or
+    /// the session's output format
+    History {
+        channel: usize,
+        since: Option<u64>,
+        format: Option<HistoryFormat>,
+    },
 }
 
 fn end(input: &[u8]) -> IResult<&[u8], ()> {
@@ -158,6 +568,15 @@ fn unsigned(input: &[u8]) -> IResult<&[u8], Result<u16, Error>> {
         })
 }
 
+fn timestamp(input: &[u8]) -> IResult<&[u8], Result<u64, Error>> {
+    take_while1(is_digit)(input)
+        .map(|(input, digits)| {
+            let result = lexical::parse(digits)
+                .map_err(|e| e.into());
+            (input, result)
+        })
+}
+
 fn float(input: &[u8]) -> IResult<&[u8], Result<f32, Error>> {
     let (input, sign) = opt(is_a("-"))(input)?;
     let negative = sign.is_some();
@@ -175,10 +594,89 @@ fn off_on(input: &[u8]) -> IResult<&[u8], bool> {
 }
 
 fn channel(input: &[u8]) -> IResult<&[u8], usize> {
-    map(one_of("01"), |c| (c as usize) - ('0' as usize))(input)
+    verify(
+        map_res(take_while1(is_digit), |digits| lexical::parse(digits)),
+        |channel: &usize| *channel < CHANNELS
+    )(input)
+}
+
+/// `all` | `<first>-<last>` | `<channel>`
+fn channels(input: &[u8]) -> IResult<&[u8], Channels> {
+    alt((
+        value(Channels::All, tag("all")),
+        map(
+            verify(
+                separated_pair(channel, char('-'), channel),
+                |(first, last)| first < last
+            ),
+            |(first, last)| Channels::Range(first, last)
+        ),
+        map(channel, Channels::One)
+    ))(input)
+}
+
+fn report_field(input: &[u8]) -> IResult<&[u8], ReportField> {
+    alt((
+        value(ReportField::Time, tag("time")),
+        value(ReportField::Raw, tag("raw")),
+        value(ReportField::Resistance, tag("resistance")),
+        value(ReportField::Temperature, tag("temp")),
+        value(ReportField::Pwm, tag("pwm")),
+        value(ReportField::Error, tag("error")),
+        value(ReportField::Pid, tag("pid")),
+        value(ReportField::Saturation, tag("saturation")),
+    ))(input)
+}
+
+/// `report channels|decimation|interval|average|fields ...`
+fn report_setting(input: &[u8]) -> IResult<&[u8], Result<ReportSetting, Error>> {
+    alt((
+        preceded(
+            tag("channels"),
+            preceded(
+                whitespace,
+                map(channels, |channels| Ok(ReportSetting::Channels(channels)))
+            )
+        ),
+        preceded(
+            tag("decimation"),
+            preceded(
+                whitespace,
+                map(
+                    verify(unsigned, |n: &Result<u16, Error>| *n != Ok(0)),
+                    |n| n.map(ReportSetting::Decimation)
+                )
+            )
+        ),
+        preceded(
+            tag("interval"),
+            preceded(
+                whitespace,
+                map(unsigned, |ms| ms.map(ReportSetting::Interval))
+            )
+        ),
+        preceded(
+            tag("average"),
+            preceded(
+                whitespace,
+                map(off_on, |average| Ok(ReportSetting::Average(average)))
+            )
+        ),
+        preceded(
+            tag("fields"),
+            map(
+                fold_many1(
+                    preceded(whitespace, report_field),
+                    ReportFields::empty(),
+                    |fields: ReportFields, field| fields.with(field)
+                ),
+                |fields| Ok(ReportSetting::Fields(fields))
+            )
+        ),
+    ))(input)
 }
 
-fn report(input: &[u8]) -> IResult<&[u8], Command> {
+fn report(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
     preceded(
         tag("report"),
         alt((
@@ -190,14 +688,45 @@ fn report(input: &[u8]) -> IResult<&[u8], Command> {
                         preceded(
                             whitespace,
                             // `report mode <on | off>` - Switch repoting mode
-                            map(off_on, Command::Reporting)
+                            map(off_on, |reporting| Ok(Command::Reporting(reporting)))
                         ),
                         // `report mode` - Show current reporting state
-                        value(Command::Show(ShowCommand::Reporting), end)
+                        value(Ok(Command::Show(ShowCommand::Reporting)), end)
                     ))
                 )),
+            // `report settings` - Show per-session report settings
+            preceded(
+                whitespace,
+                value(Ok(Command::Show(ShowCommand::ReportSettings)), tag("settings"))
+            ),
+            preceded(
+                whitespace,
+                map(report_setting, |setting| setting.map(Command::Report))
+            ),
+            // `report <channel>` - Report one channel once
+            preceded(
+                whitespace,
+                map(terminated(channels, end), |channels| Ok(Command::Show(ShowCommand::Input(channels))))
+            ),
             // `report` - Report once
-            value(Command::Show(ShowCommand::Input), end)
+            value(Ok(Command::Show(ShowCommand::Input(Channels::All))), end)
+        ))
+    )(input)
+}
+
+/// `format` | `format <text | json>`
+fn format(input: &[u8]) -> IResult<&[u8], Command> {
+    preceded(
+        tag("format"),
+        alt((
+            preceded(
+                whitespace,
+                alt((
+                    value(Command::Format(OutputFormat::Text), tag("text")),
+                    value(Command::Format(OutputFormat::Json), tag("json"))
+                ))
+            ),
+            value(Command::Show(ShowCommand::Format), end)
         ))
     )(input)
 }
@@ -256,11 +785,23 @@ fn pwm_setup(input: &[u8]) -> IResult<&[u8], Result<PwmSetup, Error>> {
     ))(input)
 }
 
-/// `pwm <0-1> pid` - Set PWM to be controlled by PID
+/// `pwm <channel> pid` - Set PWM to be controlled by PID
 fn pwm_pid(input: &[u8]) -> IResult<&[u8], Result<PwmSetup, Error>> {
     value(Ok(PwmSetup::ISet(PwmMode::Pid)), tag("pid"))(input)
 }
 
+/// `pwm <channel> <pin>?`
+fn pwm_query(input: &[u8]) -> IResult<&[u8], TecPin> {
+    terminated(
+        alt((value(TecPin::ISet, tag("i_set")),
+             value(TecPin::MaxIPos, tag("max_i_pos")),
+             value(TecPin::MaxINeg, tag("max_i_neg")),
+             value(TecPin::MaxV, tag("max_v"))
+        )),
+        tag("?")
+    )(input)
+}
+
 fn pwm(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
     let (input, _) = tag("pwm")(input)?;
     alt((
@@ -268,42 +809,87 @@ fn pwm(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
             whitespace,
             map(
                 separated_pair(
-                    channel,
+                    channels,
                     whitespace,
                     alt((
                         pwm_pid,
                         pwm_setup
                     ))
                 ),
-                |(channel, setup)| setup.map(|setup| Command::Pwm { channel, setup })
+                |(channels, setup)| setup.map(|setup| Command::Pwm { channels, setup })
+            )
+        ),
+        preceded(
+            whitespace,
+            map(
+                separated_pair(channel, whitespace, pwm_query),
+                |(channel, pin)| Ok(Command::Get { channel, parameter: Parameter::Pwm(pin) })
             )
         ),
-        value(Ok(Command::Show(ShowCommand::Pwm)), end)
+        preceded(
+            whitespace,
+            map(terminated(channels, end), |channels| Ok(Command::Show(ShowCommand::Pwm(channels))))
+        ),
+        value(Ok(Command::Show(ShowCommand::Pwm(Channels::All))), end)
     ))(input)
 }
 
-/// `pid <0-1> <parameter> <value>`
+fn pid_parameter_name(input: &[u8]) -> IResult<&[u8], PidParameter> {
+    alt((value(PidParameter::Target, tag("target")),
+         value(PidParameter::KP, tag("kp")),
+         value(PidParameter::KI, tag("ki")),
+         value(PidParameter::KD, tag("kd")),
+         value(PidParameter::OutputMin, tag("output_min")),
+         value(PidParameter::OutputMax, tag("output_max")),
+         value(PidParameter::IntegralMin, tag("integral_min")),
+         value(PidParameter::IntegralMax, tag("integral_max"))
+    ))(input)
+}
+
+/// `pid <channel> <parameter> <value>` | `pid <channel> <parameter>?`
 fn pid_parameter(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
-    let (input, channel) = channel(input)?;
-    let (input, _) = whitespace(input)?;
-    let (input, parameter) =
-        alt((value(PidParameter::Target, tag("target")),
-             value(PidParameter::KP, tag("kp")),
-             value(PidParameter::KI, tag("ki")),
-             value(PidParameter::KD, tag("kd")),
-             value(PidParameter::OutputMin, tag("output_min")),
-             value(PidParameter::OutputMax, tag("output_max")),
-             value(PidParameter::IntegralMin, tag("integral_min")),
-             value(PidParameter::IntegralMax, tag("integral_max"))
-        ))(input)?;
+    let (input, channels) = channels(input)?;
     let (input, _) = whitespace(input)?;
+    let (input, parameter) = pid_parameter_name(input)?;
+    alt((
+        map_opt(tag("?"), move |_| channels.single().map(|channel| Ok(Command::Get {
+            channel,
+            parameter: Parameter::Pid(parameter),
+        }))),
+        map(
+            preceded(whitespace, float),
+            move |value| value.map(|value| Command::Pid { channels, parameter, value })
+        )
+    ))(input)
+}
+
+/// `<parameter>=<value>`
+fn pid_assignment(input: &[u8]) -> IResult<&[u8], Result<(PidParameter, f32), Error>> {
+    let (input, parameter) = pid_parameter_name(input)?;
+    let (input, _) = char('=')(input)?;
     let (input, value) = float(input)?;
-    let result = value
-        .map(|value| Command::Pid { channel, parameter, value });
+    Ok((input, value.map(|value| (parameter, value))))
+}
+
+/// `pid <channel> <parameter>=<value> ...`
+fn pid_assignments(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, channels) = channels(input)?;
+    let (input, assignments) = fold_many1(
+        preceded(whitespace, pid_assignment),
+        Ok(PidAssignments::new()),
+        |assignments: Result<PidAssignments, Error>, assignment| {
+            let mut assignments = assignments?;
+            let (parameter, value) = assignment?;
+            assignments.assign(parameter, value)?;
+            Ok(assignments)
+        }
+    )(input)?;
+    let result = assignments
+        .map(|assignments| Command::PidAssign { channels, assignments });
     Ok((input, result))
 }
 
-/// `pid` | `pid <pid_parameter>`
+/// `pid` | `pid <channel>` | `pid <pid_parameter>` | `pid <pid_assignments>`
 fn pid(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
     let (input, _) = tag("pid")(input)?;
     alt((
@@ -311,13 +897,21 @@ fn pid(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
             whitespace,
             pid_parameter
         ),
-        value(Ok(Command::Show(ShowCommand::Pid)), end)
+        preceded(
+            whitespace,
+            pid_assignments
+        ),
+        preceded(
+            whitespace,
+            map(terminated(channels, end), |channels| Ok(Command::Show(ShowCommand::Pid(channels))))
+        ),
+        value(Ok(Command::Show(ShowCommand::Pid(Channels::All))), end)
     ))(input)
 }
 
-/// `s-h <0-1> <parameter> <value>`
+/// `s-h <channel> <parameter> <value>` | `s-h <channel> <parameter>?`
 fn steinhart_hart_parameter(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
-    let (input, channel) = channel(input)?;
+    let (input, channels) = channels(input)?;
     let (input, _) = whitespace(input)?;
     let (input, parameter) =
         alt((value(ShParameter::A, tag("a")),
@@ -325,14 +919,19 @@ fn steinhart_hart_parameter(input: &[u8]) -> IResult<&[u8], Result<Command, Erro
              value(ShParameter::C, tag("c")),
              value(ShParameter::ParallelR, tag("parallel_r"))
         ))(input)?;
-    let (input, _) = whitespace(input)?;
-    let (input, value) = float(input)?;
-    let result = value
-        .map(|value| Command::SteinhartHart { channel, parameter, value });
-    Ok((input, result))
+    alt((
+        map_opt(tag("?"), move |_| channels.single().map(|channel| Ok(Command::Get {
+            channel,
+            parameter: Parameter::SteinhartHart(parameter),
+        }))),
+        map(
+            preceded(whitespace, float),
+            move |value| value.map(|value| Command::SteinhartHart { channels, parameter, value })
+        )
+    ))(input)
 }
 
-/// `s-h` | `s-h <steinhart_hart_parameter>`
+/// `s-h` | `s-h <channel>` | `s-h <steinhart_hart_parameter>`
 fn steinhart_hart(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
     let (input, _) = tag("s-h")(input)?;
     alt((
@@ -340,7 +939,11 @@ fn steinhart_hart(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
             whitespace,
             steinhart_hart_parameter
         ),
-        value(Ok(Command::Show(ShowCommand::SteinhartHart)), end)
+        preceded(
+            whitespace,
+            map(terminated(channels, end), |channels| Ok(Command::Show(ShowCommand::SteinhartHart(channels))))
+        ),
+        value(Ok(Command::Show(ShowCommand::SteinhartHart(Channels::All))), end)
     ))(input)
 }
 
@@ -350,33 +953,540 @@ fn postfilter(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
         preceded(
             whitespace,
             |input| {
-                let (input, channel) = channel(input)?;
+                let (input, channels) = channels(input)?;
                 let (input, _) = whitespace(input)?;
                 let (input, _) = tag("rate")(input)?;
-                let (input, _) = whitespace(input)?;
-                let (input, rate) = float(input)?;
-                let result = rate
-                    .map(|rate| Command::PostFilter {
-                        channel, rate,
-                    });
-                Ok((input, result))
+                alt((
+                    map_opt(tag("?"), move |_| channels.single().map(|channel| Ok(Command::Get {
+                        channel,
+                        parameter: Parameter::PostFilterRate,
+                    }))),
+                    map(
+                        preceded(whitespace, float),
+                        move |rate| rate.map(|rate| Command::PostFilter {
+                            channels, rate,
+                        })
+                    )
+                ))(input)
             }
         ),
-        value(Ok(Command::Show(ShowCommand::PostFilter)), end)
-    ))(input)
-}
+        preceded(
+            whitespace,
+            map(terminated(channels, end), |channels| Ok(Command::Show(ShowCommand::PostFilter(channels))))
+        ),
+        value(Ok(Command::Show(ShowCommand::PostFilter(Channels::All))), end)
+    ))(input)
+}
+
+fn password(input: &[u8]) -> IResult<&[u8], Password> {
+    map(
+        verify(
+            take_while1(|c| c != b' ' && c != b'\r' && c != b'\n'),
+            |password: &[u8]| password.len() <= PASSWORD_LEN
+        ),
+        Password::new
+    )(input)
+}
+
+/// `auth` | `auth <password>`, the password may be left out while
+/// none is set
+fn auth(input: &[u8]) -> IResult<&[u8], Command> {
+    preceded(
+        tag("auth"),
+        alt((
+            preceded(whitespace, map(password, Command::Auth)),
+            value(Command::Auth(Password::new(b"")), end)
+        ))
+    )(input)
+}
+
+/// `passwd <password>`
+fn passwd(input: &[u8]) -> IResult<&[u8], Command> {
+    preceded(
+        tag("passwd"),
+        preceded(whitespace, map(password, Command::SetPassword))
+    )(input)
+}
+
+fn octet(input: &[u8]) -> IResult<&[u8], u8> {
+    map_res(take_while1(is_digit), |digits| lexical::parse(digits))(input)
+}
+
+/// `<a>.<b>.<c>.<d>:<port>`
+fn endpoint(input: &[u8]) -> IResult<&[u8], Endpoint> {
+    map(
+        tuple((
+            octet, char('.'), octet, char('.'), octet, char('.'), octet,
+            char(':'),
+            map_res(take_while1(is_digit), |digits| lexical::parse(digits))
+        )),
+        |(a, _, b, _, c, _, d, _, port)| Endpoint { addr: [a, b, c, d], port }
+    )(input)
+}
+
+/// `telemetry` | `telemetry <endpoint>` | `telemetry off`
+fn telemetry(input: &[u8]) -> IResult<&[u8], Command> {
+    preceded(
+        tag("telemetry"),
+        alt((
+            preceded(
+                whitespace,
+                alt((
+                    value(Command::Telemetry(None), tag("off")),
+                    map(endpoint, |endpoint| Command::Telemetry(Some(endpoint)))
+                ))
+            ),
+            value(Command::Show(ShowCommand::Telemetry), end)
+        ))
+    )(input)
+}
+
+/// `modbus` | `modbus write on|off`
+fn modbus(input: &[u8]) -> IResult<&[u8], Command> {
+    preceded(
+        tag("modbus"),
+        alt((
+            preceded(
+                whitespace,
+                preceded(
+                    tag("write"),
+                    preceded(whitespace, map(off_on, Command::ModbusWrite))
+                )
+            ),
+            value(Command::Show(ShowCommand::Modbus), end)
+        ))
+    )(input)
+}
+
+/// `name` | `name <name>`
+fn name(input: &[u8]) -> IResult<&[u8], Command> {
+    preceded(
+        tag("name"),
+        alt((
+            preceded(
+                whitespace,
+                map_opt(
+                    take_while1(|c: u8| c.is_ascii_alphanumeric() || c == b'-'),
+                    |name| DeviceName::new(name).map(Command::SetDeviceName)
+                )
+            ),
+            value(Command::Show(ShowCommand::DeviceName), end)
+        ))
+    )(input)
+}
+
+/// `lock` | `lock <channel>`
+fn lock(input: &[u8]) -> IResult<&[u8], Command> {
+    preceded(
+        tag("lock"),
+        alt((
+            preceded(whitespace, map(channel, Command::Lock)),
+            value(Command::Show(ShowCommand::Locks), end)
+        ))
+    )(input)
+}
+
+/// `unlock <channel>`
+fn unlock(input: &[u8]) -> IResult<&[u8], Command> {
+    preceded(
+        tag("unlock"),
+        preceded(whitespace, map(channel, Command::Unlock))
+    )(input)
+}
+
+/// `subscribe events <on | off>`
+fn subscribe(input: &[u8]) -> IResult<&[u8], Command> {
+    preceded(
+        tag("subscribe"),
+        preceded(
+            whitespace,
+            preceded(
+                tag("events"),
+                preceded(whitespace, map(off_on, Command::SubscribeEvents))
+            )
+        )
+    )(input)
+}
+
+fn help_topic(word: &[u8]) -> Option<&'static str> {
+    SYNTAX.iter()
+        .map(|entry| entry.command())
+        .find(|command| command.as_bytes() == word)
+}
+
+/// `help` | `help <command>`
+fn help(input: &[u8]) -> IResult<&[u8], Command> {
+    preceded(
+        tag("help"),
+        alt((
+            preceded(
+                whitespace,
+                map(
+                    map_opt(take_while1(|c| c != b' '), help_topic),
+                    |topic| Command::Help(Some(topic))
+                )
+            ),
+            value(Command::Help(None), end)
+        ))
+    )(input)
+}
+
+fn history_format(input: &[u8]) -> IResult<&[u8], HistoryFormat> {
+    alt((
+        value(HistoryFormat::Text, tag("text")),
+        value(HistoryFormat::Json, tag("json")),
+        value(HistoryFormat::Binary, tag("binary")),
+    ))(input)
+}
+
+/// `history <channel> [since <time>] [text | json | binary]`
+fn history(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
+    let (input, _) = tag("history")(input)?;
+    let (input, _) = whitespace(input)?;
+    let (input, channel) = channel(input)?;
+    let (input, since) = opt(
+        preceded(
+            preceded(whitespace, tag("since")),
+            preceded(whitespace, timestamp)
+        )
+    )(input)?;
+    let (input, format) = opt(preceded(whitespace, history_format))(input)?;
+    let (input, _) = end(input)?;
+    let result = match since {
+        Some(Err(e)) => Err(e),
+        Some(Ok(since)) => Ok(Command::History { channel, since: Some(since), format }),
+        None => Ok(Command::History { channel, since: None, format }),
+    };
+    Ok((input, result))
+}
+
+/// `stats` | `stats <channels>` | `stats <channels> reset`
+fn stats(input: &[u8]) -> IResult<&[u8], Command> {
+    preceded(
+        tag("stats"),
+        alt((
+            preceded(
+                whitespace,
+                map(
+                    terminated(channels, preceded(whitespace, tag("reset"))),
+                    Command::StatsReset
+                )
+            ),
+            preceded(
+                whitespace,
+                map(terminated(channels, end), |channels| Command::Show(ShowCommand::Stats(channels)))
+            ),
+            value(Command::Show(ShowCommand::Stats(Channels::All)), end)
+        ))
+    )(input)
+}
+
+/// `show board` - Show housekeeping measurements
+/// `show update` - Show the firmware update state
+fn show(input: &[u8]) -> IResult<&[u8], Command> {
+    preceded(
+        tag("show"),
+        preceded(
+            whitespace,
+            alt((
+                value(Command::Show(ShowCommand::Board), tag("board")),
+                value(Command::Show(ShowCommand::Update), tag("update"))
+            ))
+        )
+    )(input)
+}
+
+/// `id` | `version` - Firmware build and hardware identification
+fn id(input: &[u8]) -> IResult<&[u8], Command> {
+    value(
+        Command::Show(ShowCommand::Identity),
+        terminated(alt((tag("id"), tag("version"))), end)
+    )(input)
+}
+
+fn confirm(input: &[u8]) -> IResult<&[u8], Command> {
+    value(Command::Confirm, terminated(tag("confirm"), end))(input)
+}
 
 fn command(input: &[u8]) -> IResult<&[u8], Result<Command, Error>> {
     alt((value(Ok(Command::Quit), tag("quit")),
-         map(report, Ok),
+         report,
+         map(format, Ok),
          pwm,
          pid,
          steinhart_hart,
          postfilter,
+         map(show, Ok),
+         map(auth, Ok),
+         map(passwd, Ok),
+         map(lock, Ok),
+         map(unlock, Ok),
+         map(subscribe, Ok),
+         map(help, Ok),
+         history,
+         map(stats, Ok),
+         map(telemetry, Ok),
+         map(modbus, Ok),
+         map(name, Ok),
+         map(id, Ok),
+         map(confirm, Ok),
     ))(input)
 }
 
+/// Entry of the command reference used by `help` and completion
+pub struct CommandSyntax {
+    /// `a|b` are alternative keywords, `<...>` are arguments
+    pub syntax: &'static str,
+    /// Valid input, checked by the parser tests
+    pub example: &'static str,
+    pub help: &'static str,
+}
+
+impl CommandSyntax {
+    /// First keyword, used as `help` topic
+    pub fn command(&self) -> &'static str {
+        self.syntax.split(' ').next().unwrap()
+    }
+}
+
+/// Entries of a `help` listing that remain to be sent
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HelpStream {
+    topic: Option<&'static str>,
+    next: usize,
+}
+
+impl HelpStream {
+    pub fn new(topic: Option<&'static str>) -> Self {
+        HelpStream { topic, next: 0 }
+    }
+
+    pub fn next_entry(&mut self) -> Option<&'static CommandSyntax> {
+        while let Some(entry) = SYNTAX.get(self.next) {
+            self.next += 1;
+            if self.topic.map(|topic| entry.command() == topic).unwrap_or(true) {
+                return Some(entry);
+            }
+        }
+        None
+    }
+}
+
+macro_rules! syntax {
+    ($syntax: expr, $example: expr, $help: expr) => {
+        CommandSyntax { syntax: $syntax, example: $example, help: $help }
+    };
+}
+
+pub const SYNTAX: &[CommandSyntax] = &[
+    syntax!("quit", "quit",
+            "Close the connection"),
+    syntax!("help", "help",
+            "List all commands"),
+    syntax!("help <command>", "help pid",
+            "Show the syntax of one command"),
+    syntax!("report", "report",
+            "Show current input"),
+    syntax!("report <chs>", "report 1",
+            "Show current input of some channels"),
+    syntax!("report mode", "report mode",
+            "Show current report mode"),
+    syntax!("report mode on|off", "report mode on",
+            "Report every new input"),
+    syntax!("report settings", "report settings",
+            "Show which inputs are reported and how"),
+    syntax!("report channels <chs>", "report channels 0-1",
+            "Report only some channels"),
+    syntax!("report decimation <n>", "report decimation 10",
+            "Report every n-th input of a channel, 1-65535"),
+    syntax!("report interval <ms>", "report interval 1000",
+            "Report a channel at most every <ms> milliseconds, 0 for no limit"),
+    syntax!("report average on|off", "report average on",
+            "Report the average of the inputs since the previous report"),
+    syntax!("report fields <field>...", "report fields time temp pwm",
+            "Select the values in a report line: time, temp, raw, resistance, pwm, error, pid, saturation"),
+    syntax!("format", "format",
+            "Show output format of queries"),
+    syntax!("format text|json", "format json",
+            "Set output format of queries like `pid 0 kp?`"),
+    syntax!("pwm", "pwm",
+            "Show PWM configuration"),
+    syntax!("pwm <chs>", "pwm 1",
+            "Show PWM configuration of some channels"),
+    syntax!("pwm <ch> i_set?|max_i_pos?|max_i_neg?|max_v?", "pwm 0 max_v?",
+            "Show PWM duty cycle of one pin as width/total"),
+    syntax!("pwm <chs> max_i_pos|max_i_neg|max_v <width> <total>", "pwm all max_v 100 1000",
+            "Set PWM duty cycle of a limit to width/total, 0-65535"),
+    syntax!("pwm <chs> <width> <total>", "pwm 0 1 2",
+            "Set PWM duty cycle of i_set manually to width/total, 0-65535"),
+    syntax!("pwm <chs> pid", "pwm 0 pid",
+            "Set PWM of i_set to be controlled by PID"),
+    syntax!("pid", "pid",
+            "Show PID configuration"),
+    syntax!("pid <chs>", "pid 1",
+            "Show PID configuration of some channels"),
+    syntax!("pid <ch> target?|kp?|ki?|kd?|output_min?|output_max?|integral_min?|integral_max?", "pid 0 kp?",
+            "Show one PID parameter"),
+    syntax!("pid <chs> target <value>", "pid 0 target 300",
+            "Set the PID controller target in K"),
+    syntax!("pid <chs> kp|ki|kd <value>", "pid 0-1 kp 32768",
+            "Set proportional, integral or differential gain in PWM width per K"),
+    syntax!("pid <chs> <parameter>=<value>...", "pid 0 kp=1 ki=0.1 kd=0.5",
+            "Set several PID parameters at once, without running the controller in between"),
+    syntax!("pid <chs> output_min|output_max <value>", "pid 0 output_max 65535",
+            "Set output limits, PWM width 0-65535"),
+    syntax!("pid <chs> integral_min|integral_max <value>", "pid 0 integral_min -100.5",
+            "Set integral limits, PWM width"),
+    syntax!("s-h", "s-h",
+            "Show Steinhart-Hart equation parameters"),
+    syntax!("s-h <chs>", "s-h 1",
+            "Show Steinhart-Hart equation parameters of some channels"),
+    syntax!("s-h <ch> a?|b?|c?|parallel_r?", "s-h 1 b?",
+            "Show one Steinhart-Hart equation parameter"),
+    syntax!("s-h <chs> a|b|c <value>", "s-h 1 b 0.0003",
+            "Set Steinhart-Hart equation coefficient"),
+    syntax!("s-h <chs> parallel_r <value>", "s-h 0 parallel_r 5100",
+            "Set parallel resistance of the thermistor"),
+    syntax!("postfilter", "postfilter",
+            "Show postfilter settings"),
+    syntax!("postfilter <chs>", "postfilter 1",
+            "Show postfilter settings of some channels"),
+    syntax!("postfilter <ch> rate?", "postfilter 0 rate?",
+            "Show postfilter output data rate in SPS"),
+    syntax!("postfilter <chs> rate <rate>", "postfilter 0 rate 21",
+            "Set postfilter output data rate in SPS, closest of 16.67, 20, 21.25, 27"),
+    syntax!("show board", "show board",
+            "Show ADC temperature and reference voltage"),
+    syntax!("show update", "show update",
+            "Show the firmware update state: confirmed, pending, trial or rolled back"),
+    syntax!("confirm", "confirm",
+            "Keep the updated firmware that runs on trial"),
+    syntax!("id", "id",
+            "Show firmware version, build, MAC address, serial number, ADC id and uptime"),
+    syntax!("version", "version",
+            "Same as id"),
+    syntax!("auth <password>", "auth secret",
+            "Enable read-write access for this session"),
+    syntax!("auth", "auth",
+            "Enable read-write access while no password is set"),
+    syntax!("passwd <password>", "passwd secret",
+            "Set the password for read-write access, up to 32 characters"),
+    syntax!("lock", "lock",
+            "Show channel locks"),
+    syntax!("lock <ch>", "lock 0",
+            "Reserve a channel for this session"),
+    syntax!("unlock <ch>", "unlock 0",
+            "Release a channel lock"),
+    syntax!("subscribe events on|off", "subscribe events on",
+            "Receive settings change and fault events"),
+    syntax!("stats", "stats",
+            "Show temperature statistics: mean, RMS noise, min/max and Allan deviation"),
+    syntax!("stats <chs>", "stats 0",
+            "Show temperature statistics of some channels"),
+    syntax!("stats <chs> reset", "stats all reset",
+            "Restart temperature statistics"),
+    syntax!("telemetry", "telemetry",
+            "Show the destination of UDP telemetry"),
+    syntax!("telemetry <ip>:<port>", "telemetry 10.255.6.1:2300",
+            "Stream all samples in binary UDP packets to a host"),
+    syntax!("telemetry off", "telemetry off",
+            "Stop UDP telemetry"),
+    syntax!("modbus", "modbus",
+            "Show whether Modbus clients may write"),
+    syntax!("modbus write on|off", "modbus write on",
+            "Allow Modbus clients to write registers and coils, persisted, off by default"),
+    syntax!("name", "name",
+            "Show the device name announced over mDNS"),
+    syntax!("name <name>", "name cryostat-1",
+            "Rename the device, announced as <name>.local, up to 48 letters, digits and -"),
+    syntax!("history <ch>", "history 0",
+            "Download the last samples of a channel"),
+    syntax!("history <ch> since <t>", "history 0 since 1000000",
+            "Download the samples after time <t>, in µs"),
+    syntax!("history <ch> text|json|binary", "history 1 binary",
+            "Download samples in another format than set by `format`, also after `since <t>`"),
+];
+
+fn syntax_token_matches(token: &str, word: &[u8]) -> bool {
+    token.starts_with('<') ||
+        token.split('|').any(|keyword| keyword.as_bytes() == word)
+}
+
+/// Call `f` with every keyword of `SYNTAX` that may complete the
+/// last word of `input`. Keywords may be repeated.
+pub fn completions<F: FnMut(&'static str)>(input: &[u8], mut f: F) {
+    let split = input.iter()
+        .rposition(|c| *c == b' ')
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    let (words, partial) = input.split_at(split);
+    for entry in SYNTAX {
+        let mut tokens = entry.syntax.split(' ');
+        let matches = words.split(|c| *c == b' ')
+            .filter(|word| !word.is_empty())
+            .all(|word| tokens.next()
+                 .map(|token| syntax_token_matches(token, word))
+                 .unwrap_or(false)
+            );
+        match tokens.next() {
+            Some(token) if matches && !token.starts_with('<') => {
+                for keyword in token.split('|') {
+                    if keyword.as_bytes().starts_with(partial) {
+                        f(keyword);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
 impl Command {
+    /// Commands that are permitted in read-only sessions
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            Command::Quit |
+            Command::Auth(_) |
+            Command::Show(_) |
+            Command::Reporting(_) |
+            Command::Report(_) |
+            Command::Format(_) |
+            Command::Get { .. } |
+            Command::SubscribeEvents(_) |
+            Command::Help(_) |
+            Command::History { .. } =>
+                true,
+            _ =>
+                false,
+        }
+    }
+
+    /// Commands that set the PWM or PID of a TEC
+    pub fn needs_tec(&self) -> bool {
+        match self {
+            Command::Pwm { .. } |
+            Command::Pid { .. } |
+            Command::PidAssign { .. } =>
+                true,
+            _ =>
+                false,
+        }
+    }
+
+    /// Channels that are modified by this command
+    pub fn channels(&self) -> Option<Channels> {
+        match self {
+            Command::Pwm { channels, .. } |
+            Command::Pid { channels, .. } |
+            Command::PidAssign { channels, .. } |
+            Command::SteinhartHart { channels, .. } |
+            Command::PostFilter { channels, .. } =>
+                Some(*channels),
+            _ =>
+                None,
+        }
+    }
+
     pub fn parse(input: &[u8]) -> Result<Self, Error> {
         match command(input) {
             Ok((b"", result)) =>
@@ -402,7 +1512,7 @@ mod test {
     #[test]
     fn parse_report() {
         let command = Command::parse(b"report");
-        assert_eq!(command, Ok(Command::Show(ShowCommand::Input)));
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Input(Channels::All))));
     }
 
     #[test]
@@ -423,11 +1533,78 @@ mod test {
         assert_eq!(command, Ok(Command::Reporting(false)));
     }
 
+    #[test]
+    fn parse_name() {
+        let command = Command::parse(b"name cryostat-1");
+        assert_eq!(command, Ok(Command::SetDeviceName(DeviceName::new(b"cryostat-1").unwrap())));
+        assert_eq!(Command::parse(b"name"), Ok(Command::Show(ShowCommand::DeviceName)));
+        assert_eq!(Command::parse(b"name -lab").is_err(), true);
+        assert_eq!(Command::parse(b"name lab.local").is_err(), true);
+    }
+
+    #[test]
+    fn parse_telemetry() {
+        let command = Command::parse(b"telemetry 192.168.1.2:5000");
+        let endpoint = Endpoint { addr: [192, 168, 1, 2], port: 5000 };
+        assert_eq!(command, Ok(Command::Telemetry(Some(endpoint))));
+        assert_eq!(Command::parse(b"telemetry 256.0.0.1:5000").is_err(), true);
+    }
+
+    #[test]
+    fn parse_modbus() {
+        assert_eq!(Command::parse(b"modbus"), Ok(Command::Show(ShowCommand::Modbus)));
+        assert_eq!(Command::parse(b"modbus write on"), Ok(Command::ModbusWrite(true)));
+        assert_eq!(Command::parse(b"modbus write off"), Ok(Command::ModbusWrite(false)));
+        assert_eq!(Command::parse(b"modbus write").is_err(), true);
+        assert!(!Command::ModbusWrite(true).is_read_only());
+    }
+
+    #[test]
+    fn parse_stats_reset() {
+        let command = Command::parse(b"stats 0-1 reset");
+        assert_eq!(command, Ok(Command::StatsReset(Channels::Range(0, 1))));
+    }
+
+    #[test]
+    fn parse_history_since_binary() {
+        let command = Command::parse(b"history 1 since 2000 binary");
+        assert_eq!(command, Ok(Command::History {
+            channel: 1,
+            since: Some(2000),
+            format: Some(HistoryFormat::Binary),
+        }));
+    }
+
+    #[test]
+    fn parse_report_decimation() {
+        let command = Command::parse(b"report decimation 10");
+        assert_eq!(command, Ok(Command::Report(ReportSetting::Decimation(10))));
+    }
+
+    #[test]
+    fn parse_report_decimation_zero() {
+        let command = Command::parse(b"report decimation 0");
+        assert!(command.is_err());
+    }
+
+    #[test]
+    fn parse_report_fields() {
+        let command = Command::parse(b"report fields temp time");
+        let fields = ReportFields::empty()
+            .with(ReportField::Time)
+            .with(ReportField::Temperature);
+        assert_eq!(command, Ok(Command::Report(ReportSetting::Fields(fields))));
+        let mut iter = fields.iter();
+        assert_eq!(iter.next(), Some(ReportField::Time));
+        assert_eq!(iter.next(), Some(ReportField::Temperature));
+        assert_eq!(iter.next(), None);
+    }
+
     #[test]
     fn parse_pwm_manual() {
         let command = Command::parse(b"pwm 1 16383 65535");
         assert_eq!(command, Ok(Command::Pwm {
-            channel: 1,
+            channels: Channels::One(1),
             setup: PwmSetup::ISet(PwmMode::Manual(PwmConfig {
                 width: 16383,
                 total: 65535,
@@ -439,7 +1616,7 @@ mod test {
     fn parse_pwm_pid() {
         let command = Command::parse(b"pwm 0 pid");
         assert_eq!(command, Ok(Command::Pwm {
-            channel: 0,
+            channels: Channels::One(0),
             setup: PwmSetup::ISet(PwmMode::Pid),
         }));
     }
@@ -448,7 +1625,7 @@ mod test {
     fn parse_pwm_max_i_pos() {
         let command = Command::parse(b"pwm 0 max_i_pos 7 13");
         assert_eq!(command, Ok(Command::Pwm {
-            channel: 0,
+            channels: Channels::One(0),
             setup: PwmSetup::MaxIPos(PwmConfig {
                 width: 7,
                 total: 13,
@@ -460,7 +1637,7 @@ mod test {
     fn parse_pwm_max_i_neg() {
         let command = Command::parse(b"pwm 0 max_i_neg 128 65535");
         assert_eq!(command, Ok(Command::Pwm {
-            channel: 0,
+            channels: Channels::One(0),
             setup: PwmSetup::MaxINeg(PwmConfig {
                 width: 128,
                 total: 65535,
@@ -472,7 +1649,7 @@ mod test {
     fn parse_pwm_max_v() {
         let command = Command::parse(b"pwm 0 max_v 32768 65535");
         assert_eq!(command, Ok(Command::Pwm {
-            channel: 0,
+            channels: Channels::One(0),
             setup: PwmSetup::MaxV(PwmConfig {
                 width: 32768,
                 total: 65535,
@@ -483,14 +1660,14 @@ mod test {
     #[test]
     fn parse_pid() {
         let command = Command::parse(b"pid");
-        assert_eq!(command, Ok(Command::Show(ShowCommand::Pid)));
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Pid(Channels::All))));
     }
 
     #[test]
     fn parse_pid_target() {
         let command = Command::parse(b"pid 0 target 36.5");
         assert_eq!(command, Ok(Command::Pid {
-            channel: 0,
+            channels: Channels::One(0),
             parameter: PidParameter::Target,
             value: 36.5,
         }));
@@ -500,7 +1677,7 @@ mod test {
     fn parse_pid_integral_max() {
         let command = Command::parse(b"pid 1 integral_max 2000");
         assert_eq!(command, Ok(Command::Pid {
-            channel: 1,
+            channels: Channels::One(1),
             parameter: PidParameter::IntegralMax,
             value: 2000.0,
         }));
@@ -509,14 +1686,14 @@ mod test {
     #[test]
     fn parse_steinhart_hart() {
         let command = Command::parse(b"s-h");
-        assert_eq!(command, Ok(Command::Show(ShowCommand::SteinhartHart)));
+        assert_eq!(command, Ok(Command::Show(ShowCommand::SteinhartHart(Channels::All))));
     }
 
     #[test]
     fn parse_steinhart_hart_parallel_r() {
         let command = Command::parse(b"s-h 1 parallel_r 23.05");
         assert_eq!(command, Ok(Command::SteinhartHart {
-            channel: 1,
+            channels: Channels::One(1),
             parameter: ShParameter::ParallelR,
             value: 23.05,
         }));
@@ -526,8 +1703,181 @@ mod test {
     fn parse_postfilter_rate() {
         let command = Command::parse(b"postfilter 0 rate 21");
         assert_eq!(command, Ok(Command::PostFilter {
-            channel: 0,
+            channels: Channels::One(0),
             rate: 21.0,
         }));
     }
+
+    #[test]
+    fn parse_confirm() {
+        assert_eq!(Command::parse(b"confirm"), Ok(Command::Confirm));
+        assert!(!Command::Confirm.is_read_only());
+    }
+
+    #[test]
+    fn parse_id() {
+        assert_eq!(Command::parse(b"id"), Ok(Command::Show(ShowCommand::Identity)));
+        assert_eq!(Command::parse(b"version"), Ok(Command::Show(ShowCommand::Identity)));
+    }
+
+    #[test]
+    fn parse_show_board() {
+        let command = Command::parse(b"show board");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Board)));
+        let command = Command::parse(b"show update");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Update)));
+    }
+
+    #[test]
+    fn parse_pid_assign() {
+        let command = Command::parse(b"pid 1 kp=1 kd=-0.5");
+        let mut assignments = PidAssignments::new();
+        assignments.assign(PidParameter::KP, 1.0).unwrap();
+        assignments.assign(PidParameter::KD, -0.5).unwrap();
+        assert_eq!(command, Ok(Command::PidAssign {
+            channels: Channels::One(1),
+            assignments,
+        }));
+    }
+
+    #[test]
+    fn parse_pid_assign_duplicate() {
+        let command = Command::parse(b"pid 1 kp=1 kp=2");
+        assert_eq!(command, Err(Error::DuplicateParameter));
+    }
+
+    #[test]
+    fn parse_pid_all() {
+        let command = Command::parse(b"pid all kp 2");
+        assert_eq!(command, Ok(Command::Pid {
+            channels: Channels::All,
+            parameter: PidParameter::KP,
+            value: 2.0,
+        }));
+    }
+
+    #[test]
+    fn parse_s_h_range() {
+        let command = Command::parse(b"s-h 0-1");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::SteinhartHart(Channels::Range(0, 1)))));
+    }
+
+    #[test]
+    fn parse_get_all() {
+        let command = Command::parse(b"pid all kp?");
+        assert!(command.is_err());
+    }
+
+    #[test]
+    fn parse_pid_channel_out_of_range() {
+        let command = Command::parse(b"pid 10 kp 1");
+        assert!(command.is_err());
+    }
+
+    #[test]
+    fn parse_auth() {
+        let command = Command::parse(b"auth s3cret");
+        assert_eq!(command, Ok(Command::Auth(Password::new(b"s3cret"))));
+        let command = Command::parse(b"auth");
+        assert_eq!(command, Ok(Command::Auth(Password::new(b""))));
+    }
+
+    #[test]
+    fn parse_passwd_too_long() {
+        let command = Command::parse(b"passwd 0123456789abcdef0123456789abcdef0");
+        assert!(command.is_err());
+    }
+
+    #[test]
+    fn parse_lock() {
+        let command = Command::parse(b"lock 1");
+        assert_eq!(command, Ok(Command::Lock(1)));
+    }
+
+    #[test]
+    fn parse_unlock() {
+        let command = Command::parse(b"unlock 0");
+        assert_eq!(command, Ok(Command::Unlock(0)));
+    }
+
+    #[test]
+    fn parse_subscribe_events() {
+        let command = Command::parse(b"subscribe events on");
+        assert_eq!(command, Ok(Command::SubscribeEvents(true)));
+    }
+
+    #[test]
+    fn parse_syntax_examples() {
+        for entry in SYNTAX {
+            assert!(Command::parse(entry.example.as_bytes()).is_ok(), "{}", entry.example);
+            assert!(entry.example.starts_with(entry.command()));
+        }
+    }
+
+    #[test]
+    fn parse_pid_get() {
+        let command = Command::parse(b"pid 0 kp?");
+        assert_eq!(command, Ok(Command::Get {
+            channel: 0,
+            parameter: Parameter::Pid(PidParameter::KP),
+        }));
+    }
+
+    #[test]
+    fn parse_pwm_get() {
+        let command = Command::parse(b"pwm 1 max_v?");
+        assert_eq!(command, Ok(Command::Get {
+            channel: 1,
+            parameter: Parameter::Pwm(TecPin::MaxV),
+        }));
+    }
+
+    #[test]
+    fn parse_pid_channel() {
+        let command = Command::parse(b"pid 1");
+        assert_eq!(command, Ok(Command::Show(ShowCommand::Pid(Channels::One(1)))));
+    }
+
+    #[test]
+    fn parse_format_json() {
+        let command = Command::parse(b"format json");
+        assert_eq!(command, Ok(Command::Format(OutputFormat::Json)));
+    }
+
+    #[test]
+    fn parse_help() {
+        let command = Command::parse(b"help s-h");
+        assert_eq!(command, Ok(Command::Help(Some("s-h"))));
+    }
+
+    #[test]
+    fn help_stream() {
+        let mut stream = HelpStream::new(Some("s-h"));
+        let mut count = 0;
+        while let Some(entry) = stream.next_entry() {
+            assert_eq!(entry.command(), "s-h");
+            count += 1;
+        }
+        assert_eq!(count, SYNTAX.iter().filter(|entry| entry.command() == "s-h").count());
+
+        let mut stream = HelpStream::new(None);
+        assert_eq!(stream.next_entry().map(|entry| entry.syntax), Some(SYNTAX[0].syntax));
+    }
+
+    #[test]
+    fn complete_keyword() {
+        let mut count = 0;
+        completions(b"pid 0 integral_m", |keyword| {
+            assert!(["integral_min", "integral_max", "integral_min?", "integral_max?"].contains(&keyword));
+            count += 1;
+        });
+        assert_eq!(count, 4);
+    }
+
+    #[test]
+    fn complete_argument() {
+        completions(b"pid 0 kp ", |keyword| {
+            panic!("unexpected completion {}", keyword);
+        });
+    }
 }
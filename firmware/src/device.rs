@@ -0,0 +1,158 @@
+// Channel settings and outputs for the network servers
+//
+// The Modbus, SCPI and HTTP servers reach the channels through
+// `Device`, which main implements on the state of the control loop.
+
+use super::command_parser::{Channels, Command, Parameter, PidAssignments, PwmConfig, PwmMode, PwmSetup};
+use super::report::Sample;
+use super::session::ChannelLocks;
+use super::tec::TecPin;
+use super::validation::ValidationError;
+
+/// Lock owner id of Modbus and SCPI clients, never a telnet session
+/// index
+pub const REMOTE_SESSION: usize = usize::max_value();
+
+/// Whether `channels` names a monitor-only channel for a command
+/// that needs a TEC. Such commands are refused, while `all` skips
+/// monitor-only channels.
+pub fn selects_monitor_only<F: Fn(usize) -> bool>(channels: Channels, has_tec: F) -> bool {
+    channels != Channels::All &&
+        channels.iter().any(|channel| !has_tec(channel))
+}
+
+/// Value of a single parameter query
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueryValue {
+    Float(f32),
+    /// PWM width and total
+    Pwm(u16, u16),
+    None,
+}
+
+pub trait Device {
+    /// `false` for monitor-only channels
+    fn has_tec(&self, channel: usize) -> bool;
+    /// Latest input
+    fn report(&self, channel: usize) -> Option<Sample>;
+    fn pid_enabled(&self, channel: usize) -> bool;
+    /// Last conversion had ADC error flags set
+    fn conversion_error(&self, channel: usize) -> bool;
+    fn locks(&self) -> &ChannelLocks;
+    /// Current value of a setting, `QueryValue::None` for the PWM of
+    /// monitor-only channels
+    fn query(&mut self, channel: usize, parameter: Parameter) -> QueryValue;
+    /// Check the values a command would set on all selected
+    /// channels, the same way as for a telnet session
+    fn check(&self, command: &Command) -> Result<(), (Option<usize>, ValidationError)>;
+    /// Apply a checked command, `all` skips monitor-only channels
+    fn apply(&mut self, command: Command);
+    /// PID parameters and `i_set` output after power-on
+    fn power_on(&self) -> (PidAssignments, PwmConfig);
+
+    /// `i_set` setup that hands the output to the PID controller, or
+    /// turns the controller off and holds the last output
+    fn output_setup(&mut self, channel: usize, pid_enabled: bool) -> PwmSetup {
+        let mode = match self.query(channel, Parameter::Pwm(TecPin::ISet)) {
+            QueryValue::Pwm(width, total) if !pid_enabled =>
+                PwmMode::Manual(PwmConfig { width, total }),
+            // Monitor-only channels have no output to hold
+            _ =>
+                PwmMode::Pid,
+        };
+        PwmSetup::ISet(mode)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    /// Channel 0 drives a TEC, channel 1 is monitor-only
+    pub struct TestDevice {
+        pub locks: ChannelLocks,
+        pub pid_enabled: bool,
+        /// `i_set` width and total of channel 0
+        pub output: (u16, u16),
+        /// Last command that was applied
+        pub applied: Option<Command>,
+    }
+
+    impl TestDevice {
+        pub fn new() -> Self {
+            TestDevice {
+                locks: ChannelLocks::new(),
+                pid_enabled: true,
+                output: (100, 200),
+                applied: None,
+            }
+        }
+    }
+
+    impl Device for TestDevice {
+        fn has_tec(&self, channel: usize) -> bool {
+            channel == 0
+        }
+
+        fn report(&self, _: usize) -> Option<Sample> {
+            Some(Sample {
+                time: 1000,
+                raw: 0x12_3456,
+                resistance: 10_000.0,
+                temperature: 300.0,
+                pwm: None,
+                pid: None,
+            })
+        }
+
+        fn pid_enabled(&self, channel: usize) -> bool {
+            self.has_tec(channel) && self.pid_enabled
+        }
+
+        fn conversion_error(&self, _: usize) -> bool {
+            false
+        }
+
+        fn locks(&self) -> &ChannelLocks {
+            &self.locks
+        }
+
+        fn query(&mut self, channel: usize, parameter: Parameter) -> QueryValue {
+            match parameter {
+                Parameter::Pwm(_) if !self.has_tec(channel) =>
+                    QueryValue::None,
+                Parameter::Pwm(_) =>
+                    QueryValue::Pwm(self.output.0, self.output.1),
+                _ =>
+                    QueryValue::Float(1.0),
+            }
+        }
+
+        fn check(&self, _: &Command) -> Result<(), (Option<usize>, ValidationError)> {
+            Ok(())
+        }
+
+        fn apply(&mut self, command: Command) {
+            self.applied = Some(command);
+        }
+
+        fn power_on(&self) -> (PidAssignments, PwmConfig) {
+            (PidAssignments::new(), PwmConfig { width: 0, total: 1 })
+        }
+    }
+
+    #[test]
+    fn monitor_only_selection() {
+        let mut device = TestDevice::new();
+        let has_tec = |channel| channel == 0;
+        assert!(selects_monitor_only(Channels::One(1), has_tec));
+        assert!(selects_monitor_only(Channels::Range(0, 1), has_tec));
+        assert!(!selects_monitor_only(Channels::One(0), has_tec));
+        assert!(!selects_monitor_only(Channels::All, has_tec));
+
+        // Nothing to hold on a monitor-only channel
+        assert_eq!(device.output_setup(1, false), PwmSetup::ISet(PwmMode::Pid));
+        let hold = PwmConfig { width: 100, total: 200 };
+        assert_eq!(device.output_setup(0, false), PwmSetup::ISet(PwmMode::Manual(hold)));
+    }
+}
@@ -0,0 +1,175 @@
+use byteorder::{ByteOrder, LittleEndian};
+use super::command_parser::HistoryFormat;
+use super::ring_buffer::RingBuffer;
+
+/// Records kept per channel
+pub const HISTORY_LEN: usize = 256;
+/// Size of a record in binary form
+pub const RECORD_SIZE: usize = 20;
+
+// Record flags
+pub const FLAG_ADC_ERROR: u8 = 1 << 0;
+pub const FLAG_CRC_ERROR: u8 = 1 << 1;
+pub const FLAG_REG_ERROR: u8 = 1 << 2;
+/// The output is set by the PID controller, not by hand
+pub const FLAG_PID: u8 = 1 << 3;
+
+/// One conversion of a channel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Record {
+    /// µs
+    pub time: u64,
+    pub raw: i32,
+    /// K, NaN after a conversion error
+    pub temperature: f32,
+    /// Output PWM width
+    pub pwm: u16,
+    pub flags: u8,
+}
+
+impl Record {
+    pub fn pwm(&self) -> Option<u16> {
+        if self.flags & FLAG_PID != 0 {
+            Some(self.pwm)
+        } else {
+            None
+        }
+    }
+
+    /// Little-endian time, raw, temperature, pwm, flags and a
+    /// padding byte
+    pub fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut buf = [0; RECORD_SIZE];
+        LittleEndian::write_u64(&mut buf[0..8], self.time);
+        LittleEndian::write_i32(&mut buf[8..12], self.raw);
+        LittleEndian::write_f32(&mut buf[12..16], self.temperature);
+        LittleEndian::write_u16(&mut buf[16..18], self.pwm);
+        buf[18] = self.flags;
+        buf
+    }
+}
+
+/// Latest records of a channel
+///
+/// Records are addressed by a sequence number that counts all
+/// records ever pushed, so that a reader can tell whether a record
+/// has been overwritten in the meantime.
+#[derive(Clone, Copy)]
+pub struct History {
+    records: RingBuffer<[Option<Record>; HISTORY_LEN]>,
+    /// Sequence number of the next record
+    next: u64,
+}
+
+impl History {
+    pub const fn new() -> Self {
+        History {
+            records: RingBuffer::new(),
+            next: 0,
+        }
+    }
+
+    pub fn push(&mut self, record: Record) {
+        self.records.push(record);
+        self.next += 1;
+    }
+
+    /// Sequence number of the oldest record still kept
+    pub fn oldest(&self) -> u64 {
+        self.next - self.records.len() as u64
+    }
+
+    /// Sequence number of the next record to be pushed
+    pub fn next(&self) -> u64 {
+        self.next
+    }
+
+    pub fn get(&self, seq: u64) -> Option<&Record> {
+        if seq >= self.oldest() {
+            self.records.get((seq - self.oldest()) as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Sequence number of the first record after `time`
+    pub fn since(&self, time: u64) -> u64 {
+        (self.oldest()..self.next)
+            .find(|seq| self.get(*seq).unwrap().time > time)
+            .unwrap_or(self.next)
+    }
+}
+
+/// Download in progress, sent as far as the TCP buffer permits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HistoryStream {
+    pub channel: usize,
+    pub format: HistoryFormat,
+    /// Sequence number of the next record to send
+    pub seq: u64,
+    /// Records left to send
+    pub remaining: u64,
+}
+
+impl HistoryStream {
+    /// Records after `since`, or all records
+    pub fn new(history: &History, channel: usize, since: Option<u64>, format: HistoryFormat) -> Self {
+        let seq = since
+            .map(|since| history.since(since))
+            .unwrap_or(history.oldest());
+        HistoryStream {
+            channel,
+            format,
+            seq,
+            remaining: history.next() - seq,
+        }
+    }
+
+    /// Next record to send. If records have been overwritten while
+    /// the stream was held up, newer ones are sent instead so that
+    /// the number of records stays as announced.
+    pub fn next_record(&mut self, history: &History) -> Option<Record> {
+        if self.remaining == 0 {
+            return None;
+        }
+        if self.seq < history.oldest() {
+            self.seq = history.oldest();
+        }
+        let record = *history.get(self.seq)?;
+        self.seq += 1;
+        self.remaining -= 1;
+        Some(record)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn since() {
+        let mut history = History::new();
+        let first = Record { time: 0, raw: 0, temperature: 300.0, pwm: 0, flags: 0 };
+        for time in 0..10 {
+            history.push(Record { time, ..first });
+        }
+        let mut stream = HistoryStream::new(&history, 0, Some(7), HistoryFormat::Text);
+        assert_eq!(stream.remaining, 2);
+        assert_eq!(stream.next_record(&history), Some(Record { time: 8, ..first }));
+        assert_eq!(stream.next_record(&history), Some(Record { time: 9, ..first }));
+        assert_eq!(stream.next_record(&history), None);
+    }
+
+    #[test]
+    fn overwritten() {
+        let mut history = History::new();
+        let first = Record { time: 0, raw: 0, temperature: 300.0, pwm: 0, flags: 0 };
+        for time in 0..HISTORY_LEN as u64 {
+            history.push(Record { time, ..first });
+        }
+        let mut stream = HistoryStream::new(&history, 0, None, HistoryFormat::Binary);
+        assert_eq!(stream.remaining, HISTORY_LEN as u64);
+        history.push(Record { time: HISTORY_LEN as u64, ..first });
+        assert_eq!(stream.next_record(&history), Some(Record { time: 1, ..first }));
+    }
+}
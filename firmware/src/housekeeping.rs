@@ -0,0 +1,281 @@
+use core::fmt;
+use crate::ad7172::{Input, RefSource};
+
+/// ADC channel slot that is reserved for housekeeping
+/// measurements
+pub const ADC_CHANNEL: u8 = 3;
+/// Period between two housekeeping measurements in µs
+const INTERVAL: u64 = 5_000_000;
+/// Delay before a failed measurement is tried again in µs
+const RETRY_DELAY: u64 = 500_000;
+
+/// Internal reference that the diagnostic inputs are measured
+/// against
+const INTERNAL_REF: f32 = 2.5;
+/// Temperature sensor sensitivity in V/K, from the AD7172-2
+/// datasheet
+const TEMPERATURE_SENSITIVITY: f32 = 477e-6;
+/// Maximum relative deviation of the reference from the first
+/// measurement after boot
+const REF_TOLERANCE: f32 = 0.002;
+/// Bipolar zero
+const CODE_ZERO: u32 = 0x80_0000;
+const CODE_MAX: u32 = 0xFF_FFFF;
+
+/// Diagnostic input that the housekeeping channel is connected to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Measurement {
+    /// On-chip temperature sensor
+    Temperature,
+    /// (AVDD1 − AVSS)/5 against the internal reference
+    SupplyInternal,
+    /// (AVDD1 − AVSS)/5 against the external reference
+    SupplyExternal,
+}
+
+impl Measurement {
+    pub fn inputs(&self) -> (Input, Input) {
+        match self {
+            Measurement::Temperature =>
+                (Input::TemperaturePos, Input::TemperatureNeg),
+            Measurement::SupplyInternal | Measurement::SupplyExternal =>
+                (Input::AnalogSupplyPos, Input::AnalogSupplyNeg),
+        }
+    }
+
+    pub fn ref_source(&self) -> RefSource {
+        match self {
+            Measurement::Temperature | Measurement::SupplyInternal =>
+                RefSource::Internal,
+            Measurement::SupplyExternal =>
+                RefSource::External,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RefStatus {
+    /// No measurement yet
+    Unknown,
+    Ok,
+    /// Deviates from the initial measurement by more than
+    /// `REF_TOLERANCE`
+    Drifting,
+    /// The supply cannot be measured against one of the references
+    OutOfRange,
+}
+
+impl fmt::Display for RefStatus {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            RefStatus::Unknown => "unknown",
+            RefStatus::Ok => "ok",
+            RefStatus::Drifting => "drifting",
+            RefStatus::OutOfRange => "out of range",
+        }.fmt(fmt)
+    }
+}
+
+/// Bipolar conversion result relative to the reference
+fn fraction(raw: u32) -> f32 {
+    raw as f32 / CODE_ZERO as f32 - 1.0
+}
+
+/// Low-rate sampling of the ADC's internal temperature sensor and
+/// of the external reference
+///
+/// The housekeeping channel is disabled in the sequencer most of
+/// the time. Every `INTERVAL` it is enabled for a single
+/// conversion, alternating between the temperature sensor and a
+/// pair of supply measurements.
+///
+/// The external reference (3.3 V) exceeds the internal one, so it
+/// cannot be measured on the REF+/REF− inputs directly. Instead the
+/// divided supply is measured against both references right after
+/// each other: the ratio of the two results is the ratio of the
+/// references.
+pub struct Housekeeping {
+    /// Measurement currently enabled in the sequencer
+    pending: Option<Measurement>,
+    next: Measurement,
+    next_time: u64,
+    /// ADC die temperature in °C
+    temperature: Option<f32>,
+    /// Divided supply against the internal reference, relative
+    supply_internal: Option<f32>,
+    /// External reference voltage in V
+    reference: Option<f32>,
+    /// First reference measurement
+    initial_reference: Option<f32>,
+    ref_status: RefStatus,
+}
+
+impl Housekeeping {
+    pub fn new() -> Self {
+        Housekeeping {
+            pending: None,
+            next: Measurement::Temperature,
+            next_time: 0,
+            temperature: None,
+            supply_internal: None,
+            reference: None,
+            initial_reference: None,
+            ref_status: RefStatus::Unknown,
+        }
+    }
+
+    /// Returns the measurement to set up on `ADC_CHANNEL` if one
+    /// is due
+    pub fn poll(&mut self, now: u64) -> Option<Measurement> {
+        if self.pending.is_none() && now >= self.next_time {
+            self.pending = Some(self.next);
+            self.pending
+        } else {
+            None
+        }
+    }
+
+    /// Process an error-free bipolar conversion result from
+    /// `ADC_CHANNEL`
+    ///
+    /// The channel must be disabled afterwards.
+    pub fn feed(&mut self, now: u64, raw: u32) {
+        let measurement = match self.pending.take() {
+            Some(measurement) => measurement,
+            // Spurious sample
+            None => return,
+        };
+        let out_of_range = raw == CODE_MAX || raw <= CODE_ZERO;
+        match measurement {
+            Measurement::Temperature => {
+                let voltage = fraction(raw) * INTERNAL_REF;
+                self.temperature = Some(voltage / TEMPERATURE_SENSITIVITY - 273.15);
+                self.next = Measurement::SupplyInternal;
+                self.next_time = now + INTERVAL / 2;
+            }
+            Measurement::SupplyInternal => {
+                self.supply_internal = if out_of_range { None } else { Some(fraction(raw)) };
+                // Right away, while the supply is the same
+                self.next = Measurement::SupplyExternal;
+                self.next_time = now;
+            }
+            Measurement::SupplyExternal => {
+                match self.supply_internal.take() {
+                    Some(supply_internal) if !out_of_range =>
+                        self.update_reference(INTERNAL_REF * supply_internal / fraction(raw)),
+                    _ => {
+                        self.reference = None;
+                        self.ref_status = RefStatus::OutOfRange;
+                    }
+                }
+                self.next = Measurement::Temperature;
+                self.next_time = now + INTERVAL / 2;
+            }
+        }
+    }
+
+    /// The pending measurement failed, try it again after
+    /// `RETRY_DELAY`
+    pub fn abort(&mut self, now: u64) {
+        if let Some(measurement) = self.pending.take() {
+            // Both supply measurements must be taken together
+            self.next = match measurement {
+                Measurement::SupplyExternal => Measurement::SupplyInternal,
+                measurement => measurement,
+            };
+            self.next_time = now + RETRY_DELAY;
+        }
+    }
+
+    fn update_reference(&mut self, reference: f32) {
+        self.reference = Some(reference);
+        let initial = *self.initial_reference.get_or_insert(reference);
+        let deviation = (reference - initial) / initial;
+        self.ref_status = if deviation > REF_TOLERANCE || deviation < -REF_TOLERANCE {
+            RefStatus::Drifting
+        } else {
+            RefStatus::Ok
+        };
+    }
+
+    /// ADC die temperature in °C
+    pub fn temperature(&self) -> Option<f32> {
+        self.temperature
+    }
+
+    /// External reference voltage in V
+    pub fn reference(&self) -> Option<f32> {
+        self.reference
+    }
+
+    pub fn ref_status(&self) -> RefStatus {
+        self.ref_status
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Bipolar code of `voltage` against `reference`
+    fn code(voltage: f32, reference: f32) -> u32 {
+        ((voltage / reference + 1.0) * CODE_ZERO as f32) as u32
+    }
+
+    #[test]
+    fn temperature() {
+        let mut housekeeping = Housekeeping::new();
+        assert_eq!(housekeeping.poll(0), Some(Measurement::Temperature));
+        // 25 °C
+        housekeeping.feed(0, code(298.15 * TEMPERATURE_SENSITIVITY, INTERNAL_REF));
+        let temperature = housekeeping.temperature().unwrap();
+        assert!((temperature - 25.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn schedule() {
+        let mut housekeeping = Housekeeping::new();
+        assert_eq!(housekeeping.poll(0), Some(Measurement::Temperature));
+        // Until the result is fed
+        assert_eq!(housekeeping.poll(1), None);
+        housekeeping.feed(10, CODE_ZERO + 1);
+        assert_eq!(housekeeping.poll(10 + INTERVAL / 2 - 1), None);
+        assert_eq!(housekeeping.poll(10 + INTERVAL / 2), Some(Measurement::SupplyInternal));
+        housekeeping.feed(20 + INTERVAL / 2, code(1.0, INTERNAL_REF));
+        assert_eq!(housekeeping.poll(20 + INTERVAL / 2), Some(Measurement::SupplyExternal));
+
+        // Retried from the internal supply measurement
+        housekeeping.abort(30 + INTERVAL / 2);
+        assert_eq!(housekeeping.poll(30 + INTERVAL / 2), None);
+        assert_eq!(
+            housekeeping.poll(30 + INTERVAL / 2 + RETRY_DELAY),
+            Some(Measurement::SupplyInternal)
+        );
+    }
+
+    fn measure_reference(housekeeping: &mut Housekeeping, reference: f32) {
+        let now = housekeeping.next_time;
+        assert_eq!(housekeeping.poll(now), Some(Measurement::SupplyInternal));
+        housekeeping.feed(now, code(1.0, INTERNAL_REF));
+        assert_eq!(housekeeping.poll(now), Some(Measurement::SupplyExternal));
+        housekeeping.feed(now, code(1.0, reference));
+        assert_eq!(housekeeping.poll(housekeeping.next_time), Some(Measurement::Temperature));
+        housekeeping.feed(housekeeping.next_time, CODE_ZERO + 1);
+    }
+
+    #[test]
+    fn reference_drift() {
+        let mut housekeeping = Housekeeping::new();
+        assert_eq!(housekeeping.ref_status(), RefStatus::Unknown);
+        housekeeping.poll(0);
+        housekeeping.feed(0, CODE_ZERO + 1);
+
+        measure_reference(&mut housekeeping, 3.3);
+        assert!((housekeeping.reference().unwrap() - 3.3).abs() < 0.001);
+        assert_eq!(housekeeping.ref_status(), RefStatus::Ok);
+        measure_reference(&mut housekeeping, 3.302);
+        assert_eq!(housekeeping.ref_status(), RefStatus::Ok);
+        measure_reference(&mut housekeeping, 3.31);
+        assert_eq!(housekeeping.ref_status(), RefStatus::Drifting);
+    }
+}
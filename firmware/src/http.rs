@@ -0,0 +1,586 @@
+// Minimal HTTP/1.1 server
+//
+// One request per connection: the response is sent with
+// `Connection: close` and without a length, the connection is closed
+// after it. Bodies of `PUT` requests are JSON values.
+
+use core::fmt;
+use lexical_core as lexical;
+use super::command_parser::{Command, Channels, Parameter, PidParameter};
+use super::device::{Device, QueryValue};
+use super::password::Credentials;
+use super::receive_buffer::ReceiveBuffer;
+use super::tec::TecPin;
+use super::CHANNELS;
+
+pub const PORT: u16 = 80;
+/// Request line, headers and body
+pub const REQUEST_LEN: usize = 1024;
+/// Longest `Authorization: Basic` credentials, decoded
+pub const CREDENTIALS_LEN: usize = 64;
+/// Path segments of the longest route
+const MAX_SEGMENTS: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Method {
+    Get,
+    Put,
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Ok = 200,
+    NoContent = 204,
+    BadRequest = 400,
+    Unauthorized = 401,
+    NotFound = 404,
+    MethodNotAllowed = 405,
+    Conflict = 409,
+    PayloadTooLarge = 413,
+    UnprocessableEntity = 422,
+}
+
+impl Status {
+    fn reason(&self) -> &'static str {
+        match self {
+            Status::Ok => "OK",
+            Status::NoContent => "No Content",
+            Status::BadRequest => "Bad Request",
+            Status::Unauthorized => "Unauthorized",
+            Status::NotFound => "Not Found",
+            Status::MethodNotAllowed => "Method Not Allowed",
+            Status::Conflict => "Conflict",
+            Status::PayloadTooLarge => "Payload Too Large",
+            Status::UnprocessableEntity => "Unprocessable Entity",
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "{} {}", *self as u16, self.reason())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Request<'a> {
+    pub method: Method,
+    /// Without query string
+    pub path: &'a [u8],
+    /// Value of the `Authorization` header
+    pub authorization: Option<&'a [u8]>,
+    pub body: &'a [u8],
+}
+
+/// Receive buffer of a connection, collects a complete request
+pub type RequestBuffer = ReceiveBuffer<[u8; REQUEST_LEN]>;
+
+fn find(input: &[u8], needle: &[u8]) -> Option<usize> {
+    input.windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn trim(input: &[u8]) -> &[u8] {
+    let is_text = |c: &u8| !c.is_ascii_whitespace();
+    let start = input.iter().position(is_text).unwrap_or(input.len());
+    let end = input.iter().rposition(is_text).map(|pos| pos + 1).unwrap_or(start);
+    &input[start..end]
+}
+
+impl RequestBuffer {
+    /// The request, once it is complete
+    pub fn request(&self) -> Result<Option<Request>, Status> {
+        let input = self.contents();
+        let header_end = match find(input, b"\r\n\r\n") {
+            Some(pos) => pos,
+            None if self.is_full() => return Err(Status::PayloadTooLarge),
+            None => return Ok(None),
+        };
+        let mut lines = input[..header_end].split(|c| *c == b'\n')
+            .map(trim);
+
+        // `<method> <target> HTTP/1.x`
+        let mut request_line = lines.next()
+            .ok_or(Status::BadRequest)?
+            .split(|c| *c == b' ');
+        let method = match request_line.next() {
+            Some(b"GET") => Method::Get,
+            Some(b"PUT") => Method::Put,
+            Some(_) => Method::Other,
+            None => return Err(Status::BadRequest),
+        };
+        let target = request_line.next().ok_or(Status::BadRequest)?;
+        match request_line.next() {
+            Some(version) if version.starts_with(b"HTTP/1.") => {}
+            _ => return Err(Status::BadRequest),
+        }
+        let path = match target.iter().position(|c| *c == b'?') {
+            Some(pos) => &target[..pos],
+            None => target,
+        };
+        if !path.starts_with(b"/") {
+            return Err(Status::BadRequest);
+        }
+
+        let mut content_length = 0;
+        let mut authorization = None;
+        for line in lines {
+            let colon = line.iter().position(|c| *c == b':')
+                .ok_or(Status::BadRequest)?;
+            let (name, value) = (&line[..colon], trim(&line[colon + 1..]));
+            if name.eq_ignore_ascii_case(b"Content-Length") {
+                content_length = lexical::parse(value)
+                    .map_err(|_| Status::BadRequest)?;
+            } else if name.eq_ignore_ascii_case(b"Authorization") {
+                authorization = Some(value);
+            }
+        }
+
+        let body_start = header_end + 4;
+        // `content_length` comes from the client, compare without
+        // adding to it
+        if content_length > REQUEST_LEN - body_start {
+            return Err(Status::PayloadTooLarge);
+        }
+        if input.len() < body_start + content_length {
+            return Ok(None);
+        }
+        Ok(Some(Request {
+            method,
+            path,
+            authorization,
+            body: &input[body_start..body_start + content_length],
+        }))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Route {
+    /// `/`, HTML
+    StatusPage,
+    /// `/api/channels`
+    Channels,
+    /// `/api/channels/<ch>`
+    Channel(usize),
+    /// `/api/channels/<ch>/target` or `/api/channels/<ch>/pid/<parameter>`
+    Pid(usize, PidParameter),
+    /// `/api/channels/<ch>/pid_enabled`
+    PidEnabled(usize),
+}
+
+impl Route {
+    fn method(&self) -> Method {
+        match self {
+            Route::StatusPage |
+            Route::Channels |
+            Route::Channel(_) =>
+                Method::Get,
+            Route::Pid(..) |
+            Route::PidEnabled(_) =>
+                Method::Put,
+        }
+    }
+}
+
+fn channel(segment: &[u8]) -> Option<usize> {
+    lexical::parse(segment).ok()
+        .filter(|channel| *channel < CHANNELS)
+}
+
+pub fn route(request: &Request) -> Result<Route, Status> {
+    let mut segments: [&[u8]; MAX_SEGMENTS] = [&[][..]; MAX_SEGMENTS];
+    let mut count = 0;
+    for segment in request.path[1..].split(|c| *c == b'/') {
+        if count == MAX_SEGMENTS {
+            return Err(Status::NotFound);
+        }
+        segments[count] = segment;
+        count += 1;
+    }
+
+    let route = match &segments[..count] {
+        [b""] =>
+            Some(Route::StatusPage),
+        [b"api", b"channels"] =>
+            Some(Route::Channels),
+        [b"api", b"channels", ch] =>
+            channel(ch).map(Route::Channel),
+        [b"api", b"channels", ch, b"target"] =>
+            channel(ch).map(|channel| Route::Pid(channel, PidParameter::Target)),
+        [b"api", b"channels", ch, b"pid_enabled"] =>
+            channel(ch).map(Route::PidEnabled),
+        [b"api", b"channels", ch, b"pid", name] => {
+            let parameter = PidParameter::ALL.iter()
+                .find(|parameter| parameter.name().as_bytes() == *name);
+            channel(ch).and_then(|channel| {
+                parameter.map(|parameter| Route::Pid(channel, *parameter))
+            })
+        }
+        _ =>
+            None,
+    }.ok_or(Status::NotFound)?;
+
+    if request.method == route.method() {
+        Ok(route)
+    } else {
+        Err(Status::MethodNotAllowed)
+    }
+}
+
+/// JSON number body
+pub fn number(body: &[u8]) -> Result<f32, Status> {
+    lexical::parse(trim(body))
+        .map_err(|_| Status::BadRequest)
+}
+
+/// JSON boolean body
+pub fn boolean(body: &[u8]) -> Result<bool, Status> {
+    match trim(body) {
+        b"true" => Ok(true),
+        b"false" => Ok(false),
+        _ => Err(Status::BadRequest),
+    }
+}
+
+fn base64_value(c: u8) -> Option<u32> {
+    match c {
+        b'A'..=b'Z' => Some((c - b'A') as u32),
+        b'a'..=b'z' => Some((c - b'a') as u32 + 26),
+        b'0'..=b'9' => Some((c - b'0') as u32 + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+/// Password of `Authorization: Basic <base64 user:password>`, the
+/// user name is ignored
+pub fn basic_password<'b>(authorization: &[u8], buf: &'b mut [u8; CREDENTIALS_LEN]) -> Option<&'b [u8]> {
+    if authorization.len() < 6 || !authorization[..6].eq_ignore_ascii_case(b"Basic ") {
+        return None;
+    }
+    let encoded = trim(&authorization[6..]);
+    let encoded = match encoded.iter().position(|c| *c == b'=') {
+        Some(pos) => &encoded[..pos],
+        None => encoded,
+    };
+    let mut len = 0;
+    for chunk in encoded.chunks(4) {
+        let mut bits = 0;
+        for c in chunk {
+            bits = (bits << 6) | base64_value(*c)?;
+        }
+        // 6 bits per character, whole bytes only
+        let bytes = chunk.len() * 6 / 8;
+        bits >>= chunk.len() * 6 - bytes * 8;
+        if bytes == 0 || len + bytes > CREDENTIALS_LEN {
+            return None;
+        }
+        for i in 0..bytes {
+            buf[len + i] = (bits >> (8 * (bytes - 1 - i))) as u8;
+        }
+        len += bytes;
+    }
+    let credentials = &buf[..len];
+    let colon = credentials.iter().position(|c| *c == b':')?;
+    Some(&credentials[colon + 1..])
+}
+
+/// Status line and headers
+pub fn write_header<W: fmt::Write>(w: &mut W, status: Status, content_type: &str) -> fmt::Result {
+    write!(w, "HTTP/1.1 {}\r\n", status)?;
+    if status == Status::Unauthorized {
+        write!(w, "WWW-Authenticate: Basic realm=\"thermostat\"\r\n")?;
+    }
+    write!(w, "Content-Type: {}\r\nConnection: close\r\n\r\n", content_type)
+}
+
+/// Error response with a JSON body
+pub fn write_error<W: fmt::Write>(w: &mut W, status: Status, message: fmt::Arguments) -> fmt::Result {
+    write_header(w, status, "application/json")?;
+    write!(w, "{{\"error\":\"")?;
+    w.write_fmt(message)?;
+    writeln!(w, "\"}}")
+}
+
+/// JSON number, `null` when not available
+pub fn write_json_float<W: fmt::Write>(w: &mut W, value: Option<f32>) -> fmt::Result {
+    match value {
+        Some(value) if value.is_finite() => write!(w, "{}", value),
+        _ => write!(w, "null"),
+    }
+}
+
+const STATUS_PAGE_HEAD: &str = "<!DOCTYPE html>
+<html><head><meta charset=\"utf-8\"><meta http-equiv=\"refresh\" content=\"2\">
+<title>Thermostat</title></head>
+<body><h1>Thermostat</h1>
+<table border=\"1\">
+<tr><th>Channel</th><th>Temperature, K</th><th>Target, K</th><th>PID</th><th>Output</th><th>Faults</th></tr>
+";
+const STATUS_PAGE_TAIL: &str = "</table>
+<p>JSON: <a href=\"/api/channels\">/api/channels</a></p>
+</body></html>
+";
+
+/// Status page and REST API of a `Device`
+pub struct HttpDevice<D: Device> {
+    device: D,
+    credentials: Credentials,
+}
+
+impl<D: Device> HttpDevice<D> {
+    pub fn new(device: D, credentials: Credentials) -> Self {
+        HttpDevice { device, credentials }
+    }
+
+    pub fn respond<W: fmt::Write>(&mut self, out: &mut W, request: &Request) -> fmt::Result {
+        let route = match route(request) {
+            Ok(route) => route,
+            Err(status) => return write_error(out, status, format_args!("{}", status)),
+        };
+        match route {
+            Route::StatusPage => {
+                write_header(out, Status::Ok, "text/html; charset=utf-8")?;
+                self.write_status_page(out)
+            }
+            Route::Channels => {
+                write_header(out, Status::Ok, "application/json")?;
+                write!(out, "[")?;
+                for channel in 0..CHANNELS {
+                    if channel > 0 {
+                        write!(out, ",")?;
+                    }
+                    self.write_channel(out, channel)?;
+                }
+                writeln!(out, "]")
+            }
+            Route::Channel(channel) => {
+                write_header(out, Status::Ok, "application/json")?;
+                self.write_channel(out, channel)?;
+                writeln!(out, "")
+            }
+            Route::Pid(channel, parameter) => {
+                let value = match number(request.body) {
+                    Ok(value) => value,
+                    Err(status) => return write_error(out, status, format_args!("expected a number")),
+                };
+                let command = Command::Pid { channels: Channels::One(channel), parameter, value };
+                self.apply(out, request, channel, command)
+            }
+            Route::PidEnabled(channel) => {
+                let enabled = match boolean(request.body) {
+                    Ok(enabled) => enabled,
+                    Err(status) => return write_error(out, status, format_args!("expected true or false")),
+                };
+                let setup = self.device.output_setup(channel, enabled);
+                let command = Command::Pwm { channels: Channels::One(channel), setup };
+                self.apply(out, request, channel, command)
+            }
+        }
+    }
+
+    /// Changes need basic authentication, with any password while
+    /// none is set
+    fn is_authorized(&self, request: &Request) -> bool {
+        let mut buf = [0; CREDENTIALS_LEN];
+        request.authorization
+            .and_then(|authorization| basic_password(authorization, &mut buf))
+            .map(|password| self.credentials.permits(password))
+            .unwrap_or(false)
+    }
+
+    fn apply<W: fmt::Write>(&mut self, out: &mut W, request: &Request, channel: usize, command: Command) -> fmt::Result {
+        if !self.is_authorized(request) {
+            return write_error(out, Status::Unauthorized, format_args!("password required"));
+        }
+        if !self.device.has_tec(channel) {
+            return write_error(out, Status::Conflict, format_args!("channel {}: monitor only, no TEC", channel));
+        }
+        if let Some(owner) = self.device.locks().owner(channel) {
+            return write_error(out, Status::Conflict, format_args!("channel {}: locked by session {}", channel, owner));
+        }
+        if let Err((_, e)) = self.device.check(&command) {
+            return write_error(out, Status::UnprocessableEntity, format_args!("{}", e));
+        }
+        self.device.apply(command);
+        write_header(out, Status::NoContent, "application/json")
+    }
+
+    fn pid_parameter(&mut self, channel: usize, parameter: PidParameter) -> Option<f32> {
+        match self.device.query(channel, Parameter::Pid(parameter)) {
+            QueryValue::Float(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn write_channel<W: fmt::Write>(&mut self, out: &mut W, channel: usize) -> fmt::Result {
+        let report = self.device.report(channel);
+        write!(out, "{{\"channel\":{},\"temperature\":", channel)?;
+        write_json_float(out, report.map(|sample| sample.temperature))?;
+        write!(out, ",\"resistance\":")?;
+        write_json_float(out, report.map(|sample| sample.resistance))?;
+        match report {
+            Some(sample) =>
+                write!(out, ",\"raw\":{},\"time\":{}", sample.raw, sample.time)?,
+            None =>
+                write!(out, ",\"raw\":null,\"time\":null")?,
+        }
+        if let QueryValue::Pwm(width, total) = self.device.query(channel, Parameter::Pwm(TecPin::ISet)) {
+            write!(out, ",\"pid_enabled\":{},\"pid\":{{", self.device.pid_enabled(channel))?;
+            for (i, parameter) in PidParameter::ALL.iter().enumerate() {
+                let separator = if i > 0 { "," } else { "" };
+                write!(out, "{}\"{}\":", separator, parameter)?;
+                write_json_float(out, self.pid_parameter(channel, *parameter))?;
+            }
+            write!(out, "}},\"output\":{{\"width\":{},\"total\":{}}}", width, total)?;
+        }
+        let pid = report.and_then(|sample| sample.pid);
+        write!(
+            out, ",\"faults\":{{\"conversion_error\":{},\"integral_saturated\":{},\"output_saturated\":{}}}",
+            self.device.conversion_error(channel),
+            pid.map(|output| output.integral_saturated).unwrap_or(false),
+            pid.map(|output| output.output_saturated).unwrap_or(false)
+        )?;
+        match self.device.locks().owner(channel) {
+            Some(owner) => write!(out, ",\"locked_by\":{}}}", owner),
+            None => write!(out, ",\"locked_by\":null}}"),
+        }
+    }
+
+    fn write_status_page<W: fmt::Write>(&mut self, out: &mut W) -> fmt::Result {
+        write!(out, "{}", STATUS_PAGE_HEAD)?;
+        for channel in 0..CHANNELS {
+            let report = self.device.report(channel);
+            write!(out, "<tr><td>{}</td><td>", channel)?;
+            match report {
+                Some(sample) if sample.temperature.is_finite() =>
+                    write!(out, "{:.3}", sample.temperature)?,
+                _ =>
+                    write!(out, "-")?,
+            }
+            match self.device.query(channel, Parameter::Pwm(TecPin::ISet)) {
+                QueryValue::Pwm(width, total) => {
+                    let target = self.pid_parameter(channel, PidParameter::Target)
+                        .unwrap_or(core::f32::NAN);
+                    write!(
+                        out, "</td><td>{:.3}</td><td>{}</td><td>{}/{}",
+                        target,
+                        if self.device.pid_enabled(channel) { "on" } else { "off" },
+                        width, total
+                    )?;
+                }
+                _ =>
+                    write!(out, "</td><td>-</td><td>-</td><td>monitor only")?,
+            }
+            write!(out, "</td><td>")?;
+            let pid = report.and_then(|sample| sample.pid);
+            let faults = [
+                (self.device.conversion_error(channel), "conversion error"),
+                (pid.map(|output| output.integral_saturated).unwrap_or(false), "integral saturated"),
+                (pid.map(|output| output.output_saturated).unwrap_or(false), "output saturated"),
+            ];
+            let mut separator = "";
+            for (_, fault) in faults.iter().filter(|(active, _)| *active) {
+                write!(out, "{}{}", separator, fault)?;
+                separator = ", ";
+            }
+            if let Some(owner) = self.device.locks().owner(channel) {
+                write!(out, "{}locked by session {}", separator, owner)?;
+                separator = ", ";
+            }
+            if separator.is_empty() {
+                write!(out, "-")?;
+            }
+            writeln!(out, "</td></tr>")?;
+        }
+        write!(out, "{}", STATUS_PAGE_TAIL)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::test::TestDevice;
+
+    fn receive(input: &[u8]) -> RequestBuffer {
+        let mut buffer = RequestBuffer::new();
+        assert_eq!(buffer.fill(input), input.len());
+        buffer
+    }
+
+    #[test]
+    fn get_channel() {
+        let buffer = receive(b"GET /api/channels/1?x=1 HTTP/1.1\r\nHost: thermostat\r\n\r\n");
+        let request = buffer.request().unwrap().unwrap();
+        assert_eq!(request.path, b"/api/channels/1");
+        assert_eq!(route(&request), Ok(Route::Channel(1)));
+    }
+
+    #[test]
+    fn put_target_incomplete() {
+        let mut buffer = receive(b"PUT /api/channels/0/target HTTP/1.1\r\ncontent-length: 5\r\n\r\n30");
+        assert_eq!(buffer.request(), Ok(None));
+        buffer.fill(b"0.5");
+        let request = buffer.request().unwrap().unwrap();
+        assert_eq!(route(&request), Ok(Route::Pid(0, PidParameter::Target)));
+        assert_eq!(number(request.body), Ok(300.5));
+    }
+
+    #[test]
+    fn content_length_too_large() {
+        let buffer = receive(b"PUT /api/channels/0/target HTTP/1.1\r\ncontent-length: 18446744073709551615\r\n\r\n");
+        assert_eq!(buffer.request(), Err(Status::PayloadTooLarge));
+    }
+
+    #[test]
+    fn wrong_method() {
+        let buffer = receive(b"GET /api/channels/0/pid/kp HTTP/1.1\r\n\r\n");
+        let request = buffer.request().unwrap().unwrap();
+        assert_eq!(route(&request), Err(Status::MethodNotAllowed));
+        let buffer = receive(b"GET /api/channels/3 HTTP/1.1\r\n\r\n");
+        let request = buffer.request().unwrap().unwrap();
+        assert_eq!(route(&request), Err(Status::NotFound));
+    }
+
+    #[test]
+    fn basic_auth() {
+        let mut buf = [0; CREDENTIALS_LEN];
+        // admin:secret
+        let password = basic_password(b"Basic YWRtaW46c2VjcmV0", &mut buf);
+        assert_eq!(password, Some(&b"secret"[..]));
+        // a:bc
+        let password = basic_password(b"Basic YTpiYw==", &mut buf);
+        assert_eq!(password, Some(&b"bc"[..]));
+    }
+
+    fn respond(device: &mut HttpDevice<TestDevice>, input: &[u8]) -> String {
+        let buffer = receive(input);
+        let request = buffer.request().unwrap().unwrap();
+        let mut out = String::new();
+        device.respond(&mut out, &request).unwrap();
+        out
+    }
+
+    #[test]
+    fn monitor_only() {
+        let mut device = HttpDevice::new(TestDevice::new(), Credentials::Unset);
+        let response = respond(&mut device, b"GET /api/channels/1 HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!response.contains("\"pid\""));
+        let response = respond(&mut device, b"GET /api/channels/0 HTTP/1.1\r\n\r\n");
+        assert!(response.contains("\"output\":{\"width\":100,\"total\":200}"));
+        let response = respond(&mut device, b"GET / HTTP/1.1\r\n\r\n");
+        assert!(response.contains("<tr><td>1</td><td>300.000</td><td>-</td><td>-</td><td>monitor only"));
+
+        // a:b
+        let response = respond(
+            &mut device,
+            b"PUT /api/channels/1/pid_enabled HTTP/1.1\r\nAuthorization: Basic YTpi\r\nContent-Length: 5\r\n\r\nfalse"
+        );
+        assert!(response.starts_with("HTTP/1.1 409 Conflict\r\n"));
+        assert!(response.contains("channel 1: monitor only, no TEC"));
+        assert_eq!(device.device.applied, None);
+    }
+}
@@ -5,9 +5,12 @@
 use cortex_m_rt::entry;
 use core::fmt::{self, Write};
 use smoltcp::time::Instant;
-use smoltcp::wire::{IpCidr, IpAddress, EthernetAddress};
+use smoltcp::wire::{IpCidr, IpAddress, IpEndpoint, Ipv4Address, EthernetAddress};
 use smoltcp::iface::{NeighborCache, EthernetInterfaceBuilder};
-use smoltcp::socket::{SocketSet, TcpSocket, TcpSocketBuffer};
+use smoltcp::socket::{
+    SocketSet, TcpSocket, TcpSocketBuffer,
+    UdpSocket, UdpSocketBuffer, UdpPacketMetadata,
+};
 use embedded_hal::digital::v2::OutputPin;
 use cortex_m_semihosting::hio;
 
@@ -42,15 +45,49 @@ use self::board::{
 };
 mod ethmac;
 mod command_parser;
-use command_parser::{Command, ShowCommand, PwmSetup, PwmMode, PwmConfig};
+use command_parser::{
+    Command, ShowCommand, Channels, PwmSetup, PwmMode, PwmConfig,
+    Parameter, PidParameter, ShParameter, OutputFormat,
+    ReportField, ReportFields, HistoryFormat, PidAssignments, DeviceName,
+    HelpStream,
+};
+mod ring_buffer;
+mod receive_buffer;
 mod session;
-use self::session::{Session, SessionOutput};
+use self::session::{Session, SessionOutput, ChannelLocks, LockError, Event, EventQueue, Fault};
 mod ad7172;
 mod pid;
 mod tec;
-use tec::{Tec, TecPin};
+use tec::{Tec, TecControl, TecPin};
 mod steinhart_hart;
 use steinhart_hart as sh;
+mod housekeeping;
+use housekeeping::Housekeeping;
+mod sampling;
+mod settings;
+mod sha256;
+use sha256::sha256;
+mod password;
+use password::Credentials;
+mod validation;
+mod report;
+use report::Sample;
+mod history;
+use history::{History, HistoryStream, Record};
+mod stats;
+use stats::Stats;
+mod telemetry;
+use telemetry::Telemetry;
+mod device;
+use device::QueryValue;
+mod modbus;
+mod scpi;
+use scpi::ScpiSession;
+mod http;
+mod mdns;
+mod boot;
+mod update;
+use validation::ValidationError;
 
 pub struct UART0;
 
@@ -65,8 +102,37 @@ impl fmt::Write for UART0 {
     }
 }
 
+const TELNET_PORT: u16 = 23;
 const TCP_RX_BUFFER_SIZE: usize = 256;
 const TCP_TX_BUFFER_SIZE: usize = 8192;
+/// Free space in the TCP buffer before sending a `history` record
+const HISTORY_RECORD_SPACE: usize = 160;
+/// Free space in the TCP buffer before sending a `help` entry
+const HELP_ENTRY_SPACE: usize = 160;
+/// Telemetry packets queued for sending
+const UDP_TX_PACKETS: usize = 4;
+/// Source port of telemetry packets
+const TELEMETRY_PORT: u16 = 2300;
+/// Free space in the TCP buffer before reading a SCPI line
+const SCPI_RESPONSE_SPACE: usize = 512;
+const SCPI_TX_BUFFER_SIZE: usize = 1024;
+const HTTP_TX_BUFFER_SIZE: usize = 4096;
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Set by build.rs
+const GIT_COMMIT: &str = env!("GIT_COMMIT");
+const BUILD_DATE: &str = env!("BUILD_DATE");
+/// Announced over mDNS as `<device name>._<service>._tcp.local`
+const MDNS_SERVICES: [mdns::Service; 4] = [
+    mdns::Service { name: "_telnet", port: TELNET_PORT },
+    mdns::Service { name: "_http", port: http::PORT },
+    mdns::Service { name: "_modbus", port: modbus::PORT },
+    mdns::Service { name: "_scpi-raw", port: scpi::PORT },
+];
+const MDNS_RX_BUFFER_SIZE: usize = 1024;
+const UPDATE_RX_BUFFER_SIZE: usize = 4096;
+const UPDATE_TX_BUFFER_SIZE: usize = 256;
+/// Time to send the upload response before restarting, µs
+const UPDATE_RESET_DELAY: u64 = 500_000;
 
 
 macro_rules! create_socket_storage {
@@ -107,30 +173,560 @@ const DEFAULT_SH_PARAMETERS: sh::Parameters = sh::Parameters {
     parallel_r: 5_110.0,  // Ohm (TODO: verify)
 };
 
-// TODO: maybe rename to `TECS`?
-/// Number of TEC channels with four PWM channels each
+/// Number of TECs with four PWM channels each
+pub const TECS: usize = 2;
+/// Number of sensor channels
 pub const CHANNELS: usize = 2;
 
-// TODO: maybe rename to `TecState`?
-/// State per TEC channel
-#[derive(Clone)]
+/// Static configuration of a sensor channel
+struct ChannelConfig {
+    /// ADC channel slot, must not be `housekeeping::ADC_CHANNEL`
+    adc_channel: u8,
+    /// Positive and negative ADC input
+    input: (ad7172::Input, ad7172::Input),
+    /// Sensor model
+    sensor: sh::Parameters,
+    /// TEC that is driven by this channel's PID controller,
+    /// `None` for monitor-only channels
+    tec: Option<usize>,
+}
+
+const CHANNEL_TABLE: [ChannelConfig; CHANNELS] = [
+    // SENS0_{P,N}
+    ChannelConfig {
+        adc_channel: 0,
+        input: (ad7172::Input::Ain0, ad7172::Input::Ain1),
+        sensor: DEFAULT_SH_PARAMETERS,
+        tec: Some(0),
+    },
+    // SENS1_{P,N}
+    ChannelConfig {
+        adc_channel: 1,
+        input: (ad7172::Input::Ain2, ad7172::Input::Ain3),
+        sensor: DEFAULT_SH_PARAMETERS,
+        tec: Some(1),
+    },
+];
+
+/// Look up the sensor channel for an ADC channel slot
+fn channel_for_adc(adc_channel: u8) -> Option<usize> {
+    CHANNEL_TABLE.iter()
+        .position(|config| config.adc_channel == adc_channel)
+}
+
+/// State per sensor channel
+#[derive(Clone, Copy)]
 struct ControlState {
-    /// Report data (time, data, temperature)
-    report: Option<(u64, i32, f32, Option<u16>)>,
+    /// Latest input
+    report: Option<Sample>,
     pid_enabled: bool,
     pid: pid::Controller,
     sh: sh::Parameters,
+    /// Last conversion had ADC error flags set
+    conversion_error: bool,
+}
+
+fn set_pid_parameter(pid: &mut pid::Controller, parameter: PidParameter, value: f32) {
+    use command_parser::PidParameter::*;
+    match parameter {
+        Target =>
+            pid.set_target(value),
+        KP =>
+            pid.update_parameters(|parameters| parameters.kp = value),
+        KI =>
+            pid.update_parameters(|parameters| parameters.ki = value),
+        KD =>
+            pid.update_parameters(|parameters| parameters.kd = value),
+        OutputMin =>
+            pid.update_parameters(|parameters| parameters.output_min = value),
+        OutputMax =>
+            pid.update_parameters(|parameters| parameters.output_max = value),
+        IntegralMin =>
+            pid.update_parameters(|parameters| parameters.integral_min = value),
+        IntegralMax =>
+            pid.update_parameters(|parameters| parameters.integral_max = value),
+    }
+}
+
+fn set_sh_parameter(sh: &mut sh::Parameters, parameter: ShParameter, value: f32) {
+    use command_parser::ShParameter::*;
+    match parameter {
+        A => sh.a = value,
+        B => sh.b = value,
+        C => sh.c = value,
+        ParallelR => sh.parallel_r = value,
+    }
+}
+
+fn get_pid_parameter(pid: &pid::Controller, parameter: PidParameter) -> f32 {
+    use command_parser::PidParameter::*;
+    let parameters = pid.get_parameters();
+    match parameter {
+        Target => pid.get_target(),
+        KP => parameters.kp,
+        KI => parameters.ki,
+        KD => parameters.kd,
+        OutputMin => parameters.output_min,
+        OutputMax => parameters.output_max,
+        IntegralMin => parameters.integral_min,
+        IntegralMax => parameters.integral_max,
+    }
+}
+
+/// One line of `report`, with the selected fields
+fn write_report<W: Write>(w: &mut W, channel: usize, sample: &Sample, fields: ReportFields) -> fmt::Result {
+    let mut separator = "";
+    for field in fields.iter() {
+        match field {
+            ReportField::Time =>
+                write!(w, "{}t={}", separator, sample.time)?,
+            ReportField::Temperature =>
+                write!(w, "{}temp{}={}", separator, channel, sample.temperature)?,
+            ReportField::Raw =>
+                write!(w, "{}raw{}=0x{:06X}", separator, channel, sample.raw)?,
+            ReportField::Resistance =>
+                write!(w, "{}r{}={}", separator, channel, sample.resistance)?,
+            ReportField::Pwm => match sample.pwm {
+                Some(width) => write!(w, "{}pwm{}=0x{:04X}", separator, channel, width)?,
+                None => continue,
+            },
+            ReportField::Error => match sample.pid {
+                Some(output) => write!(w, "{}error{}={}", separator, channel, output.error)?,
+                None => continue,
+            },
+            ReportField::Pid => match sample.pid {
+                Some(output) => write!(
+                    w, "{}p{}={} i{}={} d{}={} unclamped{}={}",
+                    separator, channel, output.p, channel, output.i,
+                    channel, output.d, channel, output.unclamped
+                )?,
+                None => continue,
+            },
+            ReportField::Saturation => match sample.pid {
+                Some(output) => write!(
+                    w, "{}sat_integral{}={} sat_output{}={}",
+                    separator, channel, output.integral_saturated as u8,
+                    channel, output.output_saturated as u8
+                )?,
+                None => continue,
+            },
+        }
+        separator = " ";
+    }
+    writeln!(w, "")
+}
+
+/// Check that `assign` leaves the PID controllers of all selected TEC
+/// channels valid
+fn check_pids<F: Fn(&mut pid::Controller)>(
+    states: &[ControlState], channels: Channels, assign: F
+) -> Result<(), (Option<usize>, ValidationError)> {
+    let tec_channels = channels.iter()
+        .filter(|channel| CHANNEL_TABLE[*channel].tec.is_some());
+    for channel in tec_channels {
+        let mut pid = states[channel].pid;
+        assign(&mut pid);
+        validation::check_pid(&pid)
+            .map_err(|e| (Some(channel), e))?;
+    }
+    Ok(())
+}
+
+/// Check the values a command would set on copies of the settings
+/// of all selected channels, before any of them is changed
+fn check_command(
+    command: &Command, states: &[ControlState]
+) -> Result<(), (Option<usize>, ValidationError)> {
+    match *command {
+        Command::Pwm { setup, .. } =>
+            validation::check_pwm(&setup)
+            .map_err(|e| (None, e)),
+        Command::Pid { channels, parameter, value } =>
+            check_pids(states, channels, |pid| set_pid_parameter(pid, parameter, value)),
+        Command::PidAssign { channels, ref assignments } =>
+            check_pids(states, channels, |pid| {
+                for (parameter, value) in assignments.iter() {
+                    set_pid_parameter(pid, parameter, value);
+                }
+            }),
+        Command::SteinhartHart { channels, parameter, value } => {
+            for channel in channels.iter() {
+                let mut sh = states[channel].sh;
+                set_sh_parameter(&mut sh, parameter, value);
+                validation::check_sh(&sh)
+                    .map_err(|e| (Some(channel), e))?;
+            }
+            Ok(())
+        }
+        Command::PostFilter { rate, .. } =>
+            validation::check_postfilter_rate(rate)
+            .map_err(|e| (None, e)),
+        _ => Ok(()),
+    }
+}
+
+/// One record of a `history` download
+fn send_history(socket: &mut TcpSocket, channel: usize, format: HistoryFormat, record: &Record) {
+    match format {
+        HistoryFormat::Text => {
+            let _ = write!(
+                socket, "history {} t={} raw=0x{:06X} temp={}",
+                channel, record.time, record.raw, record.temperature
+            );
+            if let Some(pwm) = record.pwm() {
+                let _ = write!(socket, " pwm=0x{:04X}", pwm);
+            }
+            let _ = writeln!(socket, " flags=0x{:02X}", record.flags);
+        }
+        HistoryFormat::Json => {
+            let _ = write!(
+                socket, "{{\"channel\":{},\"t\":{},\"raw\":{},\"temp\":",
+                channel, record.time, record.raw
+            );
+            if record.temperature.is_finite() {
+                let _ = write!(socket, "{}", record.temperature);
+            } else {
+                let _ = write!(socket, "null");
+            }
+            match record.pwm() {
+                Some(pwm) => {
+                    let _ = write!(socket, ",\"pwm\":{}", pwm);
+                }
+                None => {
+                    let _ = write!(socket, ",\"pwm\":null");
+                }
+            }
+            let _ = writeln!(socket, ",\"flags\":{}}}", record.flags);
+        }
+        HistoryFormat::Binary => {
+            let _ = socket.send_slice(&record.to_bytes());
+        }
+    }
+}
+
+/// Apply a command that changes channel settings, once locks,
+/// monitor-only channels and values have been checked
+fn apply_settings<W: Write>(
+    out: &mut W, command: Command,
+    states: &mut [ControlState], tecs: &mut [&mut dyn TecControl],
+    events: &mut EventQueue
+) {
+    match command {
+        Command::Pwm { channels, setup } => {
+            // `all` skips monitor-only channels
+            let tec_channels = channels.iter()
+                .filter_map(|channel| CHANNEL_TABLE[channel].tec.map(|tec| (channel, tec)));
+            for (channel, tec) in tec_channels {
+                let state = &mut states[channel];
+                match setup {
+                    PwmSetup::ISet(PwmMode::Pid) => {
+                        state.pid_enabled = true;
+                        let _ = writeln!(out, "channel {}: PID enabled to control PWM", channel);
+                    }
+                    PwmSetup::ISet(PwmMode::Manual(config)) => {
+                        state.pid_enabled = false;
+                        let PwmConfig { width, total } = config;
+                        tecs[tec].set(TecPin::ISet, width, total);
+                        let _ = writeln!(
+                            out, "channel {}: PWM duty cycle manually set to {}/{}",
+                            channel, width, total
+                        );
+                    }
+                    PwmSetup::MaxIPos(config) |
+                    PwmSetup::MaxINeg(config) |
+                    PwmSetup::MaxV(config) => {
+                        let pin = match setup {
+                            PwmSetup::MaxIPos(_) => TecPin::MaxIPos,
+                            PwmSetup::MaxINeg(_) => TecPin::MaxINeg,
+                            _ => TecPin::MaxV,
+                        };
+                        let PwmConfig { width, total } = config;
+                        tecs[tec].set(pin, width, total);
+                        let _ = writeln!(
+                            out, "channel {}: PWM {} reconfigured to {}/{}",
+                            channel, pin, width, total
+                        );
+                    }
+                }
+                events.push(Event::Pwm { channel, setup });
+            }
+        }
+        Command::Pid { channels, parameter, value } => {
+            let tec_channels = channels.iter()
+                .filter(|channel| CHANNEL_TABLE[*channel].tec.is_some());
+            for channel in tec_channels {
+                let pid = &mut states[channel].pid;
+                set_pid_parameter(pid, parameter, value);
+                pid.reset();
+                let _ = writeln!(out, "channel {}: PID parameter updated", channel);
+                events.push(Event::Pid { channel, parameter, value });
+            }
+        }
+        Command::PidAssign { channels, assignments } => {
+            // Applied between two control loop updates
+            // with a single reset
+            let tec_channels = channels.iter()
+                .filter(|channel| CHANNEL_TABLE[*channel].tec.is_some());
+            for channel in tec_channels {
+                let pid = &mut states[channel].pid;
+                for (parameter, value) in assignments.iter() {
+                    set_pid_parameter(pid, parameter, value);
+                    events.push(Event::Pid { channel, parameter, value });
+                }
+                pid.reset();
+                let _ = writeln!(out, "channel {}: PID parameters updated", channel);
+            }
+        }
+        Command::SteinhartHart { channels, parameter, value } => {
+            for channel in channels.iter() {
+                set_sh_parameter(&mut states[channel].sh, parameter, value);
+                let _ = writeln!(out, "channel {}: Steinhart-Hart equation parameter updated", channel);
+                events.push(Event::SteinhartHart { channel, parameter, value });
+            }
+        }
+        Command::PostFilter { channels, rate } => {
+            let filter = ad7172::PostFilter::closest(rate);
+            match filter {
+                Some(filter) => {
+                    let rate = filter.output_rate().unwrap();
+                    for channel in channels.iter() {
+                        sampling::with_adc(|adc| {
+                            adc.set_postfilter(CHANNEL_TABLE[channel].adc_channel, Some(filter))
+                        }).unwrap();
+                        let _ = writeln!(
+                            out, "channel {}: postfilter set to {:.2} SPS",
+                            channel, rate
+                        );
+                        events.push(Event::PostFilter { channel, rate });
+                    }
+                }
+                None => {
+                    let _ = writeln!(out, "Unable to choose postfilter");
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// PWM width of a channel's output, set by PID or by hand, 0 for
+/// monitor-only channels
+fn output_width(tecs: &mut [&mut dyn TecControl], channel: usize) -> u16 {
+    CHANNEL_TABLE[channel].tec
+        .map(|tec| tecs[tec].get(TecPin::ISet).0)
+        .unwrap_or(0)
+}
+
+/// Sink for the result lines of commands from other protocols
+struct Discard;
+
+impl Write for Discard {
+    fn write_str(&mut self, _: &str) -> fmt::Result {
+        Ok(())
+    }
+}
+
+/// Flash slot of firmware uploads. Erasing and programming stall
+/// the code running from flash, the RDY interrupt and PID updates
+/// included; the TEC outputs hold their last setting meanwhile.
+struct SlotB;
+
+impl update::Slot for SlotB {
+    type Error = board::flash::Error;
+
+    fn erase_sector(&mut self, offset: u32) -> Result<(), Self::Error> {
+        board::flash::erase_sector(boot::SLOT_B + offset)
+    }
+
+    fn program(&mut self, offset: u32, word: u32) -> Result<(), Self::Error> {
+        board::flash::program(boot::SLOT_B + offset, &[word])
+    }
+
+    fn contents(&self) -> &[u8] {
+        board::flash::read(boot::SLOT_B, boot::SLOT_SIZE)
+    }
+}
+
+/// Current value of a setting
+fn query_value(
+    states: &[ControlState], tecs: &mut [&mut dyn TecControl],
+    channel: usize, parameter: Parameter
+) -> QueryValue {
+    let state = &states[channel];
+    match parameter {
+        Parameter::Pid(parameter) =>
+            QueryValue::Float(get_pid_parameter(&state.pid, parameter)),
+        Parameter::SteinhartHart(ShParameter::A) =>
+            QueryValue::Float(state.sh.a),
+        Parameter::SteinhartHart(ShParameter::B) =>
+            QueryValue::Float(state.sh.b),
+        Parameter::SteinhartHart(ShParameter::C) =>
+            QueryValue::Float(state.sh.c),
+        Parameter::SteinhartHart(ShParameter::ParallelR) =>
+            QueryValue::Float(state.sh.parallel_r),
+        Parameter::Pwm(pin) => match CHANNEL_TABLE[channel].tec {
+            Some(tec) => {
+                let (width, total) = tecs[tec].get(pin);
+                QueryValue::Pwm(width, total)
+            }
+            // Monitor only
+            None =>
+                QueryValue::None,
+        },
+        Parameter::PostFilterRate => {
+            let filter = sampling::with_adc(|adc| {
+                adc.get_postfilter(CHANNEL_TABLE[channel].adc_channel)
+            }).unwrap();
+            match filter.and_then(|filter| filter.output_rate()) {
+                Some(rate) => QueryValue::Float(rate),
+                None => QueryValue::None,
+            }
+        }
+    }
+}
+
+/// Channels of the control loop for the Modbus, SCPI and HTTP
+/// servers
+struct ChannelDevice<'a, 't> {
+    states: &'a mut [ControlState],
+    tecs: &'a mut [&'t mut dyn TecControl],
+    locks: &'a ChannelLocks,
+    events: &'a mut EventQueue,
+}
+
+impl<'a, 't> device::Device for ChannelDevice<'a, 't> {
+    fn has_tec(&self, channel: usize) -> bool {
+        CHANNEL_TABLE[channel].tec.is_some()
+    }
+
+    fn report(&self, channel: usize) -> Option<Sample> {
+        self.states[channel].report
+    }
+
+    fn pid_enabled(&self, channel: usize) -> bool {
+        self.states[channel].pid_enabled
+    }
+
+    fn conversion_error(&self, channel: usize) -> bool {
+        self.states[channel].conversion_error
+    }
+
+    fn locks(&self) -> &ChannelLocks {
+        self.locks
+    }
+
+    fn query(&mut self, channel: usize, parameter: Parameter) -> QueryValue {
+        query_value(self.states, self.tecs, channel, parameter)
+    }
+
+    fn check(&self, command: &Command) -> Result<(), (Option<usize>, ValidationError)> {
+        check_command(command, self.states)
+    }
+
+    fn apply(&mut self, command: Command) {
+        apply_settings(&mut Discard, command, self.states, self.tecs, self.events);
+    }
+
+    fn power_on(&self) -> (PidAssignments, PwmConfig) {
+        let defaults = pid::Controller::new(DEFAULT_PID_PARAMETERS);
+        let mut assignments = PidAssignments::new();
+        for parameter in PidParameter::ALL.iter() {
+            let _ = assignments.assign(*parameter, get_pid_parameter(&defaults, *parameter));
+        }
+        (assignments, PwmConfig { width: PWM_PID_WIDTH / 2, total: PWM_PID_WIDTH })
+    }
+}
+
+fn write_query<W: Write>(
+    w: &mut W, format: OutputFormat,
+    channel: usize, parameter: Parameter, value: QueryValue
+) -> fmt::Result {
+    match format {
+        OutputFormat::Text => match value {
+            QueryValue::Float(value) =>
+                writeln!(w, "{}", value),
+            QueryValue::Pwm(width, total) =>
+                writeln!(w, "{}/{}", width, total),
+            QueryValue::None =>
+                writeln!(w, "none"),
+        },
+        OutputFormat::Json => {
+            write!(w, "{{\"channel\":{},\"{}\":", channel, parameter)?;
+            match value {
+                QueryValue::Float(value) =>
+                    http::write_json_float(w, Some(value))?,
+                QueryValue::Pwm(width, total) =>
+                    write!(w, "{{\"width\":{},\"total\":{}}}", width, total)?,
+                QueryValue::None =>
+                    write!(w, "null")?,
+            }
+            writeln!(w, "}}")
+        }
+    }
+}
+
+/// Unique ID of the microcontroller in hex
+struct SerialNumber([u32; 4]);
+
+impl fmt::Display for SerialNumber {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for word in self.0.iter() {
+            write!(fmt, "{:08x}", word)?;
+        }
+        Ok(())
+    }
+}
+
+/// Salt for a new password, unique per device and change
+fn password_salt() -> [u8; password::SALT_LEN] {
+    let mut buf = [0; 24];
+    for (bytes, word) in buf.chunks_mut(4).zip(board::get_serial().iter()) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    buf[16..].copy_from_slice(&get_time().to_le_bytes());
+    let mut salt = [0; password::SALT_LEN];
+    salt.copy_from_slice(&sha256(&buf)[..password::SALT_LEN]);
+    salt
+}
+
+/// `thermostat-<serial>` until renamed with `name`
+fn default_device_name(serial: [u32; 4]) -> DeviceName {
+    const PREFIX: &[u8] = b"thermostat-";
+    const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut name = [0; PREFIX.len() + 32];
+    name[..PREFIX.len()].copy_from_slice(PREFIX);
+    for (i, c) in name[PREFIX.len()..].iter_mut().enumerate() {
+        let digit = serial[i / 8] >> (28 - 4 * (i % 8));
+        *c = HEX_DIGITS[digit as usize & 0xf];
+    }
+    DeviceName::new(&name).unwrap()
 }
 
 #[cfg(not(test))]
 #[entry]
 fn main() -> ! {
     let mut stdout = hio::hstdout().unwrap();
-    writeln!(stdout, "tecpak boot").unwrap();
+    writeln!(stdout, "tecpak boot, firmware {} ({})", VERSION, GIT_COMMIT).unwrap();
     board::init();
     writeln!(stdout, "board initialized").unwrap();
+    let (mut credentials, device_name, mut boot_state, mut modbus_writable) = match board::eeprom::init() {
+        Ok(()) => (
+            settings::load_credentials(),
+            settings::load_device_name(),
+            settings::load_boot_state(),
+            settings::load_modbus_writable(),
+        ),
+        Err(e) => {
+            writeln!(stdout, "EEPROM error: {:?}", e).unwrap();
+            (Credentials::Unset, None, boot::State::Confirmed, false)
+        }
+    };
+    writeln!(stdout, "firmware: {}", boot_state).unwrap();
+    let mut device_name = device_name
+        .unwrap_or_else(|| default_device_name(board::get_serial()));
     let mut tec0 = Tec::tec0().setup(PWM_PID_WIDTH);
     let mut tec1 = Tec::tec1().setup(PWM_PID_WIDTH);
+    let mut tecs: [&mut dyn TecControl; TECS] = [&mut tec0, &mut tec1];
 
     println!(r#"
   _                         _
@@ -140,8 +736,8 @@ fn main() -> ! {
  | | (/_/| |___| |_) | (_| |   <
  |_|\___\ \___/| .__/ \__,_|_|\_\
                | |
-               |_|             v1
-"#);
+               |_|             v{} {}
+"#, VERSION, GIT_COMMIT);
     // TEC0 - SHDN
     let mut pp2 = board::gpio::PP2.into_output();
     pp2.set_low().unwrap();  // keep off until used
@@ -150,18 +746,25 @@ fn main() -> ! {
     pp3.set_low().unwrap();  // keep off until used
     // CSn
     let pb4 = board::gpio::PB4.into_output();
-    // SCLK
-    let pb5 = board::gpio::PB5.into_output();
-    // MOSI
-    let pe4 = board::gpio::PE4.into_output();
-    // MISO
-    let pe5 = board::gpio::PE5.into_input();
     // max 2 MHz = 0.5 us
-    let mut delay_fn = || for _ in 0..10 { cortex_m::asm::nop(); };
-    let spi = board::softspi::SyncSoftSpi::new(
-        board::softspi::SoftSpi::new(pb5, pe4, pe5),
-        &mut delay_fn
-    );
+    #[cfg(not(feature = "hardware-spi"))]
+    let delay_fn: fn() = || for _ in 0..10 { cortex_m::asm::nop(); };
+    #[cfg(not(feature = "hardware-spi"))]
+    let spi = {
+        // SCLK
+        let pb5 = board::gpio::PB5.into_output();
+        // MOSI
+        let pe4 = board::gpio::PE4.into_output();
+        // MISO
+        let pe5 = board::gpio::PE5.into_input();
+        board::softspi::SyncSoftSpi::new(
+            board::softspi::SoftSpi::new(pb5, pe4, pe5),
+            delay_fn
+        )
+    };
+    // SSI1 with pins muxed in board::init()
+    #[cfg(feature = "hardware-spi")]
+    let spi = board::ssi::Ssi1::new();
     let mut adc = ad7172::Adc::new(spi, pb4).unwrap();
     loop {
         let r = adc.identify();
@@ -179,24 +782,38 @@ fn main() -> ! {
     }
     writeln!(stdout, "AD7172: setting checksum mode").unwrap();
     adc.set_checksum_mode(ad7172::ChecksumMode::Crc).unwrap();
-    loop {
+    let adc_id = loop {
         let r = adc.identify();
         match r {
             Err(e) =>
                 writeln!(stdout, "Cannot identify ADC: {:?}", e).unwrap(),
             Ok(id) if id & 0xFFF0 == 0x00D0 => {
                 writeln!(stdout, "ADC id: {:04X}", id).unwrap();
-                break;
+                break id;
             }
             Ok(id) =>
                 writeln!(stdout, "Corrupt ADC id: {:04X}", id).unwrap(),
         };
-    }
+    };
+    // Read channel and status together with each conversion
+    adc.set_data_stat(true).unwrap();
     adc.set_sync_enable(false).unwrap();
-    // SENS0_{P,N}
-    adc.setup_channel(0, ad7172::Input::Ain0, ad7172::Input::Ain1).unwrap();
-    // SENS1_{P,N}
-    adc.setup_channel(1, ad7172::Input::Ain2, ad7172::Input::Ain3).unwrap();
+    for config in CHANNEL_TABLE.iter() {
+        let (in_pos, in_neg) = config.input;
+        adc.setup_channel(config.adc_channel, in_pos, in_neg).unwrap();
+    }
+    // Housekeeping: internal temperature sensor, reference monitor
+    adc.set_internal_reference(true).unwrap();
+    let (in_pos, in_neg) = housekeeping::Measurement::Temperature.inputs();
+    adc.setup_channel_with_ref(
+        housekeeping::ADC_CHANNEL, in_pos, in_neg,
+        ad7172::RefSource::Internal, true
+    ).unwrap();
+    adc.disable_channel(housekeeping::ADC_CHANNEL).unwrap();
+    let mut housekeeping = Housekeeping::new();
+    // Conversions are read by the sampler from here on
+    let mut core_peripherals = cortex_m::Peripherals::take().unwrap();
+    sampling::init(adc, &mut core_peripherals.NVIC);
 
     let init_state = ControlState {
         report: None,
@@ -204,8 +821,14 @@ fn main() -> ! {
         pid_enabled: false,
         pid: pid::Controller::new(DEFAULT_PID_PARAMETERS.clone()),
         sh: DEFAULT_SH_PARAMETERS.clone(),
+        conversion_error: false,
     };
-    let mut states = [init_state.clone(), init_state.clone()];
+    let mut states = [init_state; CHANNELS];
+    let mut histories = [History::new(); CHANNELS];
+    let mut stats = [Stats::new(); CHANNELS];
+    for (state, config) in states.iter_mut().zip(CHANNEL_TABLE.iter()) {
+        state.sh = config.sensor;
+    }
 
     // let mut hardware_addr = EthernetAddress(board::get_mac_address());
     let mut hardware_addr = EthernetAddress([0xb0, 0xd5, 0xcc, 0xfc, 0xfb, 0xf6]);
@@ -216,17 +839,24 @@ fn main() -> ! {
     }
 
     // let mut hardware_addr = EthernetAddress([0xb0, 0xd5, 0xcc, 0xfc, 0xfb, 0xf6]);
-    let mut ip_addrs = [IpCidr::new(IpAddress::v4(10, 255, 6, 169), 24)];
-    println!("MAC {} IP {}", hardware_addr, ip_addrs[0]);
+    let ip_addr = Ipv4Address::new(10, 255, 6, 169);
+    let mut ip_addrs = [IpCidr::new(IpAddress::Ipv4(ip_addr), 24)];
+    println!("MAC {} IP {} name {}.local", hardware_addr, ip_addrs[0], device_name);
     let mut neighbor_cache_storage = [None; 8];
     let neighbor_cache = NeighborCache::new(&mut neighbor_cache_storage[..]);
+    let mut multicast_groups_storage = [None; 1];
     let mut device = ethmac::Device::new();
     unsafe { device.init(hardware_addr) };
     let mut iface = EthernetInterfaceBuilder::new(&mut device)
                 .ethernet_addr(hardware_addr)
                 .neighbor_cache(neighbor_cache)
                 .ip_addrs(&mut ip_addrs[..])
+                .ipv4_multicast_groups(&mut multicast_groups_storage[..])
                 .finalize();
+    let mdns_group = Ipv4Address::from_bytes(&mdns::GROUP);
+    if let Err(e) = iface.join_multicast_group(mdns_group, Instant::from_millis((get_time() / 1000) as i64)) {
+        println!("Cannot join mDNS group: {}", e);
+    }
 
     create_socket_storage!(tcp_rx_storage0, tcp_tx_storage0);
     create_socket_storage!(tcp_rx_storage1, tcp_tx_storage1);
@@ -237,7 +867,30 @@ fn main() -> ! {
     create_socket_storage!(tcp_rx_storage6, tcp_tx_storage6);
     create_socket_storage!(tcp_rx_storage7, tcp_tx_storage7);
 
-    let mut socket_set_entries: [_; 8] = Default::default();
+    let mut modbus_rx_storage = [0; modbus::ADU_LEN];
+    let mut modbus_tx_storage = [0; 2 * modbus::ADU_LEN];
+
+    let mut scpi_rx_storage0 = [0; TCP_RX_BUFFER_SIZE];
+    let mut scpi_tx_storage0 = [0; SCPI_TX_BUFFER_SIZE];
+    let mut scpi_rx_storage1 = [0; TCP_RX_BUFFER_SIZE];
+    let mut scpi_tx_storage1 = [0; SCPI_TX_BUFFER_SIZE];
+    let mut http_rx_storage0 = [0; http::REQUEST_LEN];
+    let mut http_tx_storage0 = [0; HTTP_TX_BUFFER_SIZE];
+    let mut http_rx_storage1 = [0; http::REQUEST_LEN];
+    let mut http_tx_storage1 = [0; HTTP_TX_BUFFER_SIZE];
+    let mut update_rx_storage = [0; UPDATE_RX_BUFFER_SIZE];
+    let mut update_tx_storage = [0; UPDATE_TX_BUFFER_SIZE];
+
+    let mut udp_rx_metadata = [UdpPacketMetadata::EMPTY; 1];
+    let mut udp_rx_storage = [0; 64];
+    let mut udp_tx_metadata = [UdpPacketMetadata::EMPTY; UDP_TX_PACKETS];
+    let mut udp_tx_storage = [0; UDP_TX_PACKETS * telemetry::PACKET_SIZE];
+    let mut mdns_rx_metadata = [UdpPacketMetadata::EMPTY; 4];
+    let mut mdns_rx_storage = [0; MDNS_RX_BUFFER_SIZE];
+    let mut mdns_tx_metadata = [UdpPacketMetadata::EMPTY; 2];
+    let mut mdns_tx_storage = [0; 2 * mdns::PACKET_LEN];
+
+    let mut socket_set_entries: [_; 16] = Default::default();
     let mut sockets = SocketSet::new(&mut socket_set_entries[..]);
 
     create_socket!(sockets, tcp_rx_storage0, tcp_tx_storage0, tcp_handle0);
@@ -248,6 +901,42 @@ fn main() -> ! {
     create_socket!(sockets, tcp_rx_storage5, tcp_tx_storage5, tcp_handle5);
     create_socket!(sockets, tcp_rx_storage6, tcp_tx_storage6, tcp_handle6);
     create_socket!(sockets, tcp_rx_storage7, tcp_tx_storage7, tcp_handle7);
+    create_socket!(sockets, modbus_rx_storage, modbus_tx_storage, modbus_handle);
+    let mut modbus_connection = modbus::Connection::new();
+    create_socket!(sockets, scpi_rx_storage0, scpi_tx_storage0, scpi_handle0);
+    create_socket!(sockets, scpi_rx_storage1, scpi_tx_storage1, scpi_handle1);
+    let mut scpi_sessions_handles = [
+        (ScpiSession::new(), scpi_handle0),
+        (ScpiSession::new(), scpi_handle1),
+    ];
+    create_socket!(sockets, http_rx_storage0, http_tx_storage0, http_handle0);
+    create_socket!(sockets, http_rx_storage1, http_tx_storage1, http_handle1);
+    let mut http_buffers_handles = [
+        (http::RequestBuffer::new(), http_handle0),
+        (http::RequestBuffer::new(), http_handle1),
+    ];
+    create_socket!(sockets, update_rx_storage, update_tx_storage, update_handle);
+    let mut upload = update::Upload::new();
+    let mut trial = update::TrialCheck::new(CHANNELS);
+    // Response sent, until the connection closes
+    let mut upload_answered = false;
+    let mut reset_time = None;
+    let udp_handle = {
+        let udp_rx_buffer = UdpSocketBuffer::new(&mut udp_rx_metadata[..], &mut udp_rx_storage[..]);
+        let udp_tx_buffer = UdpSocketBuffer::new(&mut udp_tx_metadata[..], &mut udp_tx_storage[..]);
+        let mut udp_socket = UdpSocket::new(udp_rx_buffer, udp_tx_buffer);
+        udp_socket.bind(TELEMETRY_PORT).unwrap();
+        sockets.add(udp_socket)
+    };
+    let mut telemetry = Telemetry::new();
+    let mdns_handle = {
+        let mdns_rx_buffer = UdpSocketBuffer::new(&mut mdns_rx_metadata[..], &mut mdns_rx_storage[..]);
+        let mdns_tx_buffer = UdpSocketBuffer::new(&mut mdns_tx_metadata[..], &mut mdns_tx_storage[..]);
+        let mut mdns_socket = UdpSocket::new(mdns_rx_buffer, mdns_tx_buffer);
+        mdns_socket.bind(mdns::PORT).unwrap();
+        sockets.add(mdns_socket)
+    };
+    let mut mdns_announcer = mdns::Announcer::new();
     let mut sessions_handles = [
         (Session::new(), tcp_handle0),
         (Session::new(), tcp_handle1),
@@ -259,87 +948,426 @@ fn main() -> ! {
         (Session::new(), tcp_handle7),
     ];
 
-    tec0.set(TecPin::ISet, PWM_PID_WIDTH/2, PWM_PID_WIDTH);
-    tec1.set(TecPin::ISet, PWM_PID_WIDTH/2, PWM_PID_WIDTH);
+    let mut locks = ChannelLocks::new();
+    for tec in tecs.iter_mut() {
+        tec.set(TecPin::ISet, PWM_PID_WIDTH/2, PWM_PID_WIDTH);
+    }
     pp2.set_high().unwrap();
     pp3.set_high().unwrap();
     loop {
-        // ADC input
-        adc.data_ready()
-            .unwrap_or_else(|e| {
-                writeln!(stdout, "ADC error: {:?}", e).unwrap();
-                None
-            }).map(|channel| {
-                let now = get_time();
-                let data = adc.read_data().unwrap();
-                let state = &mut states[usize::from(channel)];
-                let voltage = VCC * (data as f32) / (0x7FFFFF as f32);
-                let temperature = state.sh.get_temperature(voltage);
-
-                let pwm_width = if state.pid_enabled {
-                    let width = state.pid.update(temperature) as u16;
-                    match channel {
-                        0 => tec0.set(TecPin::ISet, width, PWM_PID_WIDTH),
-                        1 => tec1.set(TecPin::ISet, width, PWM_PID_WIDTH),
-                        _ => unreachable!(),
+        board::watchdog::feed();
+        if boot_state == boot::State::Trial {
+            match trial.poll(get_time()) {
+                update::Verdict::Wait => {}
+                update::Verdict::Confirm => {
+                    match settings::store_boot_state(&boot::State::Confirmed) {
+                        Ok(()) => {
+                            boot_state = boot::State::Confirmed;
+                            println!("Firmware confirmed");
+                        }
+                        Err(e) => {
+                            println!("Cannot confirm firmware, rolling back: {:?}", e);
+                            board::reset();
+                        }
                     }
-                    Some(width)
-                } else {
-                    None
-                };
+                }
+                update::Verdict::RollBack => {
+                    println!("Firmware not confirmed, rolling back");
+                    board::reset();
+                }
+            }
+        }
+
+        // Events of this iteration, for subscribed sessions
+        let mut events = EventQueue::new();
 
-                state.report = Some((now, data, temperature, pwm_width));
-                for (session, _) in sessions_handles.iter_mut() {
-                    session.set_report_pending(channel.into());
+        // Housekeeping measurement due?
+        if let Some(measurement) = housekeeping.poll(get_time()) {
+            let (in_pos, in_neg) = measurement.inputs();
+            let result = sampling::with_adc(|adc| {
+                adc.set_ref_source(housekeeping::ADC_CHANNEL, measurement.ref_source())?;
+                adc.enable_channel(housekeeping::ADC_CHANNEL, in_pos, in_neg)
+            });
+            if let Err(e) = result {
+                writeln!(stdout, "ADC error: {:?}", e).unwrap();
+                events.push(Event::Fault(Fault::Adc));
+                housekeeping.abort(get_time());
+            }
+        }
+
+        // ADC input
+        let (error, overruns) = sampling::take_errors();
+        if let Some(e) = error {
+            writeln!(stdout, "ADC error: {:?}", e).unwrap();
+            events.push(Event::Fault(Fault::Adc));
+            trial.adc_error();
+        }
+        if overruns > 0 {
+            writeln!(stdout, "ADC samples dropped: {}", overruns).unwrap();
+            events.push(Event::Fault(Fault::Overrun(overruns)));
+        }
+        sampling::poll();
+        while let Some(sampling::TimedSample { time: now, sample }) = sampling::pop() {
+            if sample.channel() == housekeeping::ADC_CHANNEL {
+                // Tried again with the next housekeeping sample if
+                // this fails
+                let result = sampling::with_adc(|adc| {
+                    adc.disable_channel(housekeeping::ADC_CHANNEL)
+                });
+                if let Err(e) = result {
+                    writeln!(stdout, "ADC error: {:?}", e).unwrap();
+                    events.push(Event::Fault(Fault::Adc));
+                }
+                if sample.adc_error() || sample.crc_error() || sample.reg_error() {
+                    housekeeping.abort(now);
+                    continue;
+                }
+                let ref_status = housekeeping.ref_status();
+                housekeeping.feed(now, sample.raw());
+                if housekeeping.ref_status() != ref_status {
+                    println!("ADC reference: {}", housekeeping.ref_status());
+                    events.push(Event::Fault(Fault::Reference(housekeeping.ref_status())));
+                }
+                continue;
+            }
+            let channel = match channel_for_adc(sample.channel()) {
+                Some(channel) => channel,
+                None => continue,
+            };
+            let state = &mut states[channel];
+            let conversion_error = sample.adc_error() || sample.crc_error() || sample.reg_error();
+            trial.conversion(channel, !conversion_error);
+            if conversion_error != state.conversion_error {
+                state.conversion_error = conversion_error;
+                events.push(Event::Fault(Fault::Conversion { channel, active: conversion_error }));
+            }
+            if conversion_error {
+                writeln!(
+                    stdout, "ADC error on channel {}: adc={} crc={} reg={}",
+                    sample.channel(),
+                    sample.adc_error(), sample.crc_error(), sample.reg_error()
+                ).unwrap();
+                let flags =
+                    if sample.adc_error() { history::FLAG_ADC_ERROR } else { 0 } |
+                    if sample.crc_error() { history::FLAG_CRC_ERROR } else { 0 } |
+                    if sample.reg_error() { history::FLAG_REG_ERROR } else { 0 };
+                histories[channel].push(Record {
+                    time: now,
+                    raw: sample.data(),
+                    temperature: core::f32::NAN,
+                    pwm: output_width(&mut tecs, channel),
+                    flags,
+                });
+                continue;
+            }
+            let data = sample.data();
+            let voltage = VCC * (data as f32) / (0x7FFFFF as f32);
+            let resistance = state.sh.get_resistance(voltage);
+            let temperature = state.sh.get_temperature(voltage);
+
+            let pid_output = match CHANNEL_TABLE[channel].tec {
+                Some(tec) if state.pid_enabled => {
+                    let output = state.pid.update(temperature);
+                    let width = output.output as u16;
+                    tecs[tec].set(TecPin::ISet, width, PWM_PID_WIDTH);
+                    Some((width, output))
                 }
+                _ => None,
+            };
+
+            let sample = Sample {
+                time: now,
+                raw: data,
+                resistance,
+                temperature,
+                pwm: pid_output.map(|(width, _)| width),
+                pid: pid_output.map(|(_, output)| output),
+            };
+            state.report = Some(sample);
+            if temperature.is_finite() {
+                stats[channel].push(now, temperature);
+            }
+            histories[channel].push(Record {
+                time: now,
+                raw: data,
+                temperature,
+                pwm: output_width(&mut tecs, channel),
+                flags: if sample.pwm.is_some() { history::FLAG_PID } else { 0 },
             });
+            for (session, _) in sessions_handles.iter_mut() {
+                session.feed_report(channel, &sample);
+            }
+        }
+
+        if let Some(destination) = telemetry.destination() {
+            let [a, b, c, d] = destination.addr;
+            let endpoint = IpEndpoint::new(IpAddress::v4(a, b, c, d), destination.port);
+            let mut socket = sockets.get::<UdpSocket>(udp_handle);
+            let mut packet = [0; telemetry::PACKET_SIZE];
+            loop {
+                let len = telemetry.fill_packet(&histories, &mut packet);
+                if len == 0 {
+                    break;
+                }
+                match socket.send_slice(&packet[..len], endpoint) {
+                    Ok(()) => telemetry.sent(),
+                    // Buffer full, the records are sent on a later
+                    // poll unless `history` overwrites them first
+                    Err(_) => break,
+                }
+            }
+        }
 
-        for (session, tcp_handle) in sessions_handles.iter_mut() {
+        {
+            let mut socket = sockets.get::<UdpSocket>(mdns_handle);
+            let host = mdns::Host {
+                name: device_name.as_bytes(),
+                addr: ip_addr.0,
+                services: &MDNS_SERVICES,
+            };
+            let group = IpEndpoint::new(IpAddress::Ipv4(mdns_group), mdns::PORT);
+            let mut packet = [0; mdns::PACKET_LEN];
+            let len = mdns_announcer.poll(get_time(), &host, &mut packet);
+            if len > 0 {
+                let _ = socket.send_slice(&packet[..len], group);
+            }
+            while let Ok((query, source)) = socket.recv() {
+                let legacy = source.port != mdns::PORT;
+                let len = mdns::respond(&host, query, legacy, &mut packet);
+                if len > 0 {
+                    let destination = if legacy { source } else { group };
+                    let _ = socket.send_slice(&packet[..len], destination);
+                }
+            }
+        }
+
+        {
+            let socket = &mut *sockets.get::<TcpSocket>(update_handle);
+            if !socket.is_open() {
+                upload.reset();
+                upload_answered = false;
+                socket.listen(update::PORT).unwrap();
+            }
+            if socket.may_recv() && socket.may_send() && !upload_answered && reset_time.is_none() {
+                let progress = if boot_state == boot::State::Trial {
+                    let _ = writeln!(socket, "ERROR trial firmware not confirmed yet, use `confirm`");
+                    None
+                } else {
+                    socket.recv(|buf| (buf.len(), upload.receive(buf, &credentials, &mut SlotB))).ok()
+                };
+                match progress {
+                    Some(Ok(update::Progress::Receiving)) => {}
+                    Some(Ok(update::Progress::Complete { length, crc })) => {
+                        let pending = boot::State::Pending { length, crc };
+                        match settings::store_boot_state(&pending) {
+                            Ok(()) => {
+                                boot_state = pending;
+                                let _ = writeln!(socket, "OK");
+                                reset_time = Some(get_time() + UPDATE_RESET_DELAY);
+                            }
+                            Err(e) => {
+                                let _ = writeln!(socket, "ERROR cannot store boot state: {:?}", e);
+                            }
+                        }
+                        upload_answered = true;
+                    }
+                    Some(Err(e)) => {
+                        let _ = writeln!(socket, "ERROR {}", e);
+                        upload_answered = true;
+                    }
+                    None =>
+                        upload_answered = true,
+                }
+                if upload_answered {
+                    socket.close();
+                }
+            }
+        }
+        // Restart into the bootloader for the update
+        if reset_time.map(|time| get_time() >= time).unwrap_or(false) {
+            board::reset();
+        }
+
+        // Changes to locked channels, by owning session
+        let mut changes: [Option<usize>; CHANNELS] = [None; CHANNELS];
+        let mut password_changed_by = None;
+        for (session_id, (session, tcp_handle)) in sessions_handles.iter_mut().enumerate() {
             let socket = &mut *sockets.get::<TcpSocket>(*tcp_handle);
             if !socket.is_open() {
+                locks.release_all(session_id);
                 if session.is_dirty() {
                     // Reset a previously uses session/socket
                     *session = Session::new();
                 }
-                socket.listen(23).unwrap()
+                socket.listen(TELNET_PORT).unwrap()
             }
 
             if socket.may_recv() && socket.may_send() {
+                trial.served();
                 let output = socket.recv(|buf| session.feed(buf));
-
-                // TODO: use "{}" to display pretty errors
+                // Echo and telnet negotiation
+                if session.output().len() > 0 {
+                    let _ = socket.send_slice(session.output());
+                    session.clear_output();
+                }
+                let invalid = match output {
+                    Ok(SessionOutput::Command(ref command)) =>
+                        check_command(command, &states).err(),
+                    _ => None,
+                };
                 match output {
                     Ok(SessionOutput::Nothing) => {}
+                    Ok(SessionOutput::Command(ref command))
+                        if command.channels().iter().flat_map(Channels::iter)
+                        .any(|channel| !locks.may_write(channel, session_id)) => {
+                        // Rejected as a whole
+                        for channel in command.channels().unwrap().iter() {
+                            if !locks.may_write(channel, session_id) {
+                                let _ = writeln!(
+                                    socket, "channel {}: locked by session {}",
+                                    channel, locks.owner(channel).unwrap()
+                                );
+                            }
+                        }
+                    }
+                    Ok(SessionOutput::Command(_)) if invalid.is_some() => {
+                        // Rejected as a whole, nothing is changed
+                        match invalid.unwrap() {
+                            (Some(channel), e) => {
+                                let _ = writeln!(socket, "channel {}: {}", channel, e);
+                            }
+                            (None, e) => {
+                                let _ = writeln!(socket, "{}", e);
+                            }
+                        }
+                    }
                     Ok(SessionOutput::Command(command)) => match command {
                         Command::Quit =>
                             socket.close(),
+                        Command::Auth(password) => {
+                            if credentials.permits(password.as_bytes()) {
+                                session.set_privileged(true);
+                                let _ = writeln!(socket, "Authenticated for read-write access");
+                            } else {
+                                let _ = writeln!(socket, "Authentication failed");
+                            }
+                        }
+                        Command::Show(ShowCommand::Locks) => {
+                            for channel in 0..CHANNELS {
+                                match locks.owner(channel) {
+                                    Some(owner) if owner == session_id => {
+                                        let _ = writeln!(socket, "channel {}: locked by this session", channel);
+                                    }
+                                    Some(owner) => {
+                                        let _ = writeln!(socket, "channel {}: locked by session {}", channel, owner);
+                                    }
+                                    None => {
+                                        let _ = writeln!(socket, "channel {}: unlocked", channel);
+                                    }
+                                }
+                            }
+                        }
+                        Command::Lock(channel) => {
+                            match locks.lock(channel, session_id) {
+                                Ok(()) => {
+                                    let _ = writeln!(socket, "channel {}: locked", channel);
+                                }
+                                Err(LockError::LockedBy(owner)) => {
+                                    let _ = writeln!(socket, "channel {}: already locked by session {}", channel, owner);
+                                }
+                                Err(LockError::NotLocked) =>
+                                    unreachable!(),
+                            }
+                        }
+                        Command::Unlock(channel) => {
+                            match locks.unlock(channel, session_id) {
+                                Ok(()) => {
+                                    let _ = writeln!(socket, "channel {}: unlocked", channel);
+                                }
+                                Err(LockError::LockedBy(owner)) => {
+                                    let _ = writeln!(socket, "channel {}: locked by session {}", channel, owner);
+                                }
+                                Err(LockError::NotLocked) => {
+                                    let _ = writeln!(socket, "channel {}: not locked", channel);
+                                }
+                            }
+                        }
+                        Command::SetPassword(password) => {
+                            let new_credentials = Credentials::new(password.as_bytes(), password_salt());
+                            match settings::store_credentials(&new_credentials) {
+                                Ok(()) => {
+                                    credentials = new_credentials;
+                                    password_changed_by = Some(session_id);
+                                    let _ = writeln!(socket, "Password changed");
+                                }
+                                Err(e) => {
+                                    let _ = writeln!(socket, "Cannot store password: {:?}", e);
+                                }
+                            }
+                        }
                         Command::Reporting(reporting) => {
                             let _ = writeln!(socket, "report={}", if reporting { "on" } else { "off" });
                         }
+                        Command::Help(topic) => {
+                            let _ = writeln!(socket, "<ch> is a channel number 0-{}", CHANNELS - 1);
+                            let _ = writeln!(socket, "<chs> is a channel number, a range like 0-1 or all");
+                            session.set_help_stream(Some(HelpStream::new(topic)));
+                        }
+                        Command::History { channel, since, format } => {
+                            let format = format.unwrap_or(match session.format() {
+                                OutputFormat::Text => HistoryFormat::Text,
+                                OutputFormat::Json => HistoryFormat::Json,
+                            });
+                            let stream = HistoryStream::new(&histories[channel], channel, since, format);
+                            if format == HistoryFormat::Binary {
+                                let _ = writeln!(socket, "history {} binary {}", channel, stream.remaining);
+                            }
+                            session.set_history_stream(Some(stream));
+                        }
+                        Command::SubscribeEvents(subscribed) => {
+                            let _ = writeln!(socket, "events={}", if subscribed { "on" } else { "off" });
+                        }
                         Command::Show(ShowCommand::Reporting) => {
                             let _ = writeln!(socket, "report={}", if session.reporting() { "on" } else { "off" });
                         }
-                        Command::Show(ShowCommand::Input) => {
-                            for (channel, state) in states.iter().enumerate() {
-                                state.report.map(|(time, data, temp, pwm_width)| {
-                                    let _ = write!(
-                                        socket, "t={} temp{}={} raw{}=0x{:06X}",
-                                        time, channel, temp, channel, data
-                                    );
-                                    pwm_width.map(|width| {
-                                        let _ = write!(
-                                            socket, " pwm{}=0x{:04X}",
-                                            channel, width
-                                        );
-                                    });
-                                    let _ = writeln!(socket, "");
-                                });
+                        Command::Format(_) | Command::Show(ShowCommand::Format) => {
+                            let _ = writeln!(socket, "format={}", session.format());
+                        }
+                        Command::Report(_) | Command::Show(ShowCommand::ReportSettings) => {
+                            let config = session.report_config();
+                            let _ = writeln!(socket, "Report settings");
+                            let _ = writeln!(socket, "- mode={}", if session.reporting() { "on" } else { "off" });
+                            let _ = write!(socket, "- channels=");
+                            let channels = config.channels.iter()
+                                .enumerate()
+                                .filter(|(_, selected)| **selected);
+                            for (i, (channel, _)) in channels.enumerate() {
+                                let _ = write!(socket, "{}{}", if i > 0 { "," } else { "" }, channel);
                             }
+                            let _ = writeln!(socket, "");
+                            let _ = writeln!(socket, "- decimation={}", config.decimation);
+                            let _ = writeln!(socket, "- interval={} ms", config.interval);
+                            let _ = writeln!(socket, "- average={}", if config.average { "on" } else { "off" });
+                            let _ = writeln!(socket, "- fields={}", config.fields);
                         }
-                        Command::Show(ShowCommand::Pid) => {
-                            for (channel, state) in states.iter().enumerate() {
+                        Command::Show(ShowCommand::Input(selection)) => {
+                            let fields = session.report_config().fields;
+                            for channel in selection.iter() {
+                                if let Some(sample) = states[channel].report {
+                                    let _ = write_report(socket, channel, &sample, fields);
+                                }
+                            }
+                        }
+                        Command::Show(ShowCommand::Pid(selection)) => {
+                            for channel in selection.iter() {
+                                if CHANNEL_TABLE[channel].tec.is_none() {
+                                    if selection != Channels::All {
+                                        let _ = writeln!(socket, "channel {}: monitor only, no TEC", channel);
+                                    }
+                                    continue;
+                                }
                                 let _ = writeln!(socket, "PID settings for channel {}", channel);
-                                let pid = &state.pid;
+                                let pid = &states[channel].pid;
                                 let _ = writeln!(socket, "- target={:.4}", pid.get_target());
                                 let p = pid.get_parameters();
                                 macro_rules! out {
@@ -354,29 +1382,44 @@ fn main() -> ! {
                                 out!(output_max);
                                 out!(integral_min);
                                 out!(integral_max);
+                                if let Some(output) = pid.get_last_output() {
+                                    let _ = writeln!(
+                                        socket, "- last update: error={:.4} p={:.4} i={:.4} d={:.4} unclamped={:.4} output={:.4}",
+                                        output.error, output.p, output.i, output.d, output.unclamped, output.output
+                                    );
+                                    let _ = writeln!(
+                                        socket, "- saturated: integral={} output={}",
+                                        output.integral_saturated, output.output_saturated
+                                    );
+                                }
                                 let _ = writeln!(socket, "");
                             }
                         }
-                        Command::Show(ShowCommand::Pwm) => {
-                            for (channel, state) in states.iter().enumerate() {
+                        Command::Show(ShowCommand::Pwm(selection)) => {
+                            for channel in selection.iter() {
+                                let tec = match CHANNEL_TABLE[channel].tec {
+                                    Some(tec) => tec,
+                                    None if selection != Channels::All => {
+                                        let _ = writeln!(socket, "channel {}: monitor only, no TEC", channel);
+                                        continue;
+                                    }
+                                    None => continue,
+                                };
                                 let _ = writeln!(
                                     socket, "channel {}: PID={}",
                                     channel,
-                                    if state.pid_enabled { "engaged" } else { "disengaged" }
+                                    if states[channel].pid_enabled { "engaged" } else { "disengaged" }
                                 );
                                 for pin in TecPin::VALID_VALUES {
-                                    let (width, total) = match channel {
-                                        0 => tec0.get(*pin),
-                                        1 => tec1.get(*pin),
-                                        _ => unreachable!(),
-                                    };
+                                    let (width, total) = tecs[tec].get(*pin);
                                     let _ = writeln!(socket, "- {}={}/{}", pin, width, total);
                                 }
                                 let _ = writeln!(socket, "");
                             }
                         }
-                        Command::Show(ShowCommand::SteinhartHart) => {
-                            for (channel, state) in states.iter().enumerate() {
+                        Command::Show(ShowCommand::SteinhartHart(selection)) => {
+                            for channel in selection.iter() {
+                                let state = &states[channel];
                                 let _ = writeln!(
                                     socket, "channel {}: Steinhart-Hart equation parameters",
                                     channel,
@@ -388,9 +1431,12 @@ fn main() -> ! {
                                 let _ = writeln!(socket, "");
                             }
                         }
-                        Command::Show(ShowCommand::PostFilter) => {
-                            for (channel, _) in states.iter().enumerate() {
-                                match adc.get_postfilter(channel as u8).unwrap() {
+                        Command::Show(ShowCommand::PostFilter(selection)) => {
+                            for channel in selection.iter() {
+                                let filter = sampling::with_adc(|adc| {
+                                    adc.get_postfilter(CHANNEL_TABLE[channel].adc_channel)
+                                }).unwrap();
+                                match filter {
                                     Some(filter) => {
                                         let _ = writeln!(
                                             socket, "channel {}: postfilter={:.2} SPS",
@@ -406,121 +1452,386 @@ fn main() -> ! {
                                 }
                             }
                         }
-                        Command::Pwm { channel, setup: PwmSetup::ISet(PwmMode::Pid) } => {
-                            states[channel].pid_enabled = true;
-                            let _ = writeln!(socket, "channel {}: PID enabled to control PWM", channel);
-                        }
-                        Command::Pwm { channel, setup: PwmSetup::ISet(PwmMode::Manual(config))} => {
-                            states[channel].pid_enabled = false;
-                            let PwmConfig { width, total } = config;
-                            match channel {
-                                0 => tec0.set(TecPin::ISet, width, total),
-                                1 => tec1.set(TecPin::ISet, width, total),
-                                _ => unreachable!(),
+                        Command::Show(ShowCommand::Stats(selection)) => {
+                            for channel in selection.iter() {
+                                let channel_stats = &stats[channel];
+                                let _ = writeln!(socket, "Statistics for channel {}", channel);
+                                let _ = writeln!(
+                                    socket, "- samples={} in {:.1} s",
+                                    channel_stats.count(), channel_stats.duration()
+                                );
+                                let values = [
+                                    ("mean", channel_stats.mean()),
+                                    ("rms", channel_stats.std_dev()),
+                                    ("min", channel_stats.min()),
+                                    ("max", channel_stats.max()),
+                                    ("p-p", channel_stats.peak_to_peak()),
+                                ];
+                                for (name, value) in values.iter() {
+                                    if let Some(value) = value {
+                                        let _ = writeln!(socket, "- {}={:.6} K", name, value);
+                                    }
+                                }
+                                for adev in channel_stats.allan_deviations() {
+                                    let _ = writeln!(
+                                        socket, "- adev tau={:.3} s ({} samples): {:.6} K",
+                                        adev.tau, adev.samples, adev.deviation
+                                    );
+                                }
+                                let _ = writeln!(socket, "");
                             }
-                            let _ = writeln!(
-                                socket, "channel {}: PWM duty cycle manually set to {}/{}",
-                                channel, config.width, config.total
-                            );
-                        }
-                        Command::Pwm { channel, setup } => {
-                            let (pin, config) = match setup {
-                                PwmSetup::ISet(_) =>
-                                    // Handled above
-                                    unreachable!(),
-                                PwmSetup::MaxIPos(config) =>
-                                    (TecPin::MaxIPos, config),
-                                PwmSetup::MaxINeg(config) =>
-                                    (TecPin::MaxINeg, config),
-                                PwmSetup::MaxV(config) =>
-                                    (TecPin::MaxV, config),
-                            };
-                            let PwmConfig { width, total } = config;
-                            match channel {
-                                0 => tec0.set(pin, width, total),
-                                1 => tec1.set(pin, width, total),
-                                _ => unreachable!(),
+                        }
+                        Command::Show(ShowCommand::Telemetry) => {
+                            match telemetry.destination() {
+                                Some(destination) => {
+                                    let _ = writeln!(socket, "telemetry={}", destination);
+                                }
+                                None => {
+                                    let _ = writeln!(socket, "telemetry=off");
+                                }
                             }
-                            let _ = writeln!(
-                                socket, "channel {}: PWM {} reconfigured to {}/{}",
-                                channel, pin, width, total
-                            );
-                        }
-                        Command::Pid { channel, parameter, value } => {
-                            let pid = &mut states[channel].pid;
-                            use command_parser::PidParameter::*;
-                            match parameter {
-                                Target =>
-                                    pid.set_target(value),
-                                KP =>
-                                    pid.update_parameters(|parameters| parameters.kp = value),
-                                KI =>
-                                    pid.update_parameters(|parameters| parameters.ki = value),
-                                KD =>
-                                    pid.update_parameters(|parameters| parameters.kd = value),
-                                OutputMin =>
-                                    pid.update_parameters(|parameters| parameters.output_min = value),
-                                OutputMax =>
-                                    pid.update_parameters(|parameters| parameters.output_max = value),
-                                IntegralMin =>
-                                    pid.update_parameters(|parameters| parameters.integral_min = value),
-                                IntegralMax =>
-                                    pid.update_parameters(|parameters| parameters.integral_max = value),
+                        }
+                        Command::Telemetry(destination) => {
+                            telemetry.set_destination(destination, &histories);
+                            match destination {
+                                Some(destination) => {
+                                    let _ = writeln!(
+                                        socket, "Streaming telemetry version {} to {}",
+                                        telemetry::VERSION, destination
+                                    );
+                                }
+                                None => {
+                                    let _ = writeln!(socket, "Telemetry stopped");
+                                }
+                            }
+                        }
+                        Command::StatsReset(selection) => {
+                            for channel in selection.iter() {
+                                stats[channel].reset();
+                                let _ = writeln!(socket, "channel {}: statistics reset", channel);
+                            }
+                        }
+                        Command::Get { channel, parameter } => {
+                            let value = query_value(&states, &mut tecs, channel, parameter);
+                            let _ = write_query(&mut *socket, session.format(), channel, parameter, value);
+                        }
+                        Command::Show(ShowCommand::DeviceName) => {
+                            let _ = writeln!(socket, "name={}", device_name);
+                            let _ = writeln!(socket, "host={}.local", device_name);
+                        }
+                        Command::SetDeviceName(name) => {
+                            match settings::store_device_name(&name) {
+                                Ok(()) => {
+                                    device_name = name;
+                                    mdns_announcer.restart(get_time());
+                                    let _ = writeln!(socket, "Device name changed, announced as {}.local", name);
+                                }
+                                Err(e) => {
+                                    let _ = writeln!(socket, "Cannot store device name: {:?}", e);
+                                }
                             }
-                            pid.reset();
-                            let _ = writeln!(socket, "PID parameter updated");
-                        }
-                        Command::SteinhartHart { channel, parameter, value } => {
-                            let sh = &mut states[channel].sh;
-                            use command_parser::ShParameter::*;
-                            match parameter {
-                                A => sh.a = value,
-                                B => sh.b = value,
-                                C => sh.c = value,
-                                ParallelR => sh.parallel_r = value,
+                        }
+                        Command::Show(ShowCommand::Modbus) => {
+                            let _ = writeln!(socket, "modbus_write={}", if modbus_writable { "on" } else { "off" });
+                        }
+                        Command::ModbusWrite(writable) => {
+                            match settings::store_modbus_writable(writable) {
+                                Ok(()) => {
+                                    modbus_writable = writable;
+                                    let _ = writeln!(
+                                        socket, "Modbus writes {}",
+                                        if writable { "allowed" } else { "refused" }
+                                    );
+                                }
+                                Err(e) => {
+                                    let _ = writeln!(socket, "Cannot store Modbus setting: {:?}", e);
+                                }
+                            }
+                        }
+                        Command::Confirm => {
+                            if boot_state != boot::State::Trial {
+                                let _ = writeln!(socket, "Firmware is not on trial, firmware={}", boot_state);
+                            } else {
+                                match settings::store_boot_state(&boot::State::Confirmed) {
+                                    Ok(()) => {
+                                        boot_state = boot::State::Confirmed;
+                                        let _ = writeln!(socket, "Firmware confirmed");
+                                    }
+                                    Err(e) => {
+                                        let _ = writeln!(socket, "Cannot confirm firmware: {:?}", e);
+                                    }
+                                }
+                            }
+                        }
+                        Command::Show(ShowCommand::Identity) => {
+                            let uptime = get_time();
+                            let _ = writeln!(socket, "version={}", VERSION);
+                            let _ = writeln!(socket, "commit={}", GIT_COMMIT);
+                            let _ = writeln!(socket, "build_date={}", BUILD_DATE);
+                            let _ = writeln!(socket, "mac={}", hardware_addr);
+                            let _ = writeln!(socket, "serial={}", SerialNumber(board::get_serial()));
+                            let _ = writeln!(socket, "adc_id={:04X}", adc_id);
+                            let _ = writeln!(socket, "uptime={}.{:03} s", uptime / 1_000_000, uptime / 1000 % 1000);
+                        }
+                        Command::Show(ShowCommand::Update) => {
+                            let _ = writeln!(socket, "firmware={}", boot_state);
+                            if upload.received() > 0 {
+                                let _ = writeln!(socket, "upload={} bytes", upload.received());
                             }
-                            let _ = writeln!(socket, "Steinhart-Hart equation parameter updated");
                         }
-                        Command::PostFilter { channel, rate } => {
-                            let filter = ad7172::PostFilter::closest(rate);
-                            match filter {
-                                Some(filter) => {
-                                    adc.set_postfilter(channel as u8, Some(filter)).unwrap();
+                        Command::Show(ShowCommand::Board) => {
+                            let _ = writeln!(socket, "board:");
+                            match housekeeping.temperature() {
+                                Some(temperature) => {
+                                    let _ = writeln!(socket, "- temperature={:.2} C", temperature);
+                                }
+                                None => {
+                                    let _ = writeln!(socket, "- temperature=unknown");
+                                }
+                            }
+                            match housekeeping.reference() {
+                                Some(reference) => {
                                     let _ = writeln!(
-                                        socket, "channel {}: postfilter set to {:.2} SPS",
-                                        channel, filter.output_rate().unwrap()
+                                        socket, "- reference={:.4} V ({})",
+                                        reference, housekeeping.ref_status()
                                     );
                                 }
                                 None => {
-                                    let _ = writeln!(socket, "Unable to choose postfilter");
+                                    let _ = writeln!(socket, "- reference={}", housekeeping.ref_status());
+                                }
+                            }
+                        }
+                        Command::Pwm { channels, .. } |
+                        Command::Pid { channels, .. } |
+                        Command::PidAssign { channels, .. }
+                            if device::selects_monitor_only(channels, |channel| CHANNEL_TABLE[channel].tec.is_some()) => {
+                            for channel in channels.iter() {
+                                if CHANNEL_TABLE[channel].tec.is_none() {
+                                    let _ = writeln!(socket, "channel {}: monitor only, no TEC", channel);
+                                }
+                            }
+                        }
+                        command @ Command::Pwm { .. } |
+                        command @ Command::Pid { .. } |
+                        command @ Command::PidAssign { .. } |
+                        command @ Command::SteinhartHart { .. } |
+                        command @ Command::PostFilter { .. } => {
+                            let channels = command.channels();
+                            apply_settings(socket, command, &mut states, &mut tecs, &mut events);
+                            for channel in channels.iter().flat_map(Channels::iter) {
+                                if locks.owner(channel) == Some(session_id) {
+                                    changes[channel] = Some(session_id);
                                 }
                             }
                         }
                     }
                     Ok(SessionOutput::Error(e)) => {
-                        let _ = writeln!(socket, "Command error: {:?}", e);
+                        let _ = writeln!(socket, "Command error: {}", e);
+                    }
+                    Ok(SessionOutput::LineTooLong) => {
+                        let _ = writeln!(socket, "Line too long, at most {} characters", session::MAX_LINE_LEN);
+                    }
+                    Ok(SessionOutput::PermissionDenied) => {
+                        let _ = writeln!(socket, "Permission denied: read-only session, use `auth <password>` first");
                     }
                     Err(_) => {}
                 }
             }
+            if let Some(stream) = session.help_stream() {
+                // Sent as buffer space becomes available, so that the
+                // listing is never cut short
+                let mut done = false;
+                while socket.may_send() &&
+                    socket.send_capacity() - socket.send_queue() >= HELP_ENTRY_SPACE {
+                    match stream.next_entry() {
+                        Some(entry) => {
+                            let _ = writeln!(socket, "{}\n    {}", entry.syntax, entry.help);
+                        }
+                        None => {
+                            done = true;
+                            break;
+                        }
+                    }
+                }
+                if done {
+                    session.set_help_stream(None);
+                }
+                continue;
+            }
+            if let Some(stream) = session.history_stream() {
+                // Reports and events wait until the download is complete
+                let channel = stream.channel;
+                while socket.may_send() &&
+                    socket.send_capacity() - socket.send_queue() >= HISTORY_RECORD_SPACE {
+                    match stream.next_record(&histories[channel]) {
+                        Some(record) => send_history(socket, channel, stream.format, &record),
+                        None => break,
+                    }
+                }
+                if stream.remaining == 0 {
+                    match stream.format {
+                        HistoryFormat::Text => {
+                            let _ = writeln!(socket, "history {} end", channel);
+                        }
+                        HistoryFormat::Json => {
+                            let _ = writeln!(socket, "{{\"channel\":{},\"end\":true}}", channel);
+                        }
+                        HistoryFormat::Binary => {}
+                    }
+                    session.set_history_stream(None);
+                }
+                continue;
+            }
             if socket.may_send() {
-                if let Some(channel) = session.is_report_pending() {
-                    states[channel].report.map(|(time, data, temp, pwm_width)| {
-                        let _ = write!(
-                            socket, "t={} temp{}={} raw{}=0x{:06X}",
-                            time, channel, temp, channel, data
-                        );
-                        pwm_width.map(|width| {
-                            let _ = write!(
-                                socket, " pwm{}=0x{:04X}",
-                                channel, width
-                            );
-                        });
-                        let _ = writeln!(socket, "");
-                    });
+                if let Some((channel, sample)) = session.is_report_pending() {
+                    let _ = write_report(socket, channel, &sample, session.report_config().fields);
                     session.mark_report_sent(channel);
                 }
             }
+            if socket.may_send() {
+                let lost = session.take_lost_changes();
+                if lost > 0 {
+                    let _ = writeln!(socket, "changes by lock owners lost {}", lost);
+                }
+                if let Some(change) = session.pop_change() {
+                    let _ = writeln!(
+                        socket, "channel {}: changed by lock owner: {}",
+                        change.channel().unwrap(), change
+                    );
+                }
+            }
+            if socket.may_send() {
+                let lost = session.take_lost_events();
+                if lost > 0 {
+                    let _ = writeln!(socket, "event lost {}", lost);
+                }
+                if let Some(event) = session.pop_event() {
+                    let _ = writeln!(socket, "event {}", event);
+                }
+            }
+        }
+
+        // Modbus TCP, one client at a time
+        {
+            let socket = &mut *sockets.get::<TcpSocket>(modbus_handle);
+            if !socket.is_open() {
+                modbus_connection.reset();
+                socket.listen(modbus::PORT).unwrap();
+            }
+            if socket.may_recv() && socket.may_send() {
+                trial.served();
+                let _ = socket.recv(|buf| (modbus_connection.fill(buf), ()));
+                // Requests wait while the response may not fit
+                while socket.send_capacity() - socket.send_queue() >= modbus::ADU_LEN {
+                    let frame = match modbus_connection.frame() {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => break,
+                        Err(modbus::InvalidFrame) => {
+                            socket.abort();
+                            modbus_connection.reset();
+                            break;
+                        }
+                    };
+                    let channels = ChannelDevice {
+                        states: &mut states,
+                        tecs: &mut tecs,
+                        locks: &locks,
+                        events: &mut events,
+                    };
+                    let mut device = modbus::ModbusDevice::new(channels, modbus_writable);
+                    let mut response = [0; modbus::ADU_LEN];
+                    let len = modbus::handle(frame, &mut device, &mut response);
+                    let frame_len = frame.len();
+                    let _ = socket.send_slice(&response[..len]);
+                    modbus_connection.consume(frame_len);
+                }
+            }
+        }
+
+        for (scpi_session, scpi_handle) in scpi_sessions_handles.iter_mut() {
+            let socket = &mut *sockets.get::<TcpSocket>(*scpi_handle);
+            if !socket.is_open() {
+                if scpi_session.is_dirty() {
+                    *scpi_session = ScpiSession::new();
+                }
+                socket.listen(scpi::PORT).unwrap();
+            }
+            // A line at a time, while its responses fit
+            if socket.may_recv() && socket.may_send() &&
+                socket.send_capacity() - socket.send_queue() >= SCPI_RESPONSE_SPACE {
+                trial.served();
+                if let Ok(Some(line)) = socket.recv(|buf| scpi_session.feed(buf)) {
+                    let channels = ChannelDevice {
+                        states: &mut states,
+                        tecs: &mut tecs,
+                        locks: &locks,
+                        events: &mut events,
+                    };
+                    let serial = SerialNumber(board::get_serial());
+                    let mut device = scpi::ScpiDevice::new(channels, credentials, &serial);
+                    let _ = device.execute_line(socket, scpi_session, &line);
+                }
+            }
+        }
+
+        for (request_buffer, http_handle) in http_buffers_handles.iter_mut() {
+            let socket = &mut *sockets.get::<TcpSocket>(*http_handle);
+            if !socket.is_open() {
+                request_buffer.reset();
+                socket.listen(http::PORT).unwrap();
+            }
+            if socket.may_recv() && socket.may_send() {
+                trial.served();
+                let _ = socket.recv(|buf| (request_buffer.fill(buf), ()));
+                let _ = match request_buffer.request() {
+                    Ok(None) =>
+                        continue,
+                    Ok(Some(request)) => {
+                        let channels = ChannelDevice {
+                            states: &mut states,
+                            tecs: &mut tecs,
+                            locks: &locks,
+                            events: &mut events,
+                        };
+                        let mut device = http::HttpDevice::new(channels, credentials);
+                        device.respond(socket, &request)
+                    }
+                    Err(status) =>
+                        http::write_error(socket, status, format_args!("{}", status)),
+                };
+                // One request per connection
+                socket.close();
+            }
+        }
+
+        // The new password applies to all other sessions
+        if let Some(changed_by) = password_changed_by {
+            for (session_id, (session, _)) in sessions_handles.iter_mut().enumerate() {
+                if session_id != changed_by {
+                    session.set_privileged(false);
+                }
+            }
+            for (scpi_session, _) in scpi_sessions_handles.iter_mut() {
+                scpi_session.set_privileged(false);
+            }
+        }
+        // Events beyond the queue of an iteration
+        let lost = events.take_lost();
+        if lost > 0 {
+            println!("Events lost: {}", lost);
+            for (session, _) in sessions_handles.iter_mut() {
+                session.add_lost_events(lost);
+            }
+        }
+        while let Some(event) = events.pop() {
+            let owner = event.channel().and_then(|channel| changes[channel]);
+            for (session_id, (session, _)) in sessions_handles.iter_mut().enumerate() {
+                // Subscribed sessions see the event
+                if owner.map(|owner| owner != session_id).unwrap_or(false) && !session.subscribed() {
+                    session.push_change(event);
+                }
+                session.push_event(event);
+            }
         }
         match iface.poll(&mut sockets, Instant::from_millis((get_time() / 1000) as i64)) {
             Ok(_) => (),
@@ -0,0 +1,541 @@
+// Multicast DNS responder (RFC 6762) with DNS-SD service records
+// (RFC 6763)
+//
+// The device answers for `<name>.local` and announces one instance
+// `<name>._<service>._tcp.local` per TCP service. There is no
+// probing: names are expected to be unique on the network.
+
+use byteorder::{BigEndian, ByteOrder};
+
+pub const PORT: u16 = 5353;
+pub const GROUP: [u8; 4] = [224, 0, 0, 251];
+/// Largest response, fits one Ethernet frame
+pub const PACKET_LEN: usize = 1024;
+
+const HEADER_LEN: usize = 12;
+/// Response, authoritative answer
+const FLAGS_RESPONSE: u16 = 0x8400;
+/// QR and opcode bits of the header flags
+const FLAGS_QUERY_MASK: u16 = 0xf800;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+/// Class bit of unique records in responses, of unicast-response
+/// requests in questions
+const CLASS_FLAG: u16 = 0x8000;
+
+/// Host address and SRV records, s
+const HOST_TTL: u32 = 120;
+/// PTR and TXT records, s
+const SERVICE_TTL: u32 = 4500;
+/// Upper bound for responses to legacy unicast queries, s
+const LEGACY_TTL: u32 = 10;
+
+const LOCAL: &[u8] = b"local";
+const TCP: &[u8] = b"_tcp";
+const SERVICES: [&[u8]; 4] = [b"_services", b"_dns-sd", b"_udp", LOCAL];
+/// Names remembered for compression
+const MAX_NAMES: usize = 32;
+/// Compression pointers followed while reading a name
+const MAX_POINTERS: usize = 8;
+
+/// Announcements after start-up and renaming
+const ANNOUNCEMENTS: u8 = 2;
+/// µs
+const ANNOUNCE_INTERVAL: u64 = 1_000_000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Service {
+    /// DNS-SD service type, e.g. `_http`
+    pub name: &'static str,
+    pub port: u16,
+}
+
+/// Records of this device
+pub struct Host<'a> {
+    /// Single label, the host and service instance name
+    pub name: &'a [u8],
+    pub addr: [u8; 4],
+    /// Up to 7, limited by the bits of `Records`
+    pub services: &'a [Service],
+}
+
+impl<'a> Host<'a> {
+    fn host_name(&self) -> [&[u8]; 2] {
+        [self.name, LOCAL]
+    }
+
+    fn service_type(&self, service: usize) -> [&[u8]; 3] {
+        [self.services[service].name.as_bytes(), TCP, LOCAL]
+    }
+
+    fn instance(&self, service: usize) -> [&[u8]; 4] {
+        [self.name, self.services[service].name.as_bytes(), TCP, LOCAL]
+    }
+}
+
+/// Selection of records: the address record, then DNS-SD
+/// enumeration, PTR, SRV and TXT per service
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Records(u32);
+
+impl Records {
+    const ADDRESS: u32 = 1;
+    const ENUMERATION: u32 = 0;
+    const POINTER: u32 = 1;
+    const SERVICE: u32 = 2;
+    const TEXT: u32 = 3;
+
+    fn all(host: &Host) -> Self {
+        Records((1 << (1 + 4 * host.services.len())) - 1)
+    }
+
+    fn service_bit(service: usize, record: u32) -> u32 {
+        1 << (1 + 4 * service as u32 + record)
+    }
+
+    fn has(&self, bit: u32) -> bool {
+        self.0 & bit != 0
+    }
+}
+
+/// Writes unsolicited announcements, a few after start-up and after
+/// `restart()`
+pub struct Announcer {
+    remaining: u8,
+    next: u64,
+}
+
+impl Announcer {
+    pub fn new() -> Self {
+        Announcer { remaining: ANNOUNCEMENTS, next: 0 }
+    }
+
+    /// After the name has changed
+    pub fn restart(&mut self, now: u64) {
+        self.remaining = ANNOUNCEMENTS;
+        self.next = now;
+    }
+
+    /// Fill `buf` with an announcement if one is due, returns its
+    /// length or 0
+    pub fn poll(&mut self, now: u64, host: &Host, buf: &mut [u8]) -> usize {
+        if self.remaining == 0 || now < self.next {
+            return 0;
+        }
+        self.remaining -= 1;
+        self.next = now + ANNOUNCE_INTERVAL;
+        let mut writer = Writer::new(buf);
+        writer.header(0, 0);
+        let count = writer.records(host, Records::all(host), false);
+        writer.finish(count, 0)
+    }
+}
+
+/// Fill `buf` with the response to `query`, returns its length or 0
+/// if there is nothing to answer. Queries from other ports than
+/// `PORT` are legacy unicast queries (RFC 6762, 6.7), their response
+/// repeats the id and questions.
+pub fn respond(host: &Host, query: &[u8], legacy: bool, buf: &mut [u8]) -> usize {
+    if query.len() < HEADER_LEN || BigEndian::read_u16(&query[2..]) & FLAGS_QUERY_MASK != 0 {
+        return 0;
+    }
+    let question_count = BigEndian::read_u16(&query[4..]);
+    let mut offset = HEADER_LEN;
+    let mut answers = 0;
+    for _ in 0..question_count {
+        let end = match skip_name(query, offset) {
+            Some(end) if end + 4 <= query.len() => end,
+            _ => return 0,
+        };
+        let qtype = BigEndian::read_u16(&query[end..]);
+        let qclass = BigEndian::read_u16(&query[end + 2..]) & !CLASS_FLAG;
+        if qclass == CLASS_IN || qclass == CLASS_ANY {
+            answers |= matching_records(host, query, offset, qtype).0;
+        }
+        offset = end + 4;
+    }
+    if answers == 0 {
+        return 0;
+    }
+
+    let answers = Records(answers);
+    let mut additional = 0;
+    for service in 0..host.services.len() {
+        if answers.has(Records::service_bit(service, Records::POINTER)) {
+            additional |= Records::service_bit(service, Records::SERVICE) |
+                Records::service_bit(service, Records::TEXT) |
+                Records::ADDRESS;
+        }
+        if answers.has(Records::service_bit(service, Records::SERVICE)) {
+            additional |= Records::ADDRESS;
+        }
+    }
+    let additional = Records(additional & !answers.0);
+
+    let mut writer = Writer::new(buf);
+    if legacy {
+        writer.header(BigEndian::read_u16(query), question_count);
+        // Compression pointers stay valid at the same offset
+        writer.bytes(&query[HEADER_LEN..offset]);
+    } else {
+        writer.header(0, 0);
+    }
+    let answer_count = writer.records(host, answers, legacy);
+    let additional_count = writer.records(host, additional, legacy);
+    writer.finish(answer_count, additional_count)
+}
+
+fn matching_records(host: &Host, packet: &[u8], offset: usize, qtype: u16) -> Records {
+    let is = |rtype| qtype == rtype || qtype == TYPE_ANY;
+    let mut records = 0;
+    if is(TYPE_A) && name_equals(packet, offset, &host.host_name()) {
+        records |= Records::ADDRESS;
+    }
+    for service in 0..host.services.len() {
+        if is(TYPE_PTR) && name_equals(packet, offset, &SERVICES) {
+            records |= Records::service_bit(service, Records::ENUMERATION);
+        }
+        if is(TYPE_PTR) && name_equals(packet, offset, &host.service_type(service)) {
+            records |= Records::service_bit(service, Records::POINTER);
+        }
+        if name_equals(packet, offset, &host.instance(service)) {
+            if is(TYPE_SRV) {
+                records |= Records::service_bit(service, Records::SERVICE);
+            }
+            if is(TYPE_TXT) {
+                records |= Records::service_bit(service, Records::TEXT);
+            }
+        }
+    }
+    Records(records)
+}
+
+/// Offset after the name at `offset`
+fn skip_name(packet: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(offset)? as usize;
+        if len == 0 {
+            return Some(offset + 1);
+        } else if len & 0xc0 == 0xc0 {
+            return Some(offset + 2).filter(|end| *end <= packet.len());
+        } else if len & 0xc0 != 0 {
+            return None;
+        }
+        offset += 1 + len;
+    }
+}
+
+/// Compare the name at `offset`, ignoring ASCII case
+fn name_equals(packet: &[u8], mut offset: usize, labels: &[&[u8]]) -> bool {
+    let mut labels = labels.iter();
+    let mut pointers = 0;
+    loop {
+        let len = match packet.get(offset) {
+            Some(len) => *len as usize,
+            None => return false,
+        };
+        if len & 0xc0 == 0xc0 {
+            pointers += 1;
+            if pointers > MAX_POINTERS || offset + 2 > packet.len() {
+                return false;
+            }
+            offset = (BigEndian::read_u16(&packet[offset..]) & 0x3fff) as usize;
+            continue;
+        } else if len & 0xc0 != 0 {
+            return false;
+        }
+        let label = &packet[(offset + 1).min(packet.len())..(offset + 1 + len).min(packet.len())];
+        match labels.next() {
+            None => return len == 0,
+            Some(expected) if label.len() == len && label.eq_ignore_ascii_case(expected) => {}
+            Some(_) => return false,
+        }
+        offset += 1 + len;
+    }
+}
+
+enum Rdata<'n> {
+    Address([u8; 4]),
+    Pointer(&'n [&'n [u8]]),
+    Service { port: u16, target: &'n [&'n [u8]] },
+    /// No key/value pairs
+    EmptyText,
+}
+
+/// Response with name compression. Output beyond the end of the
+/// buffer is dropped, leaving a response without it.
+struct Writer<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+    /// Length of the response without incomplete records
+    complete_len: usize,
+    /// Offsets of names written so far
+    names: [u16; MAX_NAMES],
+    name_count: usize,
+    overflow: bool,
+}
+
+impl<'b> Writer<'b> {
+    fn new(buf: &'b mut [u8]) -> Self {
+        Writer {
+            buf,
+            len: 0,
+            complete_len: 0,
+            names: [0; MAX_NAMES],
+            name_count: 0,
+            overflow: false,
+        }
+    }
+
+    fn bytes(&mut self, data: &[u8]) {
+        if self.overflow || self.len + data.len() > self.buf.len() {
+            self.overflow = true;
+            return;
+        }
+        self.buf[self.len..self.len + data.len()].copy_from_slice(data);
+        self.len += data.len();
+    }
+
+    fn u16(&mut self, value: u16) {
+        let mut data = [0; 2];
+        BigEndian::write_u16(&mut data, value);
+        self.bytes(&data);
+    }
+
+    fn u32(&mut self, value: u32) {
+        let mut data = [0; 4];
+        BigEndian::write_u32(&mut data, value);
+        self.bytes(&data);
+    }
+
+    fn header(&mut self, id: u16, question_count: u16) {
+        self.u16(id);
+        self.u16(FLAGS_RESPONSE);
+        self.u16(question_count);
+        // Record counts are set by `finish()`
+        self.bytes(&[0; 6]);
+        self.complete_len = self.len;
+    }
+
+    fn name(&mut self, labels: &[&[u8]]) {
+        for (i, label) in labels.iter().enumerate() {
+            let suffix = &labels[i..];
+            let known = self.names[..self.name_count].iter()
+                .find(|offset| name_equals(&self.buf[..self.len], **offset as usize, suffix))
+                .cloned();
+            if let Some(offset) = known {
+                self.u16(0xc000 | offset);
+                return;
+            }
+            if self.name_count < MAX_NAMES && self.len < 0x4000 && !self.overflow {
+                self.names[self.name_count] = self.len as u16;
+                self.name_count += 1;
+            }
+            self.bytes(&[label.len() as u8]);
+            self.bytes(label);
+        }
+        self.bytes(&[0]);
+    }
+
+    /// Returns the number of records written, 0 if it did not fit
+    fn record(&mut self, name: &[&[u8]], rtype: u16, unique: bool, ttl: u32, rdata: Rdata) -> u16 {
+        self.name(name);
+        self.u16(rtype);
+        self.u16(if unique { CLASS_IN | CLASS_FLAG } else { CLASS_IN });
+        self.u32(ttl);
+        let length_offset = self.len;
+        self.u16(0);
+        match rdata {
+            Rdata::Address(addr) =>
+                self.bytes(&addr),
+            Rdata::Pointer(target) =>
+                self.name(target),
+            Rdata::Service { port, target } => {
+                // Priority, weight
+                self.u16(0);
+                self.u16(0);
+                self.u16(port);
+                self.name(target);
+            }
+            Rdata::EmptyText =>
+                self.bytes(&[0]),
+        }
+        if self.overflow {
+            return 0;
+        }
+        let length = (self.len - length_offset - 2) as u16;
+        BigEndian::write_u16(&mut self.buf[length_offset..], length);
+        self.complete_len = self.len;
+        1
+    }
+
+    /// Write the selected records, returns their number
+    fn records(&mut self, host: &Host, records: Records, legacy: bool) -> u16 {
+        // Legacy resolvers neither know cache flushing nor expect
+        // long TTLs
+        let unique = !legacy;
+        let ttl = |ttl: u32| if legacy { ttl.min(LEGACY_TTL) } else { ttl };
+        let host_name = host.host_name();
+        let mut count = 0;
+        if records.has(Records::ADDRESS) {
+            count += self.record(&host_name, TYPE_A, unique, ttl(HOST_TTL), Rdata::Address(host.addr));
+        }
+        for service in 0..host.services.len() {
+            let service_type = host.service_type(service);
+            let instance = host.instance(service);
+            if records.has(Records::service_bit(service, Records::ENUMERATION)) {
+                count += self.record(&SERVICES, TYPE_PTR, false, ttl(SERVICE_TTL), Rdata::Pointer(&service_type));
+            }
+            if records.has(Records::service_bit(service, Records::POINTER)) {
+                count += self.record(&service_type, TYPE_PTR, false, ttl(SERVICE_TTL), Rdata::Pointer(&instance));
+            }
+            if records.has(Records::service_bit(service, Records::SERVICE)) {
+                let port = host.services[service].port;
+                let rdata = Rdata::Service { port, target: &host_name };
+                count += self.record(&instance, TYPE_SRV, unique, ttl(HOST_TTL), rdata);
+            }
+            if records.has(Records::service_bit(service, Records::TEXT)) {
+                count += self.record(&instance, TYPE_TXT, unique, ttl(SERVICE_TTL), Rdata::EmptyText);
+            }
+        }
+        count
+    }
+
+    /// Set the record counts, returns the length
+    fn finish(self, answer_count: u16, additional_count: u16) -> usize {
+        if self.complete_len < HEADER_LEN {
+            return 0;
+        }
+        BigEndian::write_u16(&mut self.buf[6..], answer_count);
+        BigEndian::write_u16(&mut self.buf[10..], additional_count);
+        self.complete_len
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEST_SERVICES: [Service; 2] = [
+        Service { name: "_telnet", port: 23 },
+        Service { name: "_http", port: 80 },
+    ];
+
+    fn host() -> Host<'static> {
+        Host {
+            name: b"thermostat-lab1",
+            addr: [10, 255, 6, 169],
+            services: &TEST_SERVICES,
+        }
+    }
+
+    fn query(id: u16, labels: &[&[u8]], qtype: u16) -> ([u8; 128], usize) {
+        let mut buf = [0; 128];
+        BigEndian::write_u16(&mut buf[0..], id);
+        BigEndian::write_u16(&mut buf[4..], 1);
+        let mut len = HEADER_LEN;
+        for label in labels {
+            buf[len] = label.len() as u8;
+            buf[len + 1..len + 1 + label.len()].copy_from_slice(label);
+            len += 1 + label.len();
+        }
+        len += 1;
+        BigEndian::write_u16(&mut buf[len..], qtype);
+        BigEndian::write_u16(&mut buf[len + 2..], CLASS_IN);
+        (buf, len + 4)
+    }
+
+    /// Type and offset of the record data of each record
+    fn records(packet: &[u8]) -> ([(u16, usize); 16], usize) {
+        let mut records = [(0, 0); 16];
+        let mut offset = HEADER_LEN;
+        for _ in 0..BigEndian::read_u16(&packet[4..]) {
+            offset = skip_name(packet, offset).unwrap() + 4;
+        }
+        let count = BigEndian::read_u16(&packet[6..]) + BigEndian::read_u16(&packet[10..]);
+        for record in records[..count as usize].iter_mut() {
+            offset = skip_name(packet, offset).unwrap();
+            let rtype = BigEndian::read_u16(&packet[offset..]);
+            let rdlength = BigEndian::read_u16(&packet[offset + 8..]) as usize;
+            *record = (rtype, offset + 10);
+            offset += 10 + rdlength;
+        }
+        assert_eq!(offset, packet.len());
+        (records, count as usize)
+    }
+
+    #[test]
+    fn address_query() {
+        let (query, len) = query(0, &[b"Thermostat-LAB1", b"local"], TYPE_A);
+        let mut buf = [0; PACKET_LEN];
+        let len = respond(&host(), &query[..len], false, &mut buf);
+        let packet = &buf[..len];
+        assert_eq!(BigEndian::read_u16(&packet[2..]), FLAGS_RESPONSE);
+        assert_eq!(BigEndian::read_u16(&packet[6..]), 1);
+        let (records, count) = records(packet);
+        assert_eq!(count, 1);
+        assert_eq!(records[0].0, TYPE_A);
+        assert_eq!(&packet[records[0].1..], &[10, 255, 6, 169]);
+    }
+
+    #[test]
+    fn unknown_name() {
+        let (query, len) = query(0, &[b"thermostat-lab2", b"local"], TYPE_A);
+        let mut buf = [0; PACKET_LEN];
+        assert_eq!(respond(&host(), &query[..len], false, &mut buf), 0);
+    }
+
+    #[test]
+    fn service_query() {
+        let (query, len) = query(0x1234, &[b"_http", TCP, LOCAL], TYPE_PTR);
+        let mut buf = [0; PACKET_LEN];
+        let len = respond(&host(), &query[..len], true, &mut buf);
+        let packet = &buf[..len];
+        // Legacy query: id and question repeated
+        assert_eq!(BigEndian::read_u16(packet), 0x1234);
+        assert_eq!(BigEndian::read_u16(&packet[4..]), 1);
+        assert_eq!(BigEndian::read_u16(&packet[6..]), 1);
+        assert_eq!(BigEndian::read_u16(&packet[10..]), 3);
+        let (records, _) = records(packet);
+        assert_eq!(records[0].0, TYPE_PTR);
+        assert!(name_equals(packet, records[0].1, &host().instance(1)));
+        // SRV, TXT, A as additional records
+        assert_eq!(records[1].0, TYPE_A);
+        assert_eq!(records[2].0, TYPE_SRV);
+        assert_eq!(BigEndian::read_u16(&packet[records[2].1 + 4..]), 80);
+        assert!(name_equals(packet, records[2].1 + 6, &host().host_name()));
+        assert_eq!(records[3].0, TYPE_TXT);
+    }
+
+    #[test]
+    fn announcement() {
+        let mut announcer = Announcer::new();
+        let mut buf = [0; PACKET_LEN];
+        let len = announcer.poll(0, &host(), &mut buf);
+        let (records, count) = records(&buf[..len]);
+        assert_eq!(count, 1 + 4 * TEST_SERVICES.len());
+        assert_eq!(records[0].0, TYPE_A);
+        assert!(name_equals(&buf, records[1].1, &host().service_type(0)));
+        // Names compressed
+        assert!(len < 256);
+
+        assert_eq!(announcer.poll(ANNOUNCE_INTERVAL / 2, &host(), &mut buf), 0);
+        assert!(announcer.poll(ANNOUNCE_INTERVAL, &host(), &mut buf) > 0);
+        assert_eq!(announcer.poll(10 * ANNOUNCE_INTERVAL, &host(), &mut buf), 0);
+    }
+
+    #[test]
+    fn truncated_response() {
+        let mut announcer = Announcer::new();
+        let mut buf = [0; 64];
+        let len = announcer.poll(0, &host(), &mut buf);
+        let (_, count) = records(&buf[..len]);
+        assert_eq!(count, 1);
+    }
+}
@@ -0,0 +1,535 @@
+// Modbus TCP server protocol
+//
+// Frames (ADUs) start with the MBAP header: transaction id, protocol
+// id (0), length of the rest and unit id, followed by the PDU: a
+// function code and its data. All values are big-endian.
+
+use byteorder::{BigEndian, ByteOrder};
+use super::command_parser::{
+    Channels, Command, Parameter, PidAssignments, PidParameter, PwmConfig, PwmSetup,
+};
+use super::device::{Device, QueryValue, REMOTE_SESSION};
+use super::receive_buffer::ReceiveBuffer;
+use super::tec::TecPin;
+use super::CHANNELS;
+
+pub const PORT: u16 = 502;
+/// Maximum frame size
+pub const ADU_LEN: usize = 260;
+const MBAP_LEN: usize = 7;
+
+// Function codes
+const READ_COILS: u8 = 0x01;
+const READ_DISCRETE_INPUTS: u8 = 0x02;
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_SINGLE_COIL: u8 = 0x05;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+/// Set in the function code of exception responses
+const EXCEPTION: u8 = 0x80;
+
+const MAX_READ_BITS: u16 = 2000;
+const MAX_READ_REGISTERS: u16 = 125;
+const MAX_WRITE_REGISTERS: u16 = 123;
+
+/// Addresses are `channel * CHANNEL_STRIDE + offset`
+const CHANNEL_STRIDE: u16 = 0x100;
+/// PWM limits in holding registers after the PID parameters
+const PWM_LIMITS: [TecPin; 3] = [TecPin::MaxIPos, TecPin::MaxINeg, TecPin::MaxV];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Exception {
+    IllegalFunction = 1,
+    IllegalDataAddress = 2,
+    IllegalDataValue = 3,
+    ServerDeviceFailure = 4,
+    ServerDeviceBusy = 6,
+}
+
+/// Data model of the device
+pub trait Registers {
+    fn read_coil(&mut self, address: u16) -> Result<bool, Exception>;
+    fn read_discrete_input(&mut self, address: u16) -> Result<bool, Exception>;
+    fn read_holding_register(&mut self, address: u16) -> Result<u16, Exception>;
+    fn read_input_register(&mut self, address: u16) -> Result<u16, Exception>;
+    fn write_coil(&mut self, address: u16, value: bool) -> Result<(), Exception>;
+    /// Consecutive registers are written as a whole or not at all
+    fn write_registers(&mut self, address: u16, values: &[u16]) -> Result<(), Exception>;
+}
+
+/// Receive buffer of a connection, collects a complete frame
+pub type Connection = ReceiveBuffer<[u8; ADU_LEN]>;
+
+/// Not a Modbus TCP frame, the connection should be closed
+#[derive(Debug, PartialEq)]
+pub struct InvalidFrame;
+
+impl Connection {
+    /// Complete frame at the start of the buffer, to be removed with
+    /// `consume()`
+    pub fn frame(&self) -> Result<Option<&[u8]>, InvalidFrame> {
+        let buf = self.contents();
+        if buf.len() < MBAP_LEN {
+            return Ok(None);
+        }
+        let protocol = BigEndian::read_u16(&buf[2..4]);
+        // Unit id and at least a function code
+        let length = BigEndian::read_u16(&buf[4..6]) as usize;
+        if protocol != 0 || length < 2 || 6 + length > ADU_LEN {
+            return Err(InvalidFrame);
+        }
+        if buf.len() < 6 + length {
+            return Ok(None);
+        }
+        Ok(Some(&buf[..6 + length]))
+    }
+}
+
+/// Handle a request frame, returns the length of the response
+pub fn handle<R: Registers>(frame: &[u8], registers: &mut R, response: &mut [u8; ADU_LEN]) -> usize {
+    let function = frame[MBAP_LEN];
+    let data = &frame[MBAP_LEN + 1..];
+    // Transaction id, protocol id and unit id are echoed
+    response[..MBAP_LEN].copy_from_slice(&frame[..MBAP_LEN]);
+    response[MBAP_LEN] = function;
+    let result = execute(function, data, registers, &mut response[MBAP_LEN + 1..]);
+    let pdu_len = match result {
+        Ok(len) => 1 + len,
+        Err(exception) => {
+            response[MBAP_LEN] = function | EXCEPTION;
+            response[MBAP_LEN + 1] = exception as u8;
+            2
+        }
+    };
+    // Unit id and PDU
+    BigEndian::write_u16(&mut response[4..6], 1 + pdu_len as u16);
+    MBAP_LEN + pdu_len
+}
+
+/// Address and count of a read request
+fn read_range(data: &[u8], max_count: u16) -> Result<(u16, u16), Exception> {
+    if data.len() != 4 {
+        return Err(Exception::IllegalDataValue);
+    }
+    let address = BigEndian::read_u16(&data[0..2]);
+    let count = BigEndian::read_u16(&data[2..4]);
+    if count == 0 || count > max_count {
+        return Err(Exception::IllegalDataValue);
+    }
+    if address.checked_add(count - 1).is_none() {
+        return Err(Exception::IllegalDataAddress);
+    }
+    Ok((address, count))
+}
+
+/// Execute the PDU `function`/`data`, returns the length of the
+/// response data in `out`
+fn execute<R: Registers>(function: u8, data: &[u8], registers: &mut R, out: &mut [u8]) -> Result<usize, Exception> {
+    match function {
+        READ_COILS | READ_DISCRETE_INPUTS => {
+            let (address, count) = read_range(data, MAX_READ_BITS)?;
+            let bytes = (count as usize + 7) / 8;
+            out[0] = bytes as u8;
+            for byte in out[1..=bytes].iter_mut() {
+                *byte = 0;
+            }
+            for i in 0..count {
+                let bit = if function == READ_COILS {
+                    registers.read_coil(address + i)?
+                } else {
+                    registers.read_discrete_input(address + i)?
+                };
+                if bit {
+                    out[1 + i as usize / 8] |= 1 << (i % 8);
+                }
+            }
+            Ok(1 + bytes)
+        }
+        READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+            let (address, count) = read_range(data, MAX_READ_REGISTERS)?;
+            out[0] = 2 * count as u8;
+            for i in 0..count {
+                let value = if function == READ_HOLDING_REGISTERS {
+                    registers.read_holding_register(address + i)?
+                } else {
+                    registers.read_input_register(address + i)?
+                };
+                let offset = 1 + 2 * i as usize;
+                BigEndian::write_u16(&mut out[offset..offset + 2], value);
+            }
+            Ok(1 + 2 * count as usize)
+        }
+        WRITE_SINGLE_COIL => {
+            if data.len() != 4 {
+                return Err(Exception::IllegalDataValue);
+            }
+            let address = BigEndian::read_u16(&data[0..2]);
+            let value = match BigEndian::read_u16(&data[2..4]) {
+                0x0000 => false,
+                0xFF00 => true,
+                _ => return Err(Exception::IllegalDataValue),
+            };
+            registers.write_coil(address, value)?;
+            // Echo of the request
+            out[..4].copy_from_slice(data);
+            Ok(4)
+        }
+        WRITE_SINGLE_REGISTER => {
+            if data.len() != 4 {
+                return Err(Exception::IllegalDataValue);
+            }
+            let address = BigEndian::read_u16(&data[0..2]);
+            let value = BigEndian::read_u16(&data[2..4]);
+            registers.write_registers(address, &[value])?;
+            out[..4].copy_from_slice(data);
+            Ok(4)
+        }
+        WRITE_MULTIPLE_REGISTERS => {
+            if data.len() < 5 {
+                return Err(Exception::IllegalDataValue);
+            }
+            let address = BigEndian::read_u16(&data[0..2]);
+            let count = BigEndian::read_u16(&data[2..4]);
+            let bytes = data[4] as usize;
+            if count == 0 || count > MAX_WRITE_REGISTERS ||
+               bytes != 2 * count as usize || data.len() != 5 + bytes {
+                return Err(Exception::IllegalDataValue);
+            }
+            let mut values = [0u16; MAX_WRITE_REGISTERS as usize];
+            let values = &mut values[..count as usize];
+            BigEndian::read_u16_into(&data[5..], values);
+            registers.write_registers(address, values)?;
+            out[..4].copy_from_slice(&data[..4]);
+            Ok(4)
+        }
+        _ =>
+            Err(Exception::IllegalFunction),
+    }
+}
+
+/// Registers holding an `f32`, high word first
+pub fn float_registers(value: f32) -> [u16; 2] {
+    let bits = value.to_bits();
+    [(bits >> 16) as u16, bits as u16]
+}
+
+pub fn registers_float(registers: [u16; 2]) -> f32 {
+    f32::from_bits((u32::from(registers[0]) << 16) | u32::from(registers[1]))
+}
+
+/// Channel and offset of an address
+fn channel_address(address: u16) -> Result<(usize, usize), Exception> {
+    let channel = (address / CHANNEL_STRIDE) as usize;
+    if channel < CHANNELS {
+        Ok((channel, (address % CHANNEL_STRIDE) as usize))
+    } else {
+        Err(Exception::IllegalDataAddress)
+    }
+}
+
+/// Register map of the device, see README. Writes are turned into
+/// commands for `Device`.
+pub struct ModbusDevice<D: Device> {
+    device: D,
+    /// Modbus has no authentication, writes are refused unless
+    /// enabled with `modbus write on`
+    writable: bool,
+}
+
+impl<D: Device> ModbusDevice<D> {
+    pub fn new(device: D, writable: bool) -> Self {
+        ModbusDevice { device, writable }
+    }
+
+    /// Coils and holding registers exist for TEC channels only
+    fn check_tec(&self, channel: usize) -> Result<(), Exception> {
+        if self.device.has_tec(channel) {
+            Ok(())
+        } else {
+            Err(Exception::IllegalDataAddress)
+        }
+    }
+
+    fn float(&mut self, channel: usize, parameter: PidParameter) -> f32 {
+        match self.device.query(channel, Parameter::Pid(parameter)) {
+            QueryValue::Float(value) => value,
+            _ => core::f32::NAN,
+        }
+    }
+
+    fn pwm(&mut self, channel: usize, pin: TecPin) -> Result<(u16, u16), Exception> {
+        match self.device.query(channel, Parameter::Pwm(pin)) {
+            QueryValue::Pwm(width, total) => Ok((width, total)),
+            _ => Err(Exception::IllegalDataAddress),
+        }
+    }
+
+    /// Check and apply the commands as a whole
+    fn execute(&mut self, channel: usize, commands: &[Option<Command>]) -> Result<(), Exception> {
+        if !self.writable {
+            return Err(Exception::IllegalFunction);
+        }
+        if !self.device.locks().may_write(channel, REMOTE_SESSION) {
+            return Err(Exception::ServerDeviceBusy);
+        }
+        for command in commands.iter().flatten() {
+            self.device.check(command)
+                .map_err(|_| Exception::IllegalDataValue)?;
+        }
+        for command in commands.iter().flatten() {
+            self.device.apply(command.clone());
+        }
+        Ok(())
+    }
+}
+
+impl<D: Device> Registers for ModbusDevice<D> {
+    fn read_coil(&mut self, address: u16) -> Result<bool, Exception> {
+        let (channel, offset) = channel_address(address)?;
+        self.check_tec(channel)?;
+        match offset {
+            0 => Ok(self.device.pid_enabled(channel)),
+            _ => Err(Exception::IllegalDataAddress),
+        }
+    }
+
+    fn read_discrete_input(&mut self, address: u16) -> Result<bool, Exception> {
+        let (channel, offset) = channel_address(address)?;
+        let pid = self.device.report(channel).and_then(|sample| sample.pid);
+        match offset {
+            0 => Ok(self.device.conversion_error(channel)),
+            1 => Ok(pid.map(|output| output.integral_saturated).unwrap_or(false)),
+            2 => Ok(pid.map(|output| output.output_saturated).unwrap_or(false)),
+            3 => Ok(self.device.locks().owner(channel).is_some()),
+            _ => Err(Exception::IllegalDataAddress),
+        }
+    }
+
+    fn read_holding_register(&mut self, address: u16) -> Result<u16, Exception> {
+        let (channel, offset) = channel_address(address)?;
+        self.check_tec(channel)?;
+        // Pairs of registers
+        let (index, word) = (offset / 2, offset % 2);
+        if index < PidParameter::ALL.len() {
+            let value = self.float(channel, PidParameter::ALL[index]);
+            Ok(float_registers(value)[word])
+        } else if index < PidParameter::ALL.len() + PWM_LIMITS.len() {
+            let pin = PWM_LIMITS[index - PidParameter::ALL.len()];
+            let (width, total) = self.pwm(channel, pin)?;
+            Ok(if word == 0 { width } else { total })
+        } else {
+            Err(Exception::IllegalDataAddress)
+        }
+    }
+
+    fn read_input_register(&mut self, address: u16) -> Result<u16, Exception> {
+        let (channel, offset) = channel_address(address)?;
+        let sample = self.device.report(channel);
+        let temperature = sample.map(|sample| sample.temperature)
+            .unwrap_or(core::f32::NAN);
+        let raw = sample.map(|sample| sample.raw).unwrap_or(0);
+        let error = sample.and_then(|sample| sample.pid)
+            .map(|output| output.error)
+            .unwrap_or(core::f32::NAN);
+        match offset {
+            0 | 1 => Ok(float_registers(temperature)[offset]),
+            2 => Ok((raw >> 16) as u16),
+            3 => Ok(raw as u16),
+            4 => Ok(self.pwm(channel, TecPin::ISet)?.0),
+            5 | 6 => Ok(float_registers(error)[offset - 5]),
+            _ => Err(Exception::IllegalDataAddress),
+        }
+    }
+
+    fn write_coil(&mut self, address: u16, value: bool) -> Result<(), Exception> {
+        let (channel, offset) = channel_address(address)?;
+        self.check_tec(channel)?;
+        if offset != 0 {
+            return Err(Exception::IllegalDataAddress);
+        }
+        let setup = self.device.output_setup(channel, value);
+        self.execute(channel, &[Some(Command::Pwm { channels: Channels::One(channel), setup })])
+    }
+
+    fn write_registers(&mut self, address: u16, values: &[u16]) -> Result<(), Exception> {
+        let (channel, start) = channel_address(address)?;
+        self.check_tec(channel)?;
+        // Whole pairs only, floats and width/total can not be split
+        let end = start + values.len();
+        let len = 2 * (PidParameter::ALL.len() + PWM_LIMITS.len());
+        if start % 2 != 0 || end % 2 != 0 || end > len {
+            return Err(Exception::IllegalDataAddress);
+        }
+
+        let mut assignments = PidAssignments::new();
+        let mut limits = [None; 3];
+        for (i, pair) in values.chunks(2).enumerate() {
+            let index = start / 2 + i;
+            if index < PidParameter::ALL.len() {
+                let value = registers_float([pair[0], pair[1]]);
+                // Each parameter once
+                let _ = assignments.assign(PidParameter::ALL[index], value);
+            } else {
+                let config = PwmConfig { width: pair[0], total: pair[1] };
+                limits[index - PidParameter::ALL.len()] = Some(config);
+            }
+        }
+
+        let channels = Channels::One(channel);
+        let mut commands = [None, None, None, None];
+        if assignments.iter().next().is_some() {
+            commands[0] = Some(Command::PidAssign { channels, assignments });
+        }
+        for (i, limit) in limits.iter().enumerate() {
+            commands[1 + i] = limit.map(|config| {
+                let setup = match PWM_LIMITS[i] {
+                    TecPin::MaxIPos => PwmSetup::MaxIPos(config),
+                    TecPin::MaxINeg => PwmSetup::MaxINeg(config),
+                    _ => PwmSetup::MaxV(config),
+                };
+                Command::Pwm { channels, setup }
+            });
+        }
+        self.execute(channel, &commands)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::command_parser::PwmMode;
+    use crate::device::test::TestDevice;
+
+    /// Coils 0-9, holding registers 0-9
+    struct TestRegisters {
+        coils: [bool; 10],
+        holding: [u16; 10],
+    }
+
+    impl Registers for TestRegisters {
+        fn read_coil(&mut self, address: u16) -> Result<bool, Exception> {
+            self.coils.get(address as usize).cloned()
+                .ok_or(Exception::IllegalDataAddress)
+        }
+
+        fn read_discrete_input(&mut self, _: u16) -> Result<bool, Exception> {
+            Err(Exception::IllegalDataAddress)
+        }
+
+        fn read_holding_register(&mut self, address: u16) -> Result<u16, Exception> {
+            self.holding.get(address as usize).cloned()
+                .ok_or(Exception::IllegalDataAddress)
+        }
+
+        fn read_input_register(&mut self, _: u16) -> Result<u16, Exception> {
+            Err(Exception::IllegalDataAddress)
+        }
+
+        fn write_coil(&mut self, address: u16, value: bool) -> Result<(), Exception> {
+            *self.coils.get_mut(address as usize)
+                .ok_or(Exception::IllegalDataAddress)? = value;
+            Ok(())
+        }
+
+        fn write_registers(&mut self, address: u16, values: &[u16]) -> Result<(), Exception> {
+            let start = address as usize;
+            if start + values.len() > self.holding.len() {
+                return Err(Exception::IllegalDataAddress);
+            }
+            self.holding[start..start + values.len()].copy_from_slice(values);
+            Ok(())
+        }
+    }
+
+    fn registers() -> TestRegisters {
+        TestRegisters {
+            coils: [false; 10],
+            holding: [0; 10],
+        }
+    }
+
+    /// Feed a request through a `Connection` and `handle`
+    fn request(registers: &mut TestRegisters, frame: &[u8]) -> ([u8; ADU_LEN], usize) {
+        let mut connection = Connection::new();
+        assert_eq!(connection.fill(frame), frame.len());
+        let frame = connection.frame().unwrap().unwrap();
+        let mut response = [0; ADU_LEN];
+        let len = handle(frame, registers, &mut response);
+        (response, len)
+    }
+
+    #[test]
+    fn incomplete_frame() {
+        let mut connection = Connection::new();
+        connection.fill(&[0, 1, 0, 0, 0, 6, 1, 3, 0]);
+        assert_eq!(connection.frame(), Ok(None));
+        connection.fill(&[0, 0, 1]);
+        assert_eq!(connection.frame().unwrap().map(|frame| frame.len()), Some(12));
+        connection.consume(12);
+        assert_eq!(connection.frame(), Ok(None));
+    }
+
+    #[test]
+    fn invalid_protocol() {
+        let mut connection = Connection::new();
+        connection.fill(&[0, 1, 0, 1, 0, 6, 1, 3, 0, 0, 0, 1]);
+        assert_eq!(connection.frame(), Err(InvalidFrame));
+    }
+
+    #[test]
+    fn write_read_registers() {
+        let mut registers = registers();
+        let (response, len) = request(
+            &mut registers,
+            &[0, 7, 0, 0, 0, 11, 1, WRITE_MULTIPLE_REGISTERS, 0, 2, 0, 2, 4, 0x12, 0x34, 0x56, 0x78]
+        );
+        assert_eq!(&response[..len], &[0, 7, 0, 0, 0, 6, 1, WRITE_MULTIPLE_REGISTERS, 0, 2, 0, 2]);
+        let (response, len) = request(
+            &mut registers,
+            &[0, 8, 0, 0, 0, 6, 1, READ_HOLDING_REGISTERS, 0, 2, 0, 2]
+        );
+        assert_eq!(&response[..len], &[0, 8, 0, 0, 0, 7, 1, READ_HOLDING_REGISTERS, 4, 0x12, 0x34, 0x56, 0x78]);
+    }
+
+    #[test]
+    fn read_coils() {
+        let mut registers = registers();
+        registers.coils[0] = true;
+        registers.coils[9] = true;
+        let (response, len) = request(&mut registers, &[0, 1, 0, 0, 0, 6, 1, READ_COILS, 0, 0, 0, 10]);
+        assert_eq!(&response[..len], &[0, 1, 0, 0, 0, 5, 1, READ_COILS, 2, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn exception() {
+        let mut registers = registers();
+        let (response, len) = request(&mut registers, &[0, 1, 0, 0, 0, 6, 1, READ_HOLDING_REGISTERS, 0, 9, 0, 2]);
+        assert_eq!(&response[..len], &[0, 1, 0, 0, 0, 3, 1, READ_HOLDING_REGISTERS | EXCEPTION, 2]);
+        let (response, len) = request(&mut registers, &[0, 1, 0, 0, 0, 2, 1, 0x2B]);
+        assert_eq!(&response[..len], &[0, 1, 0, 0, 0, 3, 1, 0x2B | EXCEPTION, 1]);
+    }
+
+    #[test]
+    fn float() {
+        assert_eq!(registers_float(float_registers(300.25)), 300.25);
+        assert_eq!(float_registers(1.0), [0x3F80, 0x0000]);
+    }
+
+    #[test]
+    fn monitor_only() {
+        let mut device = ModbusDevice::new(TestDevice::new(), true);
+        assert_eq!(device.read_input_register(CHANNEL_STRIDE), Ok(float_registers(300.0)[0]));
+        assert_eq!(device.read_input_register(CHANNEL_STRIDE + 4), Err(Exception::IllegalDataAddress));
+        assert_eq!(device.read_coil(CHANNEL_STRIDE), Err(Exception::IllegalDataAddress));
+        assert_eq!(device.read_holding_register(CHANNEL_STRIDE), Err(Exception::IllegalDataAddress));
+        assert_eq!(device.write_coil(CHANNEL_STRIDE, false), Err(Exception::IllegalDataAddress));
+        assert_eq!(device.write_registers(CHANNEL_STRIDE, &[0, 0]), Err(Exception::IllegalDataAddress));
+        assert_eq!(device.device.applied, None);
+
+        // Turning PID off holds the output of a TEC channel
+        assert_eq!(device.write_coil(0, false), Ok(()));
+        let setup = PwmSetup::ISet(PwmMode::Manual(PwmConfig { width: 100, total: 200 }));
+        assert_eq!(device.device.applied, Some(Command::Pwm { channels: Channels::One(0), setup }));
+    }
+}
@@ -0,0 +1,93 @@
+// Password for read-write access
+//
+// Stored as the SHA-256 hash of a salt followed by the password.
+// Telnet and SCPI sessions start read-only and need `auth`, HTTP
+// changes need basic authentication and firmware uploads carry the
+// password. Until a password is set, any password is accepted, so
+// that write access is still asked for explicitly.
+
+use super::command_parser::PASSWORD_LEN;
+use super::sha256::sha256;
+
+pub const SALT_LEN: usize = 16;
+
+#[derive(Clone, Copy)]
+pub enum Credentials {
+    /// No password set, read-write access for everyone
+    Unset,
+    Set { salt: [u8; SALT_LEN], hash: [u8; 32] },
+}
+
+impl Credentials {
+    pub fn new(password: &[u8], salt: [u8; SALT_LEN]) -> Self {
+        Credentials::Set { salt, hash: salted_hash(&salt, password) }
+    }
+
+    pub fn is_set(&self) -> bool {
+        match self {
+            Credentials::Unset => false,
+            Credentials::Set { .. } => true,
+        }
+    }
+
+    /// Whether `password` matches the one set, `false` while none
+    /// is set
+    pub fn verify(&self, password: &[u8]) -> bool {
+        match self {
+            Credentials::Unset =>
+                false,
+            Credentials::Set { salt, hash } =>
+                password.len() <= PASSWORD_LEN &&
+                constant_time_eq(&salted_hash(salt, password), hash),
+        }
+    }
+
+    /// Read-write access with `password`: any while no password is
+    /// set
+    pub fn permits(&self, password: &[u8]) -> bool {
+        !self.is_set() || self.verify(password)
+    }
+}
+
+fn salted_hash(salt: &[u8; SALT_LEN], password: &[u8]) -> [u8; 32] {
+    let mut buf = [0; SALT_LEN + PASSWORD_LEN];
+    let password = &password[..password.len().min(PASSWORD_LEN)];
+    buf[..SALT_LEN].copy_from_slice(salt);
+    buf[SALT_LEN..SALT_LEN + password.len()].copy_from_slice(password);
+    sha256(&buf[..SALT_LEN + password.len()])
+}
+
+/// Compares all bytes, so that the time taken does not depend on
+/// the position of the first mismatch
+fn constant_time_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn verify() {
+        let credentials = Credentials::new(b"secret", [1; SALT_LEN]);
+        assert!(credentials.is_set());
+        assert!(credentials.verify(b"secret"));
+        assert!(!credentials.verify(b"Secret"));
+        assert!(!credentials.verify(b""));
+        assert!(!credentials.permits(b"wrong"));
+    }
+
+    #[test]
+    fn salted() {
+        assert!(salted_hash(&[1; SALT_LEN], b"secret") != salted_hash(&[2; SALT_LEN], b"secret"));
+        assert!(Credentials::new(b"secret", [2; SALT_LEN]).verify(b"secret"));
+    }
+
+    #[test]
+    fn unset() {
+        assert!(!Credentials::Unset.verify(b""));
+        assert!(!Credentials::Unset.verify(b"anything"));
+        assert!(Credentials::Unset.permits(b"anything"));
+    }
+}
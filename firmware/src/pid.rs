@@ -9,12 +9,31 @@ pub struct Parameters {
     pub integral_max: f32
 }
 
-#[derive(Clone)]
+/// Terms of one controller update
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Output {
+    /// target - input
+    pub error: f32,
+    pub p: f32,
+    pub i: f32,
+    pub d: f32,
+    /// p + i + d
+    pub unclamped: f32,
+    /// Within `output_min..=output_max`
+    pub output: f32,
+    /// Integral held at `integral_min` or `integral_max`
+    pub integral_saturated: bool,
+    /// Output held at `output_min` or `output_max`
+    pub output_saturated: bool,
+}
+
+#[derive(Clone, Copy)]
 pub struct Controller {
     parameters: Parameters,
     target: f32,
     integral: f32,
-    last_input: Option<f32>
+    last_input: Option<f32>,
+    last_output: Option<Output>
 }
 
 impl Controller {
@@ -23,21 +42,25 @@ impl Controller {
             parameters: parameters,
             target: 0.0,
             last_input: None,
-            integral: 0.0
+            integral: 0.0,
+            last_output: None
         }
     }
 
-    pub fn update(&mut self, input: f32) -> f32 {
+    pub fn update(&mut self, input: f32) -> Output {
         let error = self.target - input;
 
         let p = self.parameters.kp * error;
 
         self.integral += error;
+        let mut integral_saturated = false;
         if self.integral < self.parameters.integral_min {
             self.integral = self.parameters.integral_min;
+            integral_saturated = true;
         }
         if self.integral > self.parameters.integral_max {
             self.integral = self.parameters.integral_max;
+            integral_saturated = true;
         }
         let i = self.parameters.ki * self.integral;
 
@@ -47,16 +70,31 @@ impl Controller {
         };
         self.last_input = Some(input);
 
-        let mut output = p + i + d;
+        let unclamped = p + i + d;
+        let mut output = unclamped;
+        let mut output_saturated = false;
         if output < self.parameters.output_min {
             output = self.parameters.output_min;
+            output_saturated = true;
         }
         if output > self.parameters.output_max {
             output = self.parameters.output_max;
+            output_saturated = true;
         }
+        let output = Output {
+            error, p, i, d,
+            unclamped, output,
+            integral_saturated, output_saturated,
+        };
+        self.last_output = Some(output);
         output
     }
 
+    /// Result of the previous `update()`
+    pub fn get_last_output(&self) -> Option<Output> {
+        self.last_output
+    }
+
     pub fn get_target(&self) -> f32 {
         self.target
     }
@@ -77,6 +115,7 @@ impl Controller {
     pub fn reset(&mut self) {
         self.integral = 0.0;
         self.last_input = None;
+        self.last_output = None;
     }
 }
 
@@ -111,7 +150,7 @@ mod test {
         while !values.iter().all(|value| target.contains(value)) {
             let next_t = (t + 1) % DELAY;
             // Feed the oldest temperature
-            let output = pid.update(values[next_t]);
+            let output = pid.update(values[next_t]).output;
             // Overwrite oldest with previous temperature + output
             values[next_t] = values[t] + output;
             t = next_t;
@@ -119,4 +158,17 @@ mod test {
         }
         dbg!(values[t], total_t);
     }
+
+    #[test]
+    fn test_output_saturated() {
+        let mut pid = Controller::new(PARAMETERS.clone());
+        pid.set_target(1000.0);
+        let output = pid.update(0.0);
+        assert_eq!(output.error, 1000.0);
+        assert_eq!(output.unclamped, output.p + output.i + output.d);
+        assert_eq!(output.output, PARAMETERS.output_max);
+        assert!(output.output_saturated);
+        assert!(output.integral_saturated);
+        assert_eq!(pid.get_last_output(), Some(output));
+    }
 }
@@ -0,0 +1,90 @@
+// Fixed-capacity buffer that collects the bytes of a message from a
+// TCP connection
+
+/// Array that backs a `ReceiveBuffer`, implemented for the sizes in
+/// use
+pub trait Array {
+    const EMPTY: Self;
+    fn as_slice(&self) -> &[u8];
+    fn as_mut_slice(&mut self) -> &mut [u8];
+}
+
+macro_rules! impl_array {
+    ($($len: expr),*) => {
+        $(
+            impl Array for [u8; $len] {
+                const EMPTY: Self = [0; $len];
+
+                fn as_slice(&self) -> &[u8] {
+                    self
+                }
+
+                fn as_mut_slice(&mut self) -> &mut [u8] {
+                    self
+                }
+            }
+        )*
+    };
+}
+
+impl_array!(260, 1024);
+
+pub struct ReceiveBuffer<A> {
+    buf: A,
+    len: usize,
+}
+
+impl<A: Array> ReceiveBuffer<A> {
+    pub const fn new() -> Self {
+        ReceiveBuffer {
+            buf: A::EMPTY,
+            len: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == self.buf.as_slice().len()
+    }
+
+    /// Bytes received so far
+    pub fn contents(&self) -> &[u8] {
+        &self.buf.as_slice()[..self.len]
+    }
+
+    /// Take as much of `data` as fits, returns the number of bytes
+    /// taken
+    pub fn fill(&mut self, data: &[u8]) -> usize {
+        let buf = self.buf.as_mut_slice();
+        let len = data.len().min(buf.len() - self.len);
+        buf[self.len..self.len + len].copy_from_slice(&data[..len]);
+        self.len += len;
+        len
+    }
+
+    /// Remove `len` bytes from the start
+    pub fn consume(&mut self, len: usize) {
+        self.buf.as_mut_slice().copy_within(len..self.len, 0);
+        self.len -= len;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fill_consume() {
+        let mut buffer = ReceiveBuffer::<[u8; 260]>::new();
+        assert_eq!(buffer.fill(&[1, 2, 3]), 3);
+        buffer.consume(2);
+        assert_eq!(buffer.contents(), &[3]);
+        assert_eq!(buffer.fill(&[0; 300]), 259);
+        assert!(buffer.is_full());
+        buffer.reset();
+        assert!(buffer.contents().is_empty());
+    }
+}
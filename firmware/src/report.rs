@@ -0,0 +1,226 @@
+use super::command_parser::{ReportSetting, ReportFields};
+use super::CHANNELS;
+use super::pid;
+
+/// Input of one channel after a conversion
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    /// µs
+    pub time: u64,
+    pub raw: i32,
+    /// Ohm
+    pub resistance: f32,
+    /// K
+    pub temperature: f32,
+    /// Width set by the PID controller
+    pub pwm: Option<u16>,
+    /// While PID controls the PWM
+    pub pid: Option<pid::Output>,
+}
+
+/// Per-session settings of `report mode on`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReportConfig {
+    pub channels: [bool; CHANNELS],
+    pub decimation: u16,
+    /// ms, 0 for none
+    pub interval: u16,
+    pub average: bool,
+    pub fields: ReportFields,
+}
+
+impl ReportConfig {
+    pub fn new() -> Self {
+        ReportConfig {
+            channels: [true; CHANNELS],
+            decimation: 1,
+            interval: 0,
+            average: false,
+            fields: ReportFields::default(),
+        }
+    }
+
+    pub fn apply(&mut self, setting: ReportSetting) {
+        match setting {
+            ReportSetting::Channels(channels) => {
+                self.channels = [false; CHANNELS];
+                for channel in channels.iter() {
+                    self.channels[channel] = true;
+                }
+            }
+            ReportSetting::Decimation(decimation) =>
+                self.decimation = decimation,
+            ReportSetting::Interval(interval) =>
+                self.interval = interval,
+            ReportSetting::Average(average) =>
+                self.average = average,
+            ReportSetting::Fields(fields) =>
+                self.fields = fields,
+        }
+    }
+}
+
+/// Sums of the samples since the previous report
+#[derive(Clone, Copy, Debug)]
+struct Sums {
+    count: u32,
+    raw: i64,
+    resistance: f32,
+    temperature: f32,
+    pwm: u64,
+    pwm_count: u32,
+    /// Saturation flags are set if set for any sample
+    pid: pid::Output,
+    pid_count: u32,
+}
+
+impl Sums {
+    const fn new() -> Self {
+        Sums {
+            count: 0,
+            raw: 0,
+            resistance: 0.0,
+            temperature: 0.0,
+            pwm: 0,
+            pwm_count: 0,
+            pid: pid::Output {
+                error: 0.0,
+                p: 0.0,
+                i: 0.0,
+                d: 0.0,
+                unclamped: 0.0,
+                output: 0.0,
+                integral_saturated: false,
+                output_saturated: false,
+            },
+            pid_count: 0,
+        }
+    }
+
+    fn add(&mut self, sample: &Sample) {
+        self.count += 1;
+        self.raw += i64::from(sample.raw);
+        self.resistance += sample.resistance;
+        self.temperature += sample.temperature;
+        if let Some(pwm) = sample.pwm {
+            self.pwm += u64::from(pwm);
+            self.pwm_count += 1;
+        }
+        if let Some(output) = sample.pid {
+            let sum = &mut self.pid;
+            sum.error += output.error;
+            sum.p += output.p;
+            sum.i += output.i;
+            sum.d += output.d;
+            sum.unclamped += output.unclamped;
+            sum.output += output.output;
+            sum.integral_saturated |= output.integral_saturated;
+            sum.output_saturated |= output.output_saturated;
+            self.pid_count += 1;
+        }
+    }
+
+    /// Average, with the time of the last sample
+    fn average(&self, time: u64) -> Sample {
+        let count = self.count as f32;
+        Sample {
+            time,
+            raw: (self.raw / i64::from(self.count)) as i32,
+            resistance: self.resistance / count,
+            temperature: self.temperature / count,
+            pwm: if self.pwm_count > 0 {
+                Some((self.pwm / u64::from(self.pwm_count)) as u16)
+            } else {
+                None
+            },
+            pid: if self.pid_count > 0 {
+                let count = self.pid_count as f32;
+                let sum = &self.pid;
+                Some(pid::Output {
+                    error: sum.error / count,
+                    p: sum.p / count,
+                    i: sum.i / count,
+                    d: sum.d / count,
+                    unclamped: sum.unclamped / count,
+                    output: sum.output / count,
+                    ..*sum
+                })
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// Selects the samples of one channel to report
+#[derive(Clone, Copy, Debug)]
+pub struct Decimator {
+    sums: Sums,
+    last_report: Option<u64>,
+}
+
+impl Decimator {
+    pub const fn new() -> Self {
+        Decimator {
+            sums: Sums::new(),
+            last_report: None,
+        }
+    }
+
+    /// Returns what to report, if a report is due
+    pub fn feed(&mut self, config: &ReportConfig, sample: &Sample) -> Option<Sample> {
+        self.sums.add(sample);
+        if self.sums.count < u32::from(config.decimation) {
+            return None;
+        }
+        let interval = 1000 * u64::from(config.interval);
+        let due = self.last_report
+            .map(|last_report| sample.time >= last_report + interval)
+            .unwrap_or(true);
+        if !due {
+            return None;
+        }
+
+        let report = if config.average {
+            self.sums.average(sample.time)
+        } else {
+            *sample
+        };
+        self.sums = Sums::new();
+        self.last_report = Some(sample.time);
+        Some(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decimation_average() {
+        let mut config = ReportConfig::new();
+        config.apply(ReportSetting::Decimation(3));
+        config.apply(ReportSetting::Average(true));
+        let mut decimator = Decimator::new();
+        let first = Sample { time: 1, raw: 3, resistance: 1000.0, temperature: 300.0, pwm: None, pid: None };
+        assert_eq!(decimator.feed(&config, &first), None);
+        assert_eq!(decimator.feed(&config, &Sample { time: 2, raw: 6, ..first }), None);
+        assert_eq!(
+            decimator.feed(&config, &Sample { time: 3, raw: 9, ..first }),
+            Some(Sample { time: 3, raw: 6, ..first })
+        );
+        assert_eq!(decimator.feed(&config, &Sample { time: 4, raw: 3, ..first }), None);
+    }
+
+    #[test]
+    fn interval() {
+        let mut config = ReportConfig::new();
+        config.apply(ReportSetting::Interval(1));
+        let mut decimator = Decimator::new();
+        let first = Sample { time: 0, raw: 1, resistance: 1000.0, temperature: 300.0, pwm: None, pid: None };
+        assert_eq!(decimator.feed(&config, &first), Some(first));
+        assert_eq!(decimator.feed(&config, &Sample { time: 999, raw: 2, ..first }), None);
+        let due = Sample { time: 1000, raw: 3, ..first };
+        assert_eq!(decimator.feed(&config, &due), Some(due));
+    }
+}
@@ -0,0 +1,145 @@
+// Fixed-capacity FIFO that drops its oldest entry on overflow
+
+/// Array that backs a `RingBuffer`, implemented for the sizes in use
+pub trait Array {
+    type Item: Copy;
+    const EMPTY: Self;
+    fn as_slice(&self) -> &[Option<Self::Item>];
+    fn as_mut_slice(&mut self) -> &mut [Option<Self::Item>];
+}
+
+macro_rules! impl_array {
+    ($($len: expr),*) => {
+        $(
+            impl<T: Copy> Array for [Option<T>; $len] {
+                type Item = T;
+                const EMPTY: Self = [None; $len];
+
+                fn as_slice(&self) -> &[Option<T>] {
+                    self
+                }
+
+                fn as_mut_slice(&mut self) -> &mut [Option<T>] {
+                    self
+                }
+            }
+        )*
+    };
+}
+
+impl_array!(8, 16, 256);
+
+#[derive(Clone, Copy)]
+pub struct RingBuffer<A> {
+    buf: A,
+    /// Index of the oldest entry
+    pos: usize,
+    len: usize,
+    /// Entries dropped on overflow
+    lost: u32,
+}
+
+impl<A: Array> RingBuffer<A> {
+    pub const fn new() -> Self {
+        RingBuffer {
+            buf: A::EMPTY,
+            pos: 0,
+            len: 0,
+            lost: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.as_slice().len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == self.capacity()
+    }
+
+    /// Append an entry, dropping the oldest one when full
+    pub fn push(&mut self, item: A::Item) {
+        let capacity = self.capacity();
+        if self.len == capacity {
+            self.pos = (self.pos + 1) % capacity;
+            self.len -= 1;
+            self.lost += 1;
+        }
+        self.buf.as_mut_slice()[(self.pos + self.len) % capacity] = Some(item);
+        self.len += 1;
+    }
+
+    /// Take the oldest entry
+    pub fn pop(&mut self) -> Option<A::Item> {
+        if self.len == 0 {
+            return None;
+        }
+        let item = self.buf.as_mut_slice()[self.pos].take();
+        self.pos = (self.pos + 1) % self.capacity();
+        self.len -= 1;
+        item
+    }
+
+    /// Entry `index`, counted from the oldest
+    pub fn get(&self, index: usize) -> Option<&A::Item> {
+        if index >= self.len {
+            return None;
+        }
+        self.buf.as_slice()[(self.pos + index) % self.capacity()].as_ref()
+    }
+
+    /// Newest entry
+    pub fn last_mut(&mut self) -> Option<&mut A::Item> {
+        if self.len == 0 {
+            return None;
+        }
+        let index = (self.pos + self.len - 1) % self.capacity();
+        self.buf.as_mut_slice()[index].as_mut()
+    }
+
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+
+    /// Number of entries dropped since the previous call
+    pub fn take_lost(&mut self) -> u32 {
+        let lost = self.lost;
+        self.lost = 0;
+        lost
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fifo() {
+        let mut buffer = RingBuffer::<[Option<u32>; 8]>::new();
+        assert_eq!(buffer.capacity(), 8);
+        assert_eq!(buffer.pop(), None);
+        for i in 0..10 {
+            buffer.push(i);
+        }
+        assert!(buffer.is_full());
+        assert_eq!(buffer.take_lost(), 2);
+        assert_eq!(buffer.take_lost(), 0);
+        assert_eq!(buffer.get(0), Some(&2));
+        assert_eq!(buffer.get(7), Some(&9));
+        assert_eq!(buffer.get(8), None);
+        *buffer.last_mut().unwrap() = 0;
+        assert_eq!(buffer.pop(), Some(2));
+        assert_eq!(buffer.len(), 7);
+        buffer.clear();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.last_mut(), None);
+    }
+}
@@ -0,0 +1,172 @@
+use core::cell::RefCell;
+use cortex_m::interrupt::{free, CriticalSection, Mutex};
+use cortex_m::peripheral::NVIC;
+use tm4c129x::{self, interrupt, Interrupt};
+use crate::ad7172::{self, AdcError, Sample};
+use crate::board::gpio::{GpioOutput, PB4};
+#[cfg(not(feature = "hardware-spi"))]
+use crate::board::{
+    gpio::{GpioInput, PB5, PE4, PE5},
+    softspi::SyncSoftSpi,
+};
+use crate::board::systick::get_time;
+use crate::ring_buffer::RingBuffer;
+
+#[cfg(not(feature = "hardware-spi"))]
+pub type AdcSpi = SyncSoftSpi<GpioOutput<PB5>, GpioOutput<PE4>, GpioInput<PE5>, fn()>;
+#[cfg(feature = "hardware-spi")]
+pub type AdcSpi = crate::board::ssi::Ssi1;
+pub type Adc = ad7172::Adc<AdcSpi, GpioOutput<PB4>>;
+
+/// DOUT/RDY is MISO on PE5
+const RDY_MASK: u32 = 1 << 5;
+/// Read conversions on RDY interrupts. Edges on PE5 have not been
+/// verified with the SSI1 alternate function selected, so
+/// conversions are polled with `hardware-spi`.
+const RDY_INTERRUPT: bool = !cfg!(feature = "hardware-spi");
+const QUEUE_LEN: usize = 8;
+
+/// A conversion result with the time of its RDY edge
+#[derive(Clone, Copy)]
+pub struct TimedSample {
+    /// µs, the time of the read when polled
+    pub time: u64,
+    pub sample: Sample,
+}
+
+struct Queue {
+    /// Drops the oldest samples when the control loop does not
+    /// keep up
+    samples: RingBuffer<[Option<TimedSample>; QUEUE_LEN]>,
+    error: Option<AdcError<()>>,
+}
+
+impl Queue {
+    const fn new() -> Self {
+        Queue {
+            samples: RingBuffer::new(),
+            error: None,
+        }
+    }
+}
+
+static ADC: Mutex<RefCell<Option<Adc>>> = Mutex::new(RefCell::new(None));
+static QUEUE: Mutex<RefCell<Queue>> = Mutex::new(RefCell::new(Queue::new()));
+
+/// Clear the RDY edge that may have been caused by SPI traffic.
+///
+/// With `retrigger` the interrupt is pended again if another
+/// conversion is already waiting.
+fn rearm(retrigger: bool) {
+    let gpio = unsafe { &*tm4c129x::GPIO_PORTE_AHB::ptr() };
+    gpio.icr.write(|w| unsafe { w.bits(RDY_MASK) });
+    // Masked GPIODATA access, see `board::gpio`
+    let rdy = unsafe {
+        core::ptr::read_volatile((gpio as *const _ as *const u32).add(RDY_MASK as usize))
+    };
+    if retrigger && rdy == 0 {
+        NVIC::pend(Interrupt::GPIOE);
+    }
+}
+
+/// Hand the configured ADC over to the sampler.
+///
+/// From here on conversions are read by the RDY interrupt handler,
+/// or by `poll()`, and collected with `pop()`. Other ADC access
+/// must go through `with_adc()`.
+pub fn init(mut adc: Adc, nvic: &mut NVIC) {
+    adc.set_nss_hold(RDY_INTERRUPT);
+    free(|cs| {
+        ADC.borrow(cs).replace(Some(adc));
+        if !RDY_INTERRUPT {
+            return;
+        }
+
+        // Falling edge interrupt on PE5
+        let gpio = unsafe { &*tm4c129x::GPIO_PORTE_AHB::ptr() };
+        gpio.im.modify(|r, w| unsafe { w.bits(r.bits() & !RDY_MASK) });
+        gpio.is.modify(|r, w| unsafe { w.bits(r.bits() & !RDY_MASK) });
+        gpio.ibe.modify(|r, w| unsafe { w.bits(r.bits() & !RDY_MASK) });
+        gpio.iev.modify(|r, w| unsafe { w.bits(r.bits() & !RDY_MASK) });
+        gpio.icr.write(|w| unsafe { w.bits(RDY_MASK) });
+        gpio.im.modify(|r, w| unsafe { w.bits(r.bits() | RDY_MASK) });
+        nvic.enable(Interrupt::GPIOE);
+        rearm(true);
+    });
+}
+
+/// Read the conversions that are ready when they are not read on
+/// RDY interrupts
+///
+/// Each conversion is timestamped right when it is read, not when
+/// the call started.
+pub fn poll() {
+    if RDY_INTERRUPT {
+        return;
+    }
+    free(|cs| while read(cs, get_time()) {});
+}
+
+/// Access the ADC from the main loop
+pub fn with_adc<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Adc) -> R,
+{
+    free(|cs| {
+        let result = f(ADC.borrow(cs).borrow_mut().as_mut().unwrap());
+        if RDY_INTERRUPT {
+            rearm(true);
+        }
+        result
+    })
+}
+
+/// Take the oldest conversion from the queue
+pub fn pop() -> Option<TimedSample> {
+    free(|cs| QUEUE.borrow(cs).borrow_mut().samples.pop())
+}
+
+/// Take the last error and the number of overruns since the
+/// previous call
+pub fn take_errors() -> (Option<AdcError<()>>, u32) {
+    free(|cs| {
+        let mut queue = QUEUE.borrow(cs).borrow_mut();
+        let overruns = queue.samples.take_lost();
+        (queue.error.take(), overruns)
+    })
+}
+
+/// Read a conversion into the queue, returns whether one was
+/// ready
+fn read(cs: &CriticalSection, time: u64) -> bool {
+    let mut queue = QUEUE.borrow(cs).borrow_mut();
+    let mut adc = ADC.borrow(cs).borrow_mut();
+    let adc = match adc.as_mut() {
+        Some(adc) => adc,
+        None => return false,
+    };
+    match adc.read_sample() {
+        Ok(Some(sample)) => {
+            queue.samples.push(TimedSample { time, sample });
+            true
+        }
+        // Edge caused by SPI traffic
+        Ok(None) =>
+            false,
+        Err(e) => {
+            queue.error = Some(e);
+            // Start over with a new SPI frame
+            adc.resync();
+            false
+        }
+    }
+}
+
+#[interrupt]
+fn GPIOE() {
+    let time = get_time();
+    free(|cs| {
+        let retrigger = read(cs, time);
+        rearm(retrigger);
+    });
+}
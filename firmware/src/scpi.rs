@@ -0,0 +1,1108 @@
+// SCPI dialect for test automation, on its own TCP port
+//
+// A line holds one or more commands separated by `;`, each with its
+// full header from the root: headers relative to the previous command
+// are not supported. Header nodes match in short (`MEAS`) or long form
+// (`MEASURE`), case-insensitive. Channel lists `(@<ch>)` and
+// `(@<first>:<last>)` use the channel numbers of the telnet interface
+// and select all channels when omitted.
+
+use core::fmt;
+use lexical_core as lexical;
+use super::command_parser::{
+    Command, Channels, Parameter, PidParameter, Password, PwmMode, PwmSetup, PASSWORD_LEN,
+};
+use super::device::{selects_monitor_only, Device, QueryValue, REMOTE_SESSION};
+use super::password::Credentials;
+use super::session::{LineReader, LineResult, LineTooLong};
+use super::ring_buffer::RingBuffer;
+use super::validation::ValidationError;
+use super::{CHANNELS, VERSION};
+
+pub const PORT: u16 = 5025;
+/// Manufacturer and model of the `*IDN?` response
+const IDN: &str = "M-Labs,Thermostat";
+const ERROR_QUEUE_LEN: usize = 8;
+/// Parameters of a command
+const MAX_PARAMS: usize = 3;
+/// Response for values that are not available, SCPI's NaN
+pub const NOT_A_NUMBER: &str = "9.91E+37";
+const ZERO_CELSIUS: f32 = 273.15;
+
+// Standard event status register
+const ESR_OPC: u8 = 1 << 0;
+const ESR_QYE: u8 = 1 << 2;
+const ESR_DDE: u8 = 1 << 3;
+const ESR_EXE: u8 = 1 << 4;
+const ESR_CME: u8 = 1 << 5;
+// Status byte
+const STB_EAV: u8 = 1 << 2;
+const STB_ESB: u8 = 1 << 5;
+const STB_MSS: u8 = 1 << 6;
+
+/// Entries of the error queue, with SCPI error codes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScpiError {
+    SyntaxError,
+    DataTypeError,
+    ParameterNotAllowed,
+    MissingParameter,
+    UndefinedHeader,
+    CommandProtected,
+    /// Channel locked by a telnet session
+    SettingsConflict,
+    DataOutOfRange,
+    TooMuchData,
+    IllegalParameterValue,
+    /// Rejected by `validation`
+    Validation(ValidationError),
+    /// Monitor-only channel
+    HardwareMissing,
+    QueueOverflow,
+    InputBufferOverrun,
+}
+
+impl ScpiError {
+    pub fn code(&self) -> i16 {
+        match self {
+            ScpiError::SyntaxError => -102,
+            ScpiError::DataTypeError => -104,
+            ScpiError::ParameterNotAllowed => -108,
+            ScpiError::MissingParameter => -109,
+            ScpiError::UndefinedHeader => -113,
+            ScpiError::CommandProtected => -203,
+            ScpiError::SettingsConflict => -221,
+            ScpiError::DataOutOfRange => -222,
+            ScpiError::TooMuchData => -223,
+            ScpiError::IllegalParameterValue => -224,
+            ScpiError::Validation(_) => -222,
+            ScpiError::HardwareMissing => -241,
+            ScpiError::QueueOverflow => -350,
+            ScpiError::InputBufferOverrun => -363,
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self {
+            ScpiError::SyntaxError => "Syntax error",
+            ScpiError::DataTypeError => "Data type error",
+            ScpiError::ParameterNotAllowed => "Parameter not allowed",
+            ScpiError::MissingParameter => "Missing parameter",
+            ScpiError::UndefinedHeader => "Undefined header",
+            ScpiError::CommandProtected => "Command protected",
+            ScpiError::SettingsConflict => "Settings conflict",
+            ScpiError::DataOutOfRange |
+            ScpiError::Validation(_) => "Data out of range",
+            ScpiError::TooMuchData => "Too much data",
+            ScpiError::IllegalParameterValue => "Illegal parameter value",
+            ScpiError::HardwareMissing => "Hardware missing",
+            ScpiError::QueueOverflow => "Queue overflow",
+            ScpiError::InputBufferOverrun => "Input buffer overrun",
+        }
+    }
+
+    /// Event status register bit of the error class
+    fn event_status(&self) -> u8 {
+        match self.code() {
+            -199..=-100 => ESR_CME,
+            -299..=-200 => ESR_EXE,
+            -399..=-300 => ESR_DDE,
+            _ => ESR_QYE,
+        }
+    }
+}
+
+/// `SYST:ERR?` response
+impl fmt::Display for ScpiError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "{},\"{}", self.code(), self.message())?;
+        if let ScpiError::Validation(e) = self {
+            write!(fmt, ";{}", e)?;
+        }
+        write!(fmt, "\"")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TemperatureUnit {
+    Celsius,
+    Kelvin,
+}
+
+impl TemperatureUnit {
+    pub fn to_kelvin(self, value: f32) -> f32 {
+        match self {
+            TemperatureUnit::Celsius => value + ZERO_CELSIUS,
+            TemperatureUnit::Kelvin => value,
+        }
+    }
+
+    pub fn from_kelvin(self, kelvin: f32) -> f32 {
+        match self {
+            TemperatureUnit::Celsius => kelvin - ZERO_CELSIUS,
+            TemperatureUnit::Kelvin => kelvin,
+        }
+    }
+}
+
+impl fmt::Display for TemperatureUnit {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            TemperatureUnit::Celsius => "CEL".fmt(fmt),
+            TemperatureUnit::Kelvin => "K".fmt(fmt),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    /// `*IDN?`
+    Identify,
+    /// `*RST`
+    Reset,
+    /// `*CLS`
+    ClearStatus,
+    /// `*ESR?`
+    EventStatusQuery,
+    /// `*ESE <mask>`
+    EventStatusEnable(u8),
+    EventStatusEnableQuery,
+    /// `*STB?`
+    StatusByteQuery,
+    /// `*SRE <mask>`
+    ServiceRequestEnable(u8),
+    ServiceRequestEnableQuery,
+    /// `*OPC`, commands complete immediately
+    OperationComplete,
+    OperationCompleteQuery,
+    /// `*WAI`
+    Wait,
+    /// `*TST?`
+    SelfTestQuery,
+    /// `SYST:ERR?`
+    ErrorQuery,
+    /// `SYST:VERS?`
+    VersionQuery,
+    /// `SYST:PASS:CDIS`, back to read-only access
+    PasswordDisable,
+    /// `SYST:PASS:STAT?`
+    PasswordStateQuery,
+    /// `UNIT:TEMP CEL|K`
+    Unit(TemperatureUnit),
+    UnitQuery,
+    /// `MEAS:TEMP?`
+    MeasureTemperature(Channels),
+    /// `MEAS:RES?`
+    MeasureResistance(Channels),
+    /// `OUTP ON|OFF`, PID control of the TEC
+    Output(Channels, bool),
+    OutputQuery(Channels),
+    /// Setting queries like `SOUR:TEMP?`
+    Query(Channels, Parameter),
+    /// Commands of the telnet interface, `SYST:PASS:CEN` is `auth`
+    Command(Command),
+}
+
+impl Request {
+    /// Requests that are permitted without `SYST:PASS:CEN`
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            Request::Reset |
+            Request::Output(..) =>
+                false,
+            Request::Command(command) =>
+                command.is_read_only(),
+            _ =>
+                true,
+        }
+    }
+
+    /// Requests with a response
+    pub fn is_query(&self) -> bool {
+        match self {
+            Request::Identify |
+            Request::EventStatusQuery |
+            Request::EventStatusEnableQuery |
+            Request::StatusByteQuery |
+            Request::ServiceRequestEnableQuery |
+            Request::OperationCompleteQuery |
+            Request::SelfTestQuery |
+            Request::ErrorQuery |
+            Request::VersionQuery |
+            Request::PasswordStateQuery |
+            Request::UnitQuery |
+            Request::MeasureTemperature(_) |
+            Request::MeasureResistance(_) |
+            Request::OutputQuery(_) |
+            Request::Query(..) =>
+                true,
+            _ =>
+                false,
+        }
+    }
+}
+
+const PID_HEADERS: [(&str, PidParameter); 7] = [
+    ("[SOURce]:PID:KP", PidParameter::KP),
+    ("[SOURce]:PID:KI", PidParameter::KI),
+    ("[SOURce]:PID:KD", PidParameter::KD),
+    ("[SOURce]:PID:OUTPut:LOWer", PidParameter::OutputMin),
+    ("[SOURce]:PID:OUTPut:UPPer", PidParameter::OutputMax),
+    ("[SOURce]:PID:INTegral:LOWer", PidParameter::IntegralMin),
+    ("[SOURce]:PID:INTegral:UPPer", PidParameter::IntegralMax),
+];
+
+fn trim(input: &[u8]) -> &[u8] {
+    let is_text = |c: &u8| *c != b' ' && *c != b'\t';
+    let start = input.iter().position(is_text).unwrap_or(input.len());
+    let end = input.iter().rposition(is_text).map(|pos| pos + 1).unwrap_or(start);
+    &input[start..end]
+}
+
+/// Position of the first `separator` outside of strings and channel
+/// lists
+fn find_separator(input: &[u8], separator: u8) -> Option<usize> {
+    let mut quote = None;
+    let mut list = false;
+    for (i, c) in input.iter().enumerate() {
+        match (quote, *c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, b'"') | (None, b'\'') => quote = Some(*c),
+            (None, b'(') => list = true,
+            (None, b')') => list = false,
+            (None, c) if c == separator && !list => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Commands of a line
+pub struct Units<'a> {
+    rest: Option<&'a [u8]>,
+}
+
+impl<'a> Iterator for Units<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let input = self.rest?;
+            let unit = match find_separator(input, b';') {
+                Some(pos) => {
+                    self.rest = Some(&input[pos + 1..]);
+                    &input[..pos]
+                }
+                None => {
+                    self.rest = None;
+                    input
+                }
+            };
+            let unit = trim(unit);
+            if unit.len() > 0 {
+                return Some(unit);
+            }
+        }
+    }
+}
+
+pub fn units<'a>(line: &'a [u8]) -> Units<'a> {
+    Units { rest: Some(line) }
+}
+
+/// Header and parameters of a command
+struct Unit<'a> {
+    header: &'a [u8],
+    query: bool,
+    params: [&'a [u8]; MAX_PARAMS],
+    len: usize,
+}
+
+impl<'a> Unit<'a> {
+    fn split(input: &'a [u8]) -> Result<Self, ScpiError> {
+        let pos = input.iter()
+            .position(|c| *c == b' ' || *c == b'\t')
+            .unwrap_or(input.len());
+        let (mut header, mut rest) = input.split_at(pos);
+        let query = header.last() == Some(&b'?');
+        if query {
+            header = &header[..header.len() - 1];
+        }
+        if header.is_empty() {
+            return Err(ScpiError::SyntaxError);
+        }
+
+        let mut unit = Unit { header, query, params: [&[][..]; MAX_PARAMS], len: 0 };
+        rest = trim(rest);
+        while rest.len() > 0 {
+            if unit.len == MAX_PARAMS {
+                return Err(ScpiError::ParameterNotAllowed);
+            }
+            let param = match find_separator(rest, b',') {
+                Some(pos) => {
+                    let param = &rest[..pos];
+                    rest = &rest[pos + 1..];
+                    if trim(rest).is_empty() {
+                        return Err(ScpiError::SyntaxError);
+                    }
+                    param
+                }
+                None => {
+                    let param = rest;
+                    rest = &[];
+                    param
+                }
+            };
+            let param = trim(param);
+            if param.is_empty() {
+                return Err(ScpiError::SyntaxError);
+            }
+            unit.params[unit.len] = param;
+            unit.len += 1;
+        }
+        Ok(unit)
+    }
+
+    fn params(&self) -> &[&'a [u8]] {
+        &self.params[..self.len]
+    }
+
+    fn no_params(&self) -> Result<(), ScpiError> {
+        if self.len > 0 {
+            Err(ScpiError::ParameterNotAllowed)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The only parameter
+    fn param(&self) -> Result<&'a [u8], ScpiError> {
+        match self.len {
+            0 => Err(ScpiError::MissingParameter),
+            1 => Ok(self.params[0]),
+            _ => Err(ScpiError::ParameterNotAllowed),
+        }
+    }
+
+    /// Optional channel list after `values` parameters
+    fn channels(&self, values: usize) -> Result<Channels, ScpiError> {
+        if self.len < values {
+            Err(ScpiError::MissingParameter)
+        } else if self.len == values {
+            Ok(Channels::All)
+        } else if self.len == values + 1 {
+            channel_list(self.params[values])
+        } else {
+            Err(ScpiError::ParameterNotAllowed)
+        }
+    }
+}
+
+/// Match a header node against a mnemonic, whose short form is
+/// written in upper case
+fn mnemonic_matches(mnemonic: &[u8], node: &[u8]) -> bool {
+    let short_len = mnemonic.iter()
+        .take_while(|c| !c.is_ascii_lowercase())
+        .count();
+    node.eq_ignore_ascii_case(&mnemonic[..short_len]) ||
+        node.eq_ignore_ascii_case(mnemonic)
+}
+
+/// Match a header against a pattern like `MEASure[:SCALar]:TEMPerature`,
+/// with optional nodes in brackets
+fn header_matches(pattern: &[u8], header: &[u8]) -> bool {
+    let header = if header.first() == Some(&b':') { &header[1..] } else { header };
+    let pattern = if pattern.first() == Some(&b':') { &pattern[1..] } else { pattern };
+    if pattern.is_empty() {
+        return header.is_empty();
+    }
+
+    let (mnemonic, optional, pattern_rest) = if pattern[0] == b'[' {
+        let end = pattern.iter().position(|c| *c == b']').unwrap();
+        let mnemonic = &pattern[1..end];
+        let mnemonic = if mnemonic.first() == Some(&b':') { &mnemonic[1..] } else { mnemonic };
+        (mnemonic, true, &pattern[end + 1..])
+    } else {
+        let end = pattern.iter()
+            .position(|c| *c == b':' || *c == b'[')
+            .unwrap_or(pattern.len());
+        (&pattern[..end], false, &pattern[end..])
+    };
+    if optional && header_matches(pattern_rest, header) {
+        return true;
+    }
+    let end = header.iter().position(|c| *c == b':').unwrap_or(header.len());
+    let (node, header_rest) = header.split_at(end);
+    mnemonic_matches(mnemonic, node) && header_matches(pattern_rest, header_rest)
+}
+
+fn number(param: &[u8]) -> Result<f32, ScpiError> {
+    lexical::parse(param)
+        .map_err(|_| ScpiError::DataTypeError)
+}
+
+/// Register mask, 0-255
+fn mask(param: &[u8]) -> Result<u8, ScpiError> {
+    let value: u16 = lexical::parse(param)
+        .map_err(|_| ScpiError::DataTypeError)?;
+    if value > 255 {
+        return Err(ScpiError::DataOutOfRange);
+    }
+    Ok(value as u8)
+}
+
+fn boolean(param: &[u8]) -> Result<bool, ScpiError> {
+    if param.eq_ignore_ascii_case(b"ON") || param == b"1" {
+        Ok(true)
+    } else if param.eq_ignore_ascii_case(b"OFF") || param == b"0" {
+        Ok(false)
+    } else {
+        Err(ScpiError::DataTypeError)
+    }
+}
+
+fn channel_number(digits: &[u8]) -> Result<usize, ScpiError> {
+    let channel: usize = lexical::parse(trim(digits))
+        .map_err(|_| ScpiError::SyntaxError)?;
+    if channel < CHANNELS {
+        Ok(channel)
+    } else {
+        Err(ScpiError::DataOutOfRange)
+    }
+}
+
+/// `(@<ch>)` or `(@<first>:<last>)`
+fn channel_list(param: &[u8]) -> Result<Channels, ScpiError> {
+    if !param.starts_with(b"(@") || !param.ends_with(b")") {
+        return Err(ScpiError::DataTypeError);
+    }
+    let list = &param[2..param.len() - 1];
+    match list.iter().position(|c| *c == b':') {
+        Some(pos) => {
+            let first = channel_number(&list[..pos])?;
+            let last = channel_number(&list[pos + 1..])?;
+            if first < last {
+                Ok(Channels::Range(first, last))
+            } else if first == last {
+                Ok(Channels::One(first))
+            } else {
+                Err(ScpiError::IllegalParameterValue)
+            }
+        }
+        None =>
+            channel_number(list).map(Channels::One),
+    }
+}
+
+/// Quoted string
+fn string(param: &[u8]) -> Result<&[u8], ScpiError> {
+    match (param.first(), param.last()) {
+        (Some(b'"'), Some(b'"')) | (Some(b'\''), Some(b'\'')) if param.len() >= 2 =>
+            Ok(&param[1..param.len() - 1]),
+        _ =>
+            Err(ScpiError::DataTypeError),
+    }
+}
+
+fn unit_parameter(param: &[u8]) -> Result<TemperatureUnit, ScpiError> {
+    if param.eq_ignore_ascii_case(b"CEL") || param.eq_ignore_ascii_case(b"C") {
+        Ok(TemperatureUnit::Celsius)
+    } else if param.eq_ignore_ascii_case(b"K") {
+        Ok(TemperatureUnit::Kelvin)
+    } else {
+        Err(ScpiError::IllegalParameterValue)
+    }
+}
+
+/// IEEE 488.2 common commands
+fn common(unit: &Unit) -> Result<Request, ScpiError> {
+    let name = &unit.header[1..];
+    let is = |mnemonic: &[u8]| name.eq_ignore_ascii_case(mnemonic);
+    if unit.query {
+        unit.no_params()?;
+        if is(b"IDN") {
+            Ok(Request::Identify)
+        } else if is(b"ESR") {
+            Ok(Request::EventStatusQuery)
+        } else if is(b"ESE") {
+            Ok(Request::EventStatusEnableQuery)
+        } else if is(b"STB") {
+            Ok(Request::StatusByteQuery)
+        } else if is(b"SRE") {
+            Ok(Request::ServiceRequestEnableQuery)
+        } else if is(b"OPC") {
+            Ok(Request::OperationCompleteQuery)
+        } else if is(b"TST") {
+            Ok(Request::SelfTestQuery)
+        } else {
+            Err(ScpiError::UndefinedHeader)
+        }
+    } else if is(b"ESE") {
+        mask(unit.param()?).map(Request::EventStatusEnable)
+    } else if is(b"SRE") {
+        mask(unit.param()?).map(Request::ServiceRequestEnable)
+    } else {
+        unit.no_params()?;
+        if is(b"RST") {
+            Ok(Request::Reset)
+        } else if is(b"CLS") {
+            Ok(Request::ClearStatus)
+        } else if is(b"OPC") {
+            Ok(Request::OperationComplete)
+        } else if is(b"WAI") {
+            Ok(Request::Wait)
+        } else {
+            Err(ScpiError::UndefinedHeader)
+        }
+    }
+}
+
+fn subsystem(unit: &Unit, temperature_unit: TemperatureUnit) -> Result<Request, ScpiError> {
+    let is = |pattern: &str| header_matches(pattern.as_bytes(), unit.header);
+    let pid = PID_HEADERS.iter()
+        .find(|(pattern, _)| is(pattern))
+        .map(|(_, parameter)| *parameter);
+
+    if is("SYSTem:ERRor[:NEXT]") && unit.query {
+        unit.no_params()?;
+        Ok(Request::ErrorQuery)
+    } else if is("SYSTem:VERSion") && unit.query {
+        unit.no_params()?;
+        Ok(Request::VersionQuery)
+    } else if is("SYSTem:PASSword[:CENable]") && !unit.query {
+        let password = string(unit.param()?)?;
+        if password.len() > PASSWORD_LEN {
+            return Err(ScpiError::TooMuchData);
+        }
+        Ok(Request::Command(Command::Auth(Password::new(password))))
+    } else if is("SYSTem:PASSword:CDISable") && !unit.query {
+        unit.no_params()?;
+        Ok(Request::PasswordDisable)
+    } else if is("SYSTem:PASSword:STATe") && unit.query {
+        unit.no_params()?;
+        Ok(Request::PasswordStateQuery)
+    } else if is("UNIT:TEMPerature") {
+        if unit.query {
+            unit.no_params()?;
+            Ok(Request::UnitQuery)
+        } else {
+            unit_parameter(unit.param()?).map(Request::Unit)
+        }
+    } else if is("MEASure[:SCALar]:TEMPerature") && unit.query {
+        unit.channels(0).map(Request::MeasureTemperature)
+    } else if is("MEASure[:SCALar]:RESistance") && unit.query {
+        unit.channels(0).map(Request::MeasureResistance)
+    } else if is("OUTPut[:STATe]") {
+        if unit.query {
+            unit.channels(0).map(Request::OutputQuery)
+        } else {
+            let channels = unit.channels(1)?;
+            Ok(Request::Output(channels, boolean(unit.params()[0])?))
+        }
+    } else if is("[SOURce]:TEMPerature") {
+        let parameter = Parameter::Pid(PidParameter::Target);
+        if unit.query {
+            Ok(Request::Query(unit.channels(0)?, parameter))
+        } else {
+            let channels = unit.channels(1)?;
+            let value = temperature_unit.to_kelvin(number(unit.params()[0])?);
+            Ok(Request::Command(Command::Pid { channels, parameter: PidParameter::Target, value }))
+        }
+    } else if let Some(parameter) = pid {
+        if unit.query {
+            Ok(Request::Query(unit.channels(0)?, Parameter::Pid(parameter)))
+        } else {
+            let channels = unit.channels(1)?;
+            let value = number(unit.params()[0])?;
+            Ok(Request::Command(Command::Pid { channels, parameter, value }))
+        }
+    } else if is("[SENSe]:FILTer:RATE") {
+        if unit.query {
+            Ok(Request::Query(unit.channels(0)?, Parameter::PostFilterRate))
+        } else {
+            let channels = unit.channels(1)?;
+            let rate = number(unit.params()[0])?;
+            Ok(Request::Command(Command::PostFilter { channels, rate }))
+        }
+    } else {
+        Err(ScpiError::UndefinedHeader)
+    }
+}
+
+/// Parse one command, temperatures are in `temperature_unit`
+pub fn parse(input: &[u8], temperature_unit: TemperatureUnit) -> Result<Request, ScpiError> {
+    let unit = Unit::split(input)?;
+    if unit.header[0] == b'*' {
+        common(&unit)
+    } else {
+        subsystem(&unit, temperature_unit)
+    }
+}
+
+/// Connection state: access, temperature unit, error queue and
+/// status registers
+pub struct ScpiSession {
+    reader: LineReader,
+    /// Read-write access, granted by `SYST:PASS:CEN`
+    privileged: bool,
+    unit: TemperatureUnit,
+    errors: RingBuffer<[Option<ScpiError>; ERROR_QUEUE_LEN]>,
+    /// Standard event status register
+    esr: u8,
+    ese: u8,
+    sre: u8,
+}
+
+impl ScpiSession {
+    pub fn new() -> Self {
+        ScpiSession {
+            reader: LineReader::new(),
+            privileged: false,
+            unit: TemperatureUnit::Celsius,
+            errors: RingBuffer::new(),
+            esr: 0,
+            ese: 0,
+            sre: 0,
+        }
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.reader.is_dirty() ||
+            self.privileged ||
+            self.unit != TemperatureUnit::Celsius ||
+            !self.errors.is_empty() ||
+            self.esr != 0 ||
+            self.ese != 0 ||
+            self.sre != 0
+    }
+
+    pub fn is_privileged(&self) -> bool {
+        self.privileged
+    }
+
+    pub fn set_privileged(&mut self, privileged: bool) {
+        self.privileged = privileged;
+    }
+
+    pub fn unit(&self) -> TemperatureUnit {
+        self.unit
+    }
+
+    pub fn set_unit(&mut self, unit: TemperatureUnit) {
+        self.unit = unit;
+    }
+
+    /// Returns the number of bytes taken and a complete line
+    pub fn feed(&mut self, buf: &[u8]) -> (usize, Option<LineResult>) {
+        for (i, b) in buf.iter().enumerate() {
+            match self.reader.feed(*b) {
+                Some(Ok(line)) =>
+                    return (i + 1, Some(line)),
+                Some(Err(LineTooLong)) =>
+                    self.push_error(ScpiError::InputBufferOverrun),
+                None => {}
+            }
+        }
+        (buf.len(), None)
+    }
+
+    /// Parse one command of a line, errors go to the error queue
+    pub fn parse(&mut self, input: &[u8]) -> Option<Request> {
+        match parse(input, self.unit) {
+            Ok(ref request) if !self.privileged && !request.is_read_only() => {
+                self.push_error(ScpiError::CommandProtected);
+                None
+            }
+            Ok(request) =>
+                Some(request),
+            Err(e) => {
+                self.push_error(e);
+                None
+            }
+        }
+    }
+
+    /// When the queue is full, the last entry is replaced by
+    /// `QueueOverflow`
+    pub fn push_error(&mut self, error: ScpiError) {
+        self.esr |= error.event_status();
+        if !self.errors.is_full() {
+            self.errors.push(error);
+        } else if let Some(last) = self.errors.last_mut() {
+            *last = ScpiError::QueueOverflow;
+        }
+    }
+
+    pub fn pop_error(&mut self) -> Option<ScpiError> {
+        self.errors.pop()
+    }
+
+    /// `*CLS`
+    pub fn clear_status(&mut self) {
+        self.errors.clear();
+        self.esr = 0;
+    }
+
+    pub fn operation_complete(&mut self) {
+        self.esr |= ESR_OPC;
+    }
+
+    /// `*ESR?`, clears the register
+    pub fn take_event_status(&mut self) -> u8 {
+        let esr = self.esr;
+        self.esr = 0;
+        esr
+    }
+
+    pub fn event_status_enable(&self) -> u8 {
+        self.ese
+    }
+
+    pub fn set_event_status_enable(&mut self, mask: u8) {
+        self.ese = mask;
+    }
+
+    pub fn service_request_enable(&self) -> u8 {
+        self.sre
+    }
+
+    /// Bit 6 can not be enabled
+    pub fn set_service_request_enable(&mut self, mask: u8) {
+        self.sre = mask & !STB_MSS;
+    }
+
+    pub fn status_byte(&self) -> u8 {
+        let mut stb = 0;
+        if !self.errors.is_empty() {
+            stb |= STB_EAV;
+        }
+        if self.esr & self.ese != 0 {
+            stb |= STB_ESB;
+        }
+        if stb & self.sre != 0 {
+            stb |= STB_MSS;
+        }
+        stb
+    }
+}
+
+/// Requests of a session applied to the channels of a `Device`,
+/// errors go to the session's error queue
+pub struct ScpiDevice<'a, D: Device> {
+    device: D,
+    credentials: Credentials,
+    /// Serial number in the `*IDN?` response
+    serial: &'a dyn fmt::Display,
+}
+
+impl<'a, D: Device> ScpiDevice<'a, D> {
+    pub fn new(device: D, credentials: Credentials, serial: &'a dyn fmt::Display) -> Self {
+        ScpiDevice { device, credentials, serial }
+    }
+
+    /// Execute the commands of a line, the responses of its queries
+    /// are sent as one line separated by `;`
+    pub fn execute_line<W: fmt::Write>(&mut self, out: &mut W, session: &mut ScpiSession, line: &[u8]) -> fmt::Result {
+        let mut responses = 0;
+        for unit in units(line) {
+            let request = match session.parse(unit) {
+                Some(request) => request,
+                None => continue,
+            };
+            if request.is_query() {
+                if responses > 0 {
+                    write!(out, ";")?;
+                }
+                responses += 1;
+            }
+            self.execute(out, session, request)?;
+        }
+        if responses > 0 {
+            writeln!(out, "")?;
+        }
+        Ok(())
+    }
+
+    fn execute<W: fmt::Write>(&mut self, out: &mut W, session: &mut ScpiSession, request: Request) -> fmt::Result {
+        match request {
+            Request::Identify =>
+                write!(out, "{},{},{}", IDN, self.serial, VERSION)?,
+            Request::Reset =>
+                self.reset(session),
+            Request::ClearStatus =>
+                session.clear_status(),
+            Request::EventStatusQuery =>
+                write!(out, "{}", session.take_event_status())?,
+            Request::EventStatusEnable(mask) =>
+                session.set_event_status_enable(mask),
+            Request::EventStatusEnableQuery =>
+                write!(out, "{}", session.event_status_enable())?,
+            Request::StatusByteQuery =>
+                write!(out, "{}", session.status_byte())?,
+            Request::ServiceRequestEnable(mask) =>
+                session.set_service_request_enable(mask),
+            Request::ServiceRequestEnableQuery =>
+                write!(out, "{}", session.service_request_enable())?,
+            Request::OperationComplete =>
+                session.operation_complete(),
+            Request::OperationCompleteQuery =>
+                write!(out, "1")?,
+            Request::Wait => {}
+            // No self-test, but no failure either
+            Request::SelfTestQuery =>
+                write!(out, "0")?,
+            Request::ErrorQuery => match session.pop_error() {
+                Some(e) => write!(out, "{}", e)?,
+                None => write!(out, "0,\"No error\"")?,
+            },
+            Request::VersionQuery =>
+                write!(out, "1999.0")?,
+            Request::PasswordDisable =>
+                session.set_privileged(false),
+            Request::PasswordStateQuery =>
+                write!(out, "{}", session.is_privileged() as u8)?,
+            Request::Unit(unit) =>
+                session.set_unit(unit),
+            Request::UnitQuery =>
+                write!(out, "{}", session.unit())?,
+            Request::MeasureTemperature(channels) => {
+                let unit = session.unit();
+                self.write_values(out, session, channels, false, |scpi, channel| {
+                    scpi.device.report(channel)
+                        .map(|sample| unit.from_kelvin(sample.temperature))
+                })?
+            }
+            Request::MeasureResistance(channels) =>
+                self.write_values(out, session, channels, false, |scpi, channel| {
+                    scpi.device.report(channel)
+                        .map(|sample| sample.resistance)
+                })?,
+            Request::Output(channels, on) =>
+                self.output(session, channels, on),
+            Request::OutputQuery(channels) =>
+                self.write_values(out, session, channels, true, |scpi, channel| {
+                    Some(scpi.device.pid_enabled(channel) as u8 as f32)
+                })?,
+            Request::Query(channels, parameter) => {
+                let unit = session.unit();
+                let tec_only = match parameter {
+                    Parameter::Pid(_) | Parameter::Pwm(_) => true,
+                    _ => false,
+                };
+                self.write_values(out, session, channels, tec_only, |scpi, channel| {
+                    match scpi.device.query(channel, parameter) {
+                        QueryValue::Float(value) if parameter == Parameter::Pid(PidParameter::Target) =>
+                            Some(unit.from_kelvin(value)),
+                        QueryValue::Float(value) =>
+                            Some(value),
+                        _ =>
+                            None,
+                    }
+                })?
+            }
+            Request::Command(Command::Auth(password)) => {
+                if self.credentials.permits(password.as_bytes()) {
+                    session.set_privileged(true);
+                } else {
+                    session.push_error(ScpiError::IllegalParameterValue);
+                }
+            }
+            Request::Command(command) =>
+                self.apply(session, command),
+        }
+        Ok(())
+    }
+
+    /// Values of the selected channels separated by `,`. With
+    /// `tec_only`, monitor-only channels are skipped for `all`.
+    fn write_values<W: fmt::Write, F: FnMut(&mut Self, usize) -> Option<f32>>(
+        &mut self, out: &mut W, session: &mut ScpiSession,
+        channels: Channels, tec_only: bool, mut value: F
+    ) -> fmt::Result {
+        let mut separator = "";
+        for channel in channels.iter() {
+            let value = if !tec_only || self.device.has_tec(channel) {
+                value(self, channel)
+            } else if channels == Channels::All {
+                continue;
+            } else {
+                session.push_error(ScpiError::HardwareMissing);
+                None
+            };
+            match value {
+                Some(value) if value.is_finite() =>
+                    write!(out, "{}{}", separator, value)?,
+                _ =>
+                    write!(out, "{}{}", separator, NOT_A_NUMBER)?,
+            }
+            separator = ",";
+        }
+        Ok(())
+    }
+
+    /// Monitor-only channels and locks, checked like for telnet
+    /// sessions
+    fn may_change(&self, session: &mut ScpiSession, channels: Channels, tec_only: bool) -> bool {
+        if tec_only && selects_monitor_only(channels, |channel| self.device.has_tec(channel)) {
+            session.push_error(ScpiError::HardwareMissing);
+            false
+        } else if channels.iter().any(|channel| !self.device.locks().may_write(channel, REMOTE_SESSION)) {
+            session.push_error(ScpiError::SettingsConflict);
+            false
+        } else {
+            true
+        }
+    }
+
+    fn apply(&mut self, session: &mut ScpiSession, command: Command) {
+        if let Some(channels) = command.channels() {
+            if !self.may_change(session, channels, command.needs_tec()) {
+                return;
+            }
+        }
+        match self.device.check(&command) {
+            Ok(()) =>
+                self.device.apply(command),
+            Err((_, e)) =>
+                session.push_error(ScpiError::Validation(e)),
+        }
+    }
+
+    /// `OUTP ON` hands the TEC to the PID controller, `OUTP OFF`
+    /// holds its last output
+    fn output(&mut self, session: &mut ScpiSession, channels: Channels, on: bool) {
+        if on {
+            let setup = PwmSetup::ISet(PwmMode::Pid);
+            self.apply(session, Command::Pwm { channels, setup });
+            return;
+        }
+        if !self.may_change(session, channels, true) {
+            return;
+        }
+        for channel in channels.iter() {
+            if self.device.has_tec(channel) {
+                let setup = self.device.output_setup(channel, false);
+                self.device.apply(Command::Pwm { channels: Channels::One(channel), setup });
+            }
+        }
+    }
+
+    /// `*RST`: power-on PID parameters and output, PID control off
+    fn reset(&mut self, session: &mut ScpiSession) {
+        session.set_unit(TemperatureUnit::Celsius);
+        if !self.may_change(session, Channels::All, true) {
+            return;
+        }
+        let (assignments, output) = self.device.power_on();
+        let commands = [
+            Command::Pwm { channels: Channels::All, setup: PwmSetup::ISet(PwmMode::Manual(output)) },
+            Command::PidAssign { channels: Channels::All, assignments },
+        ];
+        for command in commands.iter() {
+            self.device.apply(command.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::command_parser::PwmConfig;
+    use crate::device::test::TestDevice;
+
+    #[test]
+    fn parse_measure_temperature() {
+        let request = parse(b"MEAS:TEMP? (@1)", TemperatureUnit::Celsius);
+        assert_eq!(request, Ok(Request::MeasureTemperature(Channels::One(1))));
+        let request = parse(b"measure:scalar:temperature?", TemperatureUnit::Celsius);
+        assert_eq!(request, Ok(Request::MeasureTemperature(Channels::All)));
+        let request = parse(b"MEAS:TEMP? (@3)", TemperatureUnit::Celsius);
+        assert_eq!(request, Err(ScpiError::DataOutOfRange));
+    }
+
+    #[test]
+    fn parse_source_temperature() {
+        let request = parse(b"SOUR:TEMP 25", TemperatureUnit::Celsius);
+        assert_eq!(request, Ok(Request::Command(Command::Pid {
+            channels: Channels::All,
+            parameter: PidParameter::Target,
+            value: 25.0 + ZERO_CELSIUS,
+        })));
+        let request = parse(b"TEMP 300, (@0:1)", TemperatureUnit::Kelvin);
+        assert_eq!(request, Ok(Request::Command(Command::Pid {
+            channels: Channels::Range(0, 1),
+            parameter: PidParameter::Target,
+            value: 300.0,
+        })));
+    }
+
+    #[test]
+    fn parse_common() {
+        assert_eq!(parse(b"*IDN?", TemperatureUnit::Celsius), Ok(Request::Identify));
+        assert_eq!(parse(b"*ese 36", TemperatureUnit::Celsius), Ok(Request::EventStatusEnable(36)));
+        assert_eq!(parse(b"*ESE", TemperatureUnit::Celsius), Err(ScpiError::MissingParameter));
+        assert_eq!(parse(b"*FOO", TemperatureUnit::Celsius), Err(ScpiError::UndefinedHeader));
+    }
+
+    #[test]
+    fn split_units() {
+        let mut units = units(b"SYST:PASS \"a;b\"; *OPC?;");
+        assert_eq!(units.next(), Some(&b"SYST:PASS \"a;b\""[..]));
+        assert_eq!(units.next(), Some(&b"*OPC?"[..]));
+        assert_eq!(units.next(), None);
+    }
+
+    #[test]
+    fn write_protected() {
+        let mut session = ScpiSession::new();
+        assert_eq!(session.parse(b"OUTP ON"), None);
+        assert_eq!(session.pop_error(), Some(ScpiError::CommandProtected));
+        assert_eq!(session.take_event_status(), ESR_EXE);
+    }
+
+    #[test]
+    fn error_queue_overflow() {
+        let mut session = ScpiSession::new();
+        session.set_event_status_enable(ESR_CME);
+        session.set_service_request_enable(STB_ESB);
+        for _ in 0..ERROR_QUEUE_LEN + 1 {
+            session.push_error(ScpiError::UndefinedHeader);
+        }
+        assert_eq!(session.status_byte(), STB_EAV | STB_ESB | STB_MSS);
+        for _ in 0..ERROR_QUEUE_LEN - 1 {
+            assert_eq!(session.pop_error(), Some(ScpiError::UndefinedHeader));
+        }
+        let error = session.pop_error().unwrap();
+        assert_eq!(format!("{}", error), "-350,\"Queue overflow\"");
+        assert_eq!(session.pop_error(), None);
+    }
+
+    fn execute(device: &mut ScpiDevice<TestDevice>, session: &mut ScpiSession, line: &[u8]) -> String {
+        let mut out = String::new();
+        device.execute_line(&mut out, session, line).unwrap();
+        out
+    }
+
+    #[test]
+    fn monitor_only() {
+        let mut device = ScpiDevice::new(TestDevice::new(), Credentials::Unset, &"0");
+        let mut session = ScpiSession::new();
+        session.set_privileged(true);
+
+        assert_eq!(execute(&mut device, &mut session, b"OUTP OFF,(@1)"), "");
+        assert_eq!(session.pop_error(), Some(ScpiError::HardwareMissing));
+        execute(&mut device, &mut session, b"SOUR:TEMP 30,(@0:1)");
+        assert_eq!(session.pop_error(), Some(ScpiError::HardwareMissing));
+        assert_eq!(device.device.applied, None);
+
+        // `all` skips the monitor-only channel
+        assert_eq!(execute(&mut device, &mut session, b"OUTP?"), "1\n");
+        assert_eq!(execute(&mut device, &mut session, b"OUTP? (@1)"), format!("{}\n", NOT_A_NUMBER));
+        assert_eq!(session.pop_error(), Some(ScpiError::HardwareMissing));
+        execute(&mut device, &mut session, b"OUTP OFF");
+        assert_eq!(session.pop_error(), None);
+        let setup = PwmSetup::ISet(PwmMode::Manual(PwmConfig { width: 100, total: 200 }));
+        assert_eq!(device.device.applied, Some(Command::Pwm { channels: Channels::One(0), setup }));
+    }
+}
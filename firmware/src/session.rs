@@ -1,12 +1,147 @@
+use core::fmt;
 use core::ops::Deref;
-use super::command_parser::{Command, Error as ParserError};
+use super::command_parser::{
+    self, Command, Error as ParserError, OutputFormat, HelpStream,
+    PidParameter, ShParameter, PwmSetup, PwmMode, PwmConfig,
+};
+use super::housekeeping::RefStatus;
+use super::report::{Sample, ReportConfig, Decimator};
+use super::history::HistoryStream;
+use super::ring_buffer::RingBuffer;
 use super::CHANNELS;
 
-const MAX_LINE_LEN: usize = 64;
+/// Fits the one-line `pid <chs> k=v ...` form
+pub const MAX_LINE_LEN: usize = 128;
+/// Keeps the line history of each of the telnet sessions at 512 bytes
+const HISTORY_LEN: usize = 4;
+const OUTPUT_LEN: usize = 256;
+const EVENT_QUEUE_LEN: usize = 16;
 
-struct LineReader {
+// Telnet, RFC 854
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const OPT_ECHO: u8 = 1;
+const OPT_SGA: u8 = 3;
+
+const BEL: u8 = 7;
+const BS: u8 = 8;
+const TAB: u8 = 9;
+const LF: u8 = 10;
+const CR: u8 = 13;
+const ESC: u8 = 27;
+const DEL: u8 = 127;
+
+#[derive(Clone, Copy, PartialEq)]
+enum InputState {
+    Data,
+    /// After CR, a following LF or NUL belongs to the same Enter
+    Cr,
+    Iac,
+    /// Option negotiation `IAC <command>`, waiting for the option
+    Negotiation(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+    Escape,
+    /// `ESC [` or `ESC O`, waiting for the final byte
+    Csi,
+}
+
+/// Bytes to send back to the client: echo and telnet replies
+struct Output {
+    buf: [u8; OUTPUT_LEN],
+    len: usize,
+}
+
+impl Output {
+    fn new() -> Self {
+        Output {
+            buf: [0; OUTPUT_LEN],
+            len: 0,
+        }
+    }
+
+    /// Excess output is dropped
+    fn push(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(OUTPUT_LEN - self.len);
+        self.buf[self.len..(self.len + len)].copy_from_slice(&bytes[..len]);
+        self.len += len;
+    }
+
+    fn push_repeat(&mut self, byte: u8, count: usize) {
+        for _ in 0..count {
+            self.push(&[byte]);
+        }
+    }
+}
+
+/// Ring of previously entered lines
+struct History {
+    lines: [[u8; MAX_LINE_LEN]; HISTORY_LEN],
+    lens: [usize; HISTORY_LEN],
+    /// Index of the next line to write
+    next: usize,
+    count: usize,
+}
+
+impl History {
+    fn new() -> Self {
+        History {
+            lines: [[0; MAX_LINE_LEN]; HISTORY_LEN],
+            lens: [0; HISTORY_LEN],
+            next: 0,
+            count: 0,
+        }
+    }
+
+    /// `age` 1 is the most recent line
+    fn get(&self, age: usize) -> Option<&[u8]> {
+        if age < 1 || age > self.count {
+            return None;
+        }
+        let index = (self.next + HISTORY_LEN - age) % HISTORY_LEN;
+        Some(&self.lines[index][..self.lens[index]])
+    }
+
+    fn push(&mut self, line: &[u8]) {
+        if self.get(1) == Some(line) {
+            return;
+        }
+        self.lines[self.next][..line.len()].copy_from_slice(line);
+        self.lens[self.next] = line.len();
+        self.next = (self.next + 1) % HISTORY_LEN;
+        self.count = (self.count + 1).min(HISTORY_LEN);
+    }
+}
+
+/// Input line exceeded `MAX_LINE_LEN` and was discarded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LineTooLong;
+
+/// Line editor for telnet clients
+///
+/// Raw TCP clients send complete lines and do their own echo. Once a
+/// client negotiates telnet options, the server offers to echo and
+/// edits lines in character mode.
+pub struct LineReader {
     buf: [u8; MAX_LINE_LEN],
     pos: usize,
+    cursor: usize,
+    /// Characters were dropped from the current line
+    overflow: bool,
+    state: InputState,
+    /// `WILL ECHO` and `WILL SGA` have been sent
+    offered: bool,
+    /// Client accepted server-side echo
+    echo: bool,
+    history: History,
+    /// Browsing history, 0 is the line being edited
+    history_age: usize,
+    output: Output,
 }
 
 impl LineReader {
@@ -14,32 +149,317 @@ impl LineReader {
         LineReader {
             buf: [0; MAX_LINE_LEN],
             pos: 0,
+            cursor: 0,
+            overflow: false,
+            state: InputState::Data,
+            offered: false,
+            echo: false,
+            history: History::new(),
+            history_age: 0,
+            output: Output::new(),
         }
     }
 
-    pub fn feed(&mut self, c: u8) -> Option<LineResult> {
-        if c == 13 || c == 10 {
-            // Enter
-            if self.pos > 0 {
-                let len = self.pos;
-                self.pos = 0;
-                Some(LineResult {
-                    buf: self.buf.clone(),
-                    len,
-                })
-            } else {
+    pub fn is_dirty(&self) -> bool {
+        self.pos > 0 ||
+            self.offered ||
+            self.history.count > 0
+    }
+
+    pub fn feed(&mut self, c: u8) -> Option<Result<LineResult, LineTooLong>> {
+        match self.state {
+            InputState::Cr if c == LF || c == 0 => {
+                self.state = InputState::Data;
                 None
             }
-        } else if self.pos < self.buf.len() {
-            // Add input
-            self.buf[self.pos] = c;
-            self.pos += 1;
-            None
+            InputState::Data | InputState::Cr => {
+                self.state = InputState::Data;
+                self.feed_data(c)
+            }
+            InputState::Iac => {
+                self.state = match c {
+                    WILL | WONT | DO | DONT => InputState::Negotiation(c),
+                    SB => InputState::Subnegotiation,
+                    // Other commands and escaped 255 are ignored
+                    _ => InputState::Data,
+                };
+                None
+            }
+            InputState::Negotiation(command) => {
+                self.state = InputState::Data;
+                self.negotiate(command, c);
+                None
+            }
+            InputState::Subnegotiation => {
+                if c == IAC {
+                    self.state = InputState::SubnegotiationIac;
+                }
+                None
+            }
+            InputState::SubnegotiationIac => {
+                self.state = if c == SE {
+                    InputState::Data
+                } else {
+                    InputState::Subnegotiation
+                };
+                None
+            }
+            InputState::Escape => {
+                self.state = match c {
+                    b'[' | b'O' => InputState::Csi,
+                    _ => InputState::Data,
+                };
+                None
+            }
+            InputState::Csi => {
+                match c {
+                    b'A' => self.history_up(),
+                    b'B' => self.history_down(),
+                    b'C' => self.cursor_right(),
+                    b'D' => self.cursor_left(),
+                    _ => {}
+                }
+                // Parameter bytes are skipped until the final byte
+                if c >= 0x40 {
+                    self.state = InputState::Data;
+                }
+                None
+            }
+        }
+    }
+
+    fn feed_data(&mut self, c: u8) -> Option<Result<LineResult, LineTooLong>> {
+        match c {
+            CR => {
+                self.state = InputState::Cr;
+                return self.enter();
+            }
+            LF =>
+                return self.enter(),
+            IAC =>
+                self.state = InputState::Iac,
+            ESC =>
+                self.state = InputState::Escape,
+            BS | DEL =>
+                self.backspace(),
+            TAB if self.echo =>
+                self.complete(),
+            b' '..=b'~' =>
+                self.insert(c),
+            _ => {}
+        }
+        None
+    }
+
+    /// Bytes to send to the client
+    pub fn output(&self) -> &[u8] {
+        &self.output.buf[..self.output.len]
+    }
+
+    pub fn clear_output(&mut self) {
+        self.output.len = 0;
+    }
+
+    /// Ask for character mode with server-side echo, once the client
+    /// has shown that it speaks telnet
+    fn offer(&mut self) {
+        if !self.offered {
+            self.offered = true;
+            self.output.push(&[IAC, WILL, OPT_ECHO, IAC, WILL, OPT_SGA]);
+        }
+    }
+
+    fn negotiate(&mut self, command: u8, option: u8) {
+        self.offer();
+        match (command, option) {
+            (DO, OPT_ECHO) =>
+                self.echo = true,
+            (DONT, OPT_ECHO) =>
+                self.echo = false,
+            (DO, OPT_SGA) | (DONT, OPT_SGA) | (WONT, _) | (DONT, _) => {}
+            (WILL, OPT_SGA) =>
+                self.output.push(&[IAC, DO, OPT_SGA]),
+            // Refuse everything else
+            (DO, _) =>
+                self.output.push(&[IAC, WONT, option]),
+            (WILL, _) =>
+                self.output.push(&[IAC, DONT, option]),
+            _ => {}
+        }
+    }
+
+    fn enter(&mut self) -> Option<Result<LineResult, LineTooLong>> {
+        if self.echo {
+            self.output.push(b"\r\n");
+        }
+        let len = self.pos;
+        let overflow = self.overflow;
+        self.pos = 0;
+        self.cursor = 0;
+        self.overflow = false;
+        self.history_age = 0;
+
+        if overflow {
+            Some(Err(LineTooLong))
+        } else if len > 0 {
+            let line = &self.buf[..len];
+            // Keep passwords out of the history
+            if !line.starts_with(b"auth ") && !line.starts_with(b"passwd ") {
+                self.history.push(line);
+            }
+            Some(Ok(LineResult {
+                buf: self.buf.clone(),
+                len,
+            }))
         } else {
-            // Buffer is full, ignore
             None
         }
     }
+
+    fn insert(&mut self, c: u8) {
+        if self.pos == MAX_LINE_LEN {
+            // Buffer is full, line will be rejected
+            self.overflow = true;
+            if self.echo {
+                self.output.push(&[BEL]);
+            }
+            return;
+        }
+        self.buf.copy_within(self.cursor..self.pos, self.cursor + 1);
+        self.buf[self.cursor] = c;
+        self.pos += 1;
+        self.cursor += 1;
+        if self.echo {
+            self.output.push(&self.buf[(self.cursor - 1)..self.pos]);
+            self.output.push_repeat(BS, self.pos - self.cursor);
+        }
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor == 0 {
+            return;
+        }
+        self.buf.copy_within(self.cursor..self.pos, self.cursor - 1);
+        self.pos -= 1;
+        self.cursor -= 1;
+        if self.echo {
+            self.output.push(&[BS]);
+            self.output.push(&self.buf[self.cursor..self.pos]);
+            self.output.push(b" ");
+            self.output.push_repeat(BS, self.pos - self.cursor + 1);
+        }
+    }
+
+    fn cursor_left(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            if self.echo {
+                self.output.push(&[BS]);
+            }
+        }
+    }
+
+    fn cursor_right(&mut self) {
+        if self.cursor < self.pos {
+            if self.echo {
+                self.output.push(&self.buf[self.cursor..(self.cursor + 1)]);
+            }
+            self.cursor += 1;
+        }
+    }
+
+    fn history_up(&mut self) {
+        if self.history_age < self.history.count {
+            self.history_age += 1;
+            self.recall();
+        }
+    }
+
+    fn history_down(&mut self) {
+        if self.history_age > 0 {
+            self.history_age -= 1;
+            self.recall();
+        }
+    }
+
+    /// Replace the line with the history entry at `history_age`
+    fn recall(&mut self) {
+        let old_len = self.pos;
+        if self.echo {
+            self.output.push_repeat(BS, self.cursor);
+        }
+        let len = match self.history.get(self.history_age) {
+            Some(line) => {
+                self.buf[..line.len()].copy_from_slice(line);
+                line.len()
+            }
+            None => 0,
+        };
+        self.pos = len;
+        self.cursor = len;
+        self.overflow = false;
+        if self.echo {
+            self.output.push(&self.buf[..len]);
+            if old_len > len {
+                self.output.push_repeat(b' ', old_len - len);
+                self.output.push_repeat(BS, old_len - len);
+            }
+        }
+    }
+
+    /// Complete the word before the cursor from the command grammar
+    fn complete(&mut self) {
+        let mut candidates = [""; 16];
+        let mut count = 0;
+        command_parser::completions(&self.buf[..self.cursor], |keyword| {
+            if count < candidates.len() && !candidates[..count].contains(&keyword) {
+                candidates[count] = keyword;
+                count += 1;
+            }
+        });
+        let candidates = &candidates[..count];
+
+        let partial_len = self.buf[..self.cursor].iter()
+            .rev()
+            .take_while(|c| **c != b' ')
+            .count();
+        // Longest common prefix of all candidates
+        let common_len = candidates.iter()
+            .skip(1)
+            .fold(candidates.first().map(|c| c.len()).unwrap_or(0), |len, candidate| {
+                candidates[0].bytes()
+                    .zip(candidate.bytes())
+                    .take(len)
+                    .take_while(|(a, b)| a == b)
+                    .count()
+            });
+
+        match candidates {
+            [] =>
+                self.output.push(&[BEL]),
+            [keyword] => {
+                for c in keyword[partial_len..].bytes().chain(b" ".iter().cloned()) {
+                    self.insert(c);
+                }
+            }
+            _ if common_len > partial_len => {
+                for c in candidates[0][partial_len..common_len].bytes() {
+                    self.insert(c);
+                }
+            }
+            _ => {
+                // Ambiguous: list candidates, then redraw the line
+                self.output.push(b"\r\n");
+                for keyword in candidates {
+                    self.output.push(keyword.as_bytes());
+                    self.output.push(b" ");
+                }
+                self.output.push(b"\r\n");
+                self.output.push(&self.buf[..self.pos]);
+                self.output.push_repeat(BS, self.pos - self.cursor);
+            }
+        }
+    }
 }
 
 pub struct LineResult {
@@ -58,6 +478,9 @@ pub enum SessionOutput {
     Nothing,
     Command(Command),
     Error(ParserError),
+    /// Command requires a read-write session
+    PermissionDenied,
+    LineTooLong,
 }
 
 impl From<Result<Command, ParserError>> for SessionOutput {
@@ -67,36 +490,228 @@ impl From<Result<Command, ParserError>> for SessionOutput {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LockError {
+    NotLocked,
+    /// Locked by another session
+    LockedBy(usize),
+}
+
+/// Exclusive write access to channels, by session index
+pub struct ChannelLocks {
+    owners: [Option<usize>; CHANNELS],
+}
+
+impl ChannelLocks {
+    pub fn new() -> Self {
+        ChannelLocks {
+            owners: [None; CHANNELS],
+        }
+    }
+
+    pub fn owner(&self, channel: usize) -> Option<usize> {
+        self.owners[channel]
+    }
+
+    /// Unlocked channels may be written by any session
+    pub fn may_write(&self, channel: usize, session: usize) -> bool {
+        self.owners[channel]
+            .map(|owner| owner == session)
+            .unwrap_or(true)
+    }
+
+    pub fn lock(&mut self, channel: usize, session: usize) -> Result<(), LockError> {
+        match self.owners[channel] {
+            Some(owner) if owner != session =>
+                Err(LockError::LockedBy(owner)),
+            _ => {
+                self.owners[channel] = Some(session);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn unlock(&mut self, channel: usize, session: usize) -> Result<(), LockError> {
+        match self.owners[channel] {
+            None =>
+                Err(LockError::NotLocked),
+            Some(owner) if owner != session =>
+                Err(LockError::LockedBy(owner)),
+            Some(_) => {
+                self.owners[channel] = None;
+                Ok(())
+            }
+        }
+    }
+
+    /// Release all locks of a closed session
+    pub fn release_all(&mut self, session: usize) {
+        for owner in self.owners.iter_mut() {
+            if *owner == Some(session) {
+                *owner = None;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    /// Communication with the ADC failed
+    Adc,
+    /// Samples dropped because the main loop did not keep up
+    Overrun(u32),
+    /// ADC error flags on a channel's conversions, set or cleared
+    Conversion { channel: usize, active: bool },
+    /// Housekeeping reference status changed
+    Reference(RefStatus),
+}
+
+/// State change that is pushed to subscribed sessions
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    Pwm { channel: usize, setup: PwmSetup },
+    Pid { channel: usize, parameter: PidParameter, value: f32 },
+    SteinhartHart { channel: usize, parameter: ShParameter, value: f32 },
+    /// Output data rate actually selected
+    PostFilter { channel: usize, rate: f32 },
+    Fault(Fault),
+}
+
+impl Event {
+    /// Channel of a settings change
+    pub fn channel(&self) -> Option<usize> {
+        match *self {
+            Event::Pwm { channel, .. } |
+            Event::Pid { channel, .. } |
+            Event::SteinhartHart { channel, .. } |
+            Event::PostFilter { channel, .. } =>
+                Some(channel),
+            Event::Fault(_) =>
+                None,
+        }
+    }
+}
+
+/// Displays as the command that would apply the change
+impl fmt::Display for Event {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Event::Pwm { channel, setup } => {
+                let (name, config) = match setup {
+                    PwmSetup::ISet(PwmMode::Pid) =>
+                        return write!(fmt, "pwm {} pid", channel),
+                    PwmSetup::ISet(PwmMode::Manual(config)) =>
+                        return write!(fmt, "pwm {} {} {}", channel, config.width, config.total),
+                    PwmSetup::MaxIPos(config) => ("max_i_pos", config),
+                    PwmSetup::MaxINeg(config) => ("max_i_neg", config),
+                    PwmSetup::MaxV(config) => ("max_v", config),
+                };
+                let PwmConfig { width, total } = config;
+                write!(fmt, "pwm {} {} {} {}", channel, name, width, total)
+            }
+            Event::Pid { channel, parameter, value } =>
+                write!(fmt, "pid {} {} {}", channel, parameter, value),
+            Event::SteinhartHart { channel, parameter, value } =>
+                write!(fmt, "s-h {} {} {}", channel, parameter, value),
+            Event::PostFilter { channel, rate } =>
+                write!(fmt, "postfilter {} rate {:.2}", channel, rate),
+            Event::Fault(Fault::Adc) =>
+                write!(fmt, "fault adc"),
+            Event::Fault(Fault::Overrun(count)) =>
+                write!(fmt, "fault overrun {}", count),
+            Event::Fault(Fault::Conversion { channel, active }) =>
+                write!(fmt, "fault channel {} {}", channel, if *active { "set" } else { "cleared" }),
+            Event::Fault(Fault::Reference(status)) =>
+                write!(fmt, "fault reference {}", status),
+        }
+    }
+}
+
+/// Events waiting to be sent, the oldest are dropped on overflow
+pub type EventQueue = RingBuffer<[Option<Event>; EVENT_QUEUE_LEN]>;
+
 pub struct Session {
     reader: LineReader,
+    /// Read-write access, granted by `auth`
+    privileged: bool,
     reporting: bool,
-    report_pending: [bool; CHANNELS],
+    format: OutputFormat,
+    report_config: ReportConfig,
+    decimators: [Decimator; CHANNELS],
+    report_pending: [Option<Sample>; CHANNELS],
+    /// Changes by the owner of a locked channel to notify about
+    changes: EventQueue,
+    /// Set by `subscribe events on`
+    events: Option<EventQueue>,
+    /// Events dropped before being queued
+    lost_events: u32,
+    /// `help` listing in progress
+    help: Option<HelpStream>,
+    /// `history` download in progress
+    history: Option<HistoryStream>,
 }
 
 impl Session {
     pub fn new() -> Self {
         Session {
             reader: LineReader::new(),
+            privileged: false,
             reporting: false,
-            report_pending: [false; CHANNELS],
+            format: OutputFormat::Text,
+            report_config: ReportConfig::new(),
+            decimators: [Decimator::new(); CHANNELS],
+            report_pending: [None; CHANNELS],
+            changes: EventQueue::new(),
+            events: None,
+            lost_events: 0,
+            help: None,
+            history: None,
         }
     }
 
     pub fn is_dirty(&self) -> bool {
-        self.reader.pos > 0
+        self.reader.is_dirty() ||
+            self.privileged ||
+            self.reporting ||
+            self.format != OutputFormat::Text ||
+            self.report_config != ReportConfig::new() ||
+            !self.changes.is_empty() ||
+            self.events.is_some() ||
+            self.help.is_some() ||
+            self.history.is_some()
+    }
+
+    pub fn is_privileged(&self) -> bool {
+        self.privileged
+    }
+
+    pub fn set_privileged(&mut self, privileged: bool) {
+        self.privileged = privileged;
     }
 
     pub fn reporting(&self) -> bool {
         self.reporting
     }
 
-    pub fn set_report_pending(&mut self, channel: usize) {
-        if self.reporting {
-            self.report_pending[channel] = true;
+    pub fn format(&self) -> OutputFormat {
+        self.format
+    }
+
+    pub fn report_config(&self) -> &ReportConfig {
+        &self.report_config
+    }
+
+    /// Pass a new sample through the decimation of this session
+    pub fn feed_report(&mut self, channel: usize, sample: &Sample) {
+        if self.reporting && self.report_config.channels[channel] {
+            let report = self.decimators[channel].feed(&self.report_config, sample);
+            if report.is_some() {
+                self.report_pending[channel] = report;
+            }
         }
     }
 
-    pub fn is_report_pending(&self) -> Option<usize> {
+    pub fn is_report_pending(&self) -> Option<(usize, Sample)> {
         if ! self.reporting {
             None
         } else {
@@ -104,14 +719,89 @@ impl Session {
                 .enumerate()
                 .fold(None, |result, (channel, report_pending)| {
                     result.or_else(|| {
-                        if *report_pending { Some(channel) } else { None }
+                        report_pending.map(|sample| (channel, sample))
                     })
                 })
         }
     }
 
     pub fn mark_report_sent(&mut self, channel: usize) {
-        self.report_pending[channel] = false;
+        self.report_pending[channel] = None;
+    }
+
+    /// Restart decimation, e.g. after a change of settings
+    fn reset_reports(&mut self) {
+        self.decimators = [Decimator::new(); CHANNELS];
+        self.report_pending = [None; CHANNELS];
+    }
+
+    /// Queue a change of a channel locked by another session
+    pub fn push_change(&mut self, change: Event) {
+        self.changes.push(change);
+    }
+
+    pub fn pop_change(&mut self) -> Option<Event> {
+        self.changes.pop()
+    }
+
+    /// Number of changes dropped since the previous call
+    pub fn take_lost_changes(&mut self) -> u32 {
+        self.changes.take_lost()
+    }
+
+    pub fn subscribed(&self) -> bool {
+        self.events.is_some()
+    }
+
+    /// Queue an event if subscribed
+    pub fn push_event(&mut self, event: Event) {
+        if let Some(events) = self.events.as_mut() {
+            events.push(event);
+        }
+    }
+
+    /// Count events that were dropped before reaching the session
+    pub fn add_lost_events(&mut self, count: u32) {
+        if self.events.is_some() {
+            self.lost_events += count;
+        }
+    }
+
+    pub fn pop_event(&mut self) -> Option<Event> {
+        self.events.as_mut().and_then(|events| events.pop())
+    }
+
+    /// Number of events dropped since the previous call
+    pub fn take_lost_events(&mut self) -> u32 {
+        let lost = self.events.as_mut()
+            .map(|events| events.take_lost())
+            .unwrap_or(0);
+        lost + core::mem::replace(&mut self.lost_events, 0)
+    }
+
+    pub fn help_stream(&mut self) -> Option<&mut HelpStream> {
+        self.help.as_mut()
+    }
+
+    pub fn set_help_stream(&mut self, stream: Option<HelpStream>) {
+        self.help = stream;
+    }
+
+    pub fn history_stream(&mut self) -> Option<&mut HistoryStream> {
+        self.history.as_mut()
+    }
+
+    pub fn set_history_stream(&mut self, stream: Option<HistoryStream>) {
+        self.history = stream;
+    }
+
+    /// Echo and telnet negotiation to send to the client
+    pub fn output(&self) -> &[u8] {
+        self.reader.output()
+    }
+
+    pub fn clear_output(&mut self) {
+        self.reader.clear_output();
     }
 
     pub fn feed(&mut self, buf: &[u8]) -> (usize, SessionOutput) {
@@ -120,11 +810,34 @@ impl Session {
             buf_bytes = i + 1;
             let line = self.reader.feed(*b);
             match line {
-                Some(line) => {
+                Some(Err(LineTooLong)) => {
+                    return (buf_bytes, SessionOutput::LineTooLong);
+                }
+                Some(Ok(line)) => {
                     let command = Command::parse(&line);
                     match command {
+                        Ok(ref command) if !self.privileged && !command.is_read_only() => {
+                            return (buf_bytes, SessionOutput::PermissionDenied);
+                        }
                         Ok(Command::Reporting(reporting)) => {
                             self.reporting = reporting;
+                            self.reset_reports();
+                        }
+                        Ok(Command::Report(setting)) => {
+                            self.report_config.apply(setting);
+                            self.reset_reports();
+                        }
+                        Ok(Command::Format(format)) => {
+                            self.format = format;
+                        }
+                        Ok(Command::SubscribeEvents(true)) => {
+                            if self.events.is_none() {
+                                self.events = Some(EventQueue::new());
+                            }
+                        }
+                        Ok(Command::SubscribeEvents(false)) => {
+                            self.events = None;
+                            self.lost_events = 0;
                         }
                         _ => {}
                     }
@@ -136,3 +849,149 @@ impl Session {
         (buf_bytes, SessionOutput::Nothing)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Feed `input`, return the last completed line
+    fn feed_all(reader: &mut LineReader, input: &[u8]) -> Option<Result<LineResult, LineTooLong>> {
+        input.iter().fold(None, |result, c| reader.feed(*c).or(result))
+    }
+
+    /// Reader in character mode with echo
+    fn telnet_reader() -> LineReader {
+        let mut reader = LineReader::new();
+        assert!(feed_all(&mut reader, &[IAC, DO, OPT_ECHO]).is_none());
+        assert_eq!(reader.output(), &[IAC, WILL, OPT_ECHO, IAC, WILL, OPT_SGA]);
+        reader.clear_output();
+        reader
+    }
+
+    #[test]
+    fn line_raw() {
+        let mut reader = LineReader::new();
+        let line = feed_all(&mut reader, b"pid\r\n").unwrap().unwrap();
+        assert_eq!(&*line, b"pid");
+        assert_eq!(reader.output(), b"");
+    }
+
+    #[test]
+    fn line_refuse_option() {
+        let mut reader = telnet_reader();
+        feed_all(&mut reader, &[IAC, WILL, 31]);
+        assert_eq!(reader.output(), &[IAC, DONT, 31]);
+    }
+
+    #[test]
+    fn line_edit() {
+        let mut reader = telnet_reader();
+        // Cursor left, delete the `x`
+        let line = feed_all(&mut reader, b"pixd\x1b[D\x7f\r\0").unwrap().unwrap();
+        assert_eq!(&*line, b"pid");
+        assert_eq!(&reader.output()[..4], b"pixd");
+    }
+
+    #[test]
+    fn line_too_long() {
+        let mut reader = LineReader::new();
+        for _ in 0..MAX_LINE_LEN {
+            assert!(reader.feed(b'x').is_none());
+        }
+        assert!(reader.feed(b'x').is_none());
+        assert_eq!(reader.feed(b'\n').map(|line| line.err()), Some(Some(LineTooLong)));
+        assert_eq!(&*feed_all(&mut reader, b"pid\n").unwrap().unwrap(), b"pid");
+    }
+
+    #[test]
+    fn line_history() {
+        let mut reader = telnet_reader();
+        feed_all(&mut reader, b"pid\r\n");
+        feed_all(&mut reader, b"pwm\r\n");
+        let line = feed_all(&mut reader, b"\x1b[A\x1b[A\x1b[A\x1b[B\r\n").unwrap().unwrap();
+        assert_eq!(&*line, b"pwm");
+    }
+
+    #[test]
+    fn line_complete() {
+        let mut reader = telnet_reader();
+        let line = feed_all(&mut reader, b"su\tev\ton\r\n").unwrap().unwrap();
+        assert_eq!(&*line, b"subscribe events on");
+    }
+
+    #[test]
+    fn lock_exclusive() {
+        let mut locks = ChannelLocks::new();
+        assert_eq!(locks.lock(0, 3), Ok(()));
+        assert!(locks.may_write(0, 3));
+        assert!(!locks.may_write(0, 4));
+        assert!(locks.may_write(1, 4));
+        assert_eq!(locks.lock(0, 4), Err(LockError::LockedBy(3)));
+        assert_eq!(locks.unlock(0, 4), Err(LockError::LockedBy(3)));
+        assert_eq!(locks.unlock(0, 3), Ok(()));
+        assert_eq!(locks.unlock(0, 3), Err(LockError::NotLocked));
+    }
+
+    #[test]
+    fn lock_release_all() {
+        let mut locks = ChannelLocks::new();
+        locks.lock(0, 2).unwrap();
+        locks.lock(1, 2).unwrap();
+        locks.release_all(2);
+        assert_eq!(locks.owner(0), None);
+        assert_eq!(locks.owner(1), None);
+    }
+
+    #[test]
+    fn event_queue_overflow() {
+        let mut events = EventQueue::new();
+        for count in 0..(EVENT_QUEUE_LEN as u32 + 2) {
+            events.push(Event::Fault(Fault::Overrun(count)));
+        }
+        assert_eq!(events.take_lost(), 2);
+        assert_eq!(events.take_lost(), 0);
+        assert_eq!(events.pop(), Some(Event::Fault(Fault::Overrun(2))));
+    }
+
+    #[test]
+    fn subscribe_events() {
+        let mut session = Session::new();
+        session.push_event(Event::Fault(Fault::Adc));
+        assert_eq!(session.pop_event(), None);
+        session.feed(b"subscribe events on\n");
+        session.push_event(Event::Fault(Fault::Adc));
+        assert_eq!(session.pop_event(), Some(Event::Fault(Fault::Adc)));
+    }
+
+    #[test]
+    fn lock_owner_changes() {
+        let mut session = Session::new();
+        assert!(!session.is_dirty());
+        let change = Event::Pid { channel: 1, parameter: PidParameter::Target, value: 300.0 };
+        assert_eq!(change.channel(), Some(1));
+        assert_eq!(Event::Fault(Fault::Adc).channel(), None);
+        session.push_change(change);
+        assert!(session.is_dirty());
+        assert_eq!(session.pop_change(), Some(change));
+        assert_eq!(session.pop_change(), None);
+    }
+
+    #[test]
+    fn report_channels() {
+        let sample = Sample {
+            time: 0,
+            raw: 0,
+            resistance: 1000.0,
+            temperature: 300.0,
+            pwm: None,
+            pid: None,
+        };
+        let mut session = Session::new();
+        session.feed(b"report mode on\n");
+        session.feed(b"report channels 1\n");
+        session.feed_report(0, &sample);
+        assert_eq!(session.is_report_pending(), None);
+        session.feed_report(1, &sample);
+        assert_eq!(session.is_report_pending(), Some((1, sample)));
+    }
+}
@@ -0,0 +1,93 @@
+// Settings persisted in EEPROM
+//
+// Each setting occupies one 16-word EEPROM block, starting with
+// a magic word that marks it as valid.
+
+use byteorder::{ByteOrder, LittleEndian};
+use crate::board::eeprom;
+use crate::command_parser::{DeviceName, DEVICE_NAME_LEN};
+use crate::boot;
+use crate::password::{Credentials, SALT_LEN};
+
+pub use eeprom::Error;
+
+const PASSWORD_BLOCK: u32 = 0;
+const PASSWORD_MAGIC: u32 = 0x746c_6173;
+/// Magic, salt and hash
+const PASSWORD_WORDS: usize = 1 + SALT_LEN / 4 + 8;
+const DEVICE_NAME_BLOCK: u32 = 1;
+const DEVICE_NAME_MAGIC: u32 = 0x656d_616e;
+/// Magic, length and the name
+const DEVICE_NAME_WORDS: usize = 2 + DEVICE_NAME_LEN / 4;
+const MODBUS_BLOCK: u32 = 3;
+const MODBUS_MAGIC: u32 = 0x6264_6f6d;
+
+/// Password for read-write access
+pub fn load_credentials() -> Credentials {
+    let mut words = [0u32; PASSWORD_WORDS];
+    eeprom::read(PASSWORD_BLOCK, 0, &mut words);
+    if words[0] != PASSWORD_MAGIC {
+        return Credentials::Unset;
+    }
+    let mut salt = [0u8; SALT_LEN];
+    let mut hash = [0u8; 32];
+    LittleEndian::write_u32_into(&words[1..1 + SALT_LEN / 4], &mut salt);
+    LittleEndian::write_u32_into(&words[1 + SALT_LEN / 4..], &mut hash);
+    Credentials::Set { salt, hash }
+}
+
+pub fn store_credentials(credentials: &Credentials) -> Result<(), Error> {
+    let mut words = [0u32; PASSWORD_WORDS];
+    if let Credentials::Set { salt, hash } = credentials {
+        words[0] = PASSWORD_MAGIC;
+        LittleEndian::read_u32_into(salt, &mut words[1..1 + SALT_LEN / 4]);
+        LittleEndian::read_u32_into(hash, &mut words[1 + SALT_LEN / 4..]);
+    }
+    eeprom::write(PASSWORD_BLOCK, 0, &words)
+}
+
+/// Name set with `name`, `None` if unset
+pub fn load_device_name() -> Option<DeviceName> {
+    let mut words = [0u32; DEVICE_NAME_WORDS];
+    eeprom::read(DEVICE_NAME_BLOCK, 0, &mut words);
+    if words[0] != DEVICE_NAME_MAGIC {
+        return None;
+    }
+    let mut buf = [0u8; DEVICE_NAME_LEN];
+    LittleEndian::write_u32_into(&words[2..], &mut buf);
+    let len = (words[1] as usize).min(DEVICE_NAME_LEN);
+    DeviceName::new(&buf[..len])
+}
+
+pub fn store_device_name(name: &DeviceName) -> Result<(), Error> {
+    let mut words = [0u32; DEVICE_NAME_WORDS];
+    words[0] = DEVICE_NAME_MAGIC;
+    words[1] = name.as_bytes().len() as u32;
+    let mut buf = [0u8; DEVICE_NAME_LEN];
+    buf[..name.as_bytes().len()].copy_from_slice(name.as_bytes());
+    LittleEndian::read_u32_into(&buf, &mut words[2..]);
+    eeprom::write(DEVICE_NAME_BLOCK, 0, &words)
+}
+
+/// Whether Modbus clients may write, off unless enabled with
+/// `modbus write on`
+pub fn load_modbus_writable() -> bool {
+    let mut words = [0u32; 2];
+    eeprom::read(MODBUS_BLOCK, 0, &mut words);
+    words[0] == MODBUS_MAGIC && words[1] != 0
+}
+
+pub fn store_modbus_writable(writable: bool) -> Result<(), Error> {
+    eeprom::write(MODBUS_BLOCK, 0, &[MODBUS_MAGIC, writable as u32])
+}
+
+/// Shared with the bootloader
+pub fn load_boot_state() -> boot::State {
+    let mut words = [0u32; boot::STATE_WORDS];
+    eeprom::read(boot::STATE_BLOCK, 0, &mut words);
+    boot::State::decode(&words)
+}
+
+pub fn store_boot_state(state: &boot::State) -> Result<(), Error> {
+    eeprom::write(boot::STATE_BLOCK, 0, &state.encode())
+}
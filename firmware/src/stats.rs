@@ -0,0 +1,199 @@
+use libm::F32Ext;
+
+/// Allan deviation is computed for averaging windows of 1, 2, 4, ...
+/// samples
+pub const ALLAN_OCTAVES: usize = 8;
+/// Phase values kept for the longest window, 2 * 2^(octaves - 1) + 1
+const PHASE_LEN: usize = (2 << (ALLAN_OCTAVES - 1)) + 1;
+
+/// Allan deviation for one averaging window
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AllanDeviation {
+    /// Window length in samples
+    pub samples: u32,
+    /// Window length, s
+    pub tau: f32,
+    pub deviation: f32,
+    /// Number of overlapping differences averaged
+    pub count: u64,
+}
+
+#[derive(Clone, Copy)]
+struct AllanSum {
+    sum: f64,
+    count: u64,
+}
+
+/// Running statistics of a channel's temperature
+///
+/// Mean and variance use Welford's algorithm. The overlapping Allan
+/// variance is accumulated from the phase, the running sum of the
+/// values, as `(x[n] - 2 x[n - m] + x[n - 2m])² / (2 m²)` for window
+/// lengths `m`.
+#[derive(Clone, Copy)]
+pub struct Stats {
+    count: u64,
+    mean: f64,
+    /// Sum of squared differences from the mean
+    m2: f64,
+    min: f32,
+    max: f32,
+    first_time: u64,
+    last_time: u64,
+    /// First value, subtracted from the phase to keep it small
+    offset: f32,
+    /// Ring of the latest phase values, `x[n]` at `n % PHASE_LEN`
+    phase: [f64; PHASE_LEN],
+    allan: [AllanSum; ALLAN_OCTAVES],
+}
+
+impl Stats {
+    pub const fn new() -> Self {
+        Stats {
+            count: 0,
+            mean: 0.0,
+            m2: 0.0,
+            min: 0.0,
+            max: 0.0,
+            first_time: 0,
+            last_time: 0,
+            offset: 0.0,
+            phase: [0.0; PHASE_LEN],
+            allan: [AllanSum { sum: 0.0, count: 0 }; ALLAN_OCTAVES],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Stats::new();
+    }
+
+    /// Add a value taken at `time` in µs
+    pub fn push(&mut self, time: u64, value: f32) {
+        if self.count == 0 {
+            self.first_time = time;
+            self.offset = value;
+            self.min = value;
+            self.max = value;
+        }
+        self.last_time = time;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+
+        self.count += 1;
+        let delta = f64::from(value) - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (f64::from(value) - self.mean);
+
+        // x[n] with n = count, x[0] = 0
+        let n = self.count as usize;
+        let x = self.phase[(n - 1) % PHASE_LEN] + f64::from(value - self.offset);
+        self.phase[n % PHASE_LEN] = x;
+        for (octave, allan) in self.allan.iter_mut().enumerate() {
+            let m = 1 << octave;
+            if n < 2 * m {
+                break;
+            }
+            let d = x
+                - 2.0 * self.phase[(n - m) % PHASE_LEN]
+                + self.phase[(n - 2 * m) % PHASE_LEN];
+            allan.sum += d * d;
+            allan.count += 1;
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Time from the first to the last value, s
+    pub fn duration(&self) -> f32 {
+        (self.last_time - self.first_time) as f32 / 1_000_000.0
+    }
+
+    pub fn mean(&self) -> Option<f32> {
+        if self.count > 0 {
+            Some(self.mean as f32)
+        } else {
+            None
+        }
+    }
+
+    /// Standard deviation, RMS noise around the mean
+    pub fn std_dev(&self) -> Option<f32> {
+        if self.count > 1 {
+            Some(((self.m2 / (self.count - 1) as f64) as f32).sqrt())
+        } else {
+            None
+        }
+    }
+
+    pub fn min(&self) -> Option<f32> {
+        if self.count > 0 { Some(self.min) } else { None }
+    }
+
+    pub fn max(&self) -> Option<f32> {
+        if self.count > 0 { Some(self.max) } else { None }
+    }
+
+    pub fn peak_to_peak(&self) -> Option<f32> {
+        if self.count > 0 { Some(self.max - self.min) } else { None }
+    }
+
+    /// Allan deviations of the window lengths with enough values
+    pub fn allan_deviations<'a>(&'a self) -> impl Iterator<Item = AllanDeviation> + 'a {
+        // Mean sample interval
+        let interval = if self.count > 1 {
+            self.duration() / (self.count - 1) as f32
+        } else {
+            0.0
+        };
+        self.allan.iter()
+            .enumerate()
+            .take_while(|(_, allan)| allan.count > 0)
+            .map(move |(octave, allan)| {
+                let m = 1u32 << octave;
+                let variance = allan.sum / (2.0 * f64::from(m * m) * allan.count as f64);
+                AllanDeviation {
+                    samples: m,
+                    tau: m as f32 * interval,
+                    deviation: (variance as f32).sqrt(),
+                    count: allan.count,
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mean_std_dev() {
+        let mut stats = Stats::new();
+        for (i, value) in [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0].iter().enumerate() {
+            stats.push(i as u64 * 1000, *value);
+        }
+        assert_eq!(stats.mean(), Some(5.0));
+        assert!((stats.std_dev().unwrap() - 2.138).abs() < 0.001);
+        assert_eq!(stats.peak_to_peak(), Some(7.0));
+    }
+
+    #[test]
+    fn allan_alternating() {
+        // ±1 alternating: windows of even length average to 0
+        let mut stats = Stats::new();
+        for i in 0..100 {
+            stats.push(i * 100_000, if i % 2 == 0 { 1.0 } else { -1.0 });
+        }
+        let mut adevs = stats.allan_deviations();
+        let adev = adevs.next().unwrap();
+        assert_eq!(adev.samples, 1);
+        assert!((adev.tau - 0.1).abs() < 0.0001);
+        // Consecutive values differ by 2: sqrt(4 / 2)
+        assert!((adev.deviation - 2.0f32.sqrt()).abs() < 0.0001);
+        assert_eq!(adev.count, 99);
+        let adev = adevs.next().unwrap();
+        assert_eq!(adev.samples, 2);
+        assert!(adev.deviation < 0.0001);
+    }
+}
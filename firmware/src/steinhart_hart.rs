@@ -1,7 +1,7 @@
 use libm::F32Ext;
 
 /// Steinhart-Hart equation parameters
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct Parameters {
     pub a: f32,
     pub b: f32,
@@ -14,13 +14,18 @@ pub struct Parameters {
 }
 
 impl Parameters {
+    /// Result unit: Ohm
+    pub fn get_resistance(&self, voltage: f32) -> f32 {
+        self.parallel_r * voltage
+    }
+
     /// Perform the voltage to temperature conversion.
     ///
     /// Result unit: Kelvin
     ///
     /// TODO: verify
     pub fn get_temperature(&self, voltage: f32) -> f32 {
-        let r = self.parallel_r * voltage;
+        let r = self.get_resistance(voltage);
         let ln_r = r.abs().ln();
         let inv_temp = self.a +
             self.b * ln_r +
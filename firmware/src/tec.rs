@@ -1,7 +1,7 @@
 use core::fmt;
 use crate::board::pwm::{self, PwmChannel, PwmPeripheral};
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum TecPin {
     ISet,
     MaxIPos,
@@ -66,8 +66,17 @@ impl<MaxIPos: PwmChannel, MaxINeg: PwmChannel, ISet: PwmChannel, MaxV: PwmChanne
         self.max_v.set(max, max);
         self
     }
+}
+
+/// Access to a `Tec` regardless of the timers behind its PWM
+/// channels so that TECs can be looked up by index
+pub trait TecControl {
+    fn get(&mut self, pin: TecPin) -> (u16, u16);
+    fn set(&mut self, pin: TecPin, width: u16, total: u16);
+}
 
-    pub fn get(&mut self, pin: TecPin) -> (u16, u16) {
+impl<MaxIPos: PwmChannel, MaxINeg: PwmChannel, ISet: PwmChannel, MaxV: PwmChannel> TecControl for Tec<MaxIPos, MaxINeg, ISet, MaxV> {
+    fn get(&mut self, pin: TecPin) -> (u16, u16) {
         match pin {
             TecPin::MaxIPos =>
                 self.max_i_pos.get(),
@@ -80,7 +89,7 @@ impl<MaxIPos: PwmChannel, MaxINeg: PwmChannel, ISet: PwmChannel, MaxV: PwmChanne
         }
     }
 
-    pub fn set(&mut self, pin: TecPin, width: u16, total: u16) {
+    fn set(&mut self, pin: TecPin, width: u16, total: u16) {
         match pin {
             TecPin::MaxIPos =>
                 self.max_i_pos.set(width, total),
@@ -0,0 +1,126 @@
+// Binary telemetry over UDP
+//
+// Packets start with an 8-byte header: magic `TP`, format version,
+// number of records and a sequence number (u32, little-endian) that
+// increments with every packet, so that receivers can detect loss.
+// Each record is the channel number (u8) followed by a `history`
+// record, 21 bytes in all.
+
+use byteorder::{ByteOrder, LittleEndian};
+use super::command_parser::Endpoint;
+use super::history::{History, RECORD_SIZE};
+use super::CHANNELS;
+
+const MAGIC: &[u8; 2] = b"TP";
+pub const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 8;
+const TELEMETRY_RECORD_SIZE: usize = 1 + RECORD_SIZE;
+/// Records per packet, keeps packets below the Ethernet MTU
+const MAX_RECORDS: usize = 64;
+pub const PACKET_SIZE: usize = HEADER_SIZE + MAX_RECORDS * TELEMETRY_RECORD_SIZE;
+
+pub struct Telemetry {
+    destination: Option<Endpoint>,
+    /// Sequence number of the next history record to send, per channel
+    next: [u64; CHANNELS],
+    /// `next` once the last filled packet has been sent
+    pending: [u64; CHANNELS],
+    /// Sequence number of the next packet
+    seq: u32,
+}
+
+impl Telemetry {
+    pub fn new() -> Self {
+        Telemetry {
+            destination: None,
+            next: [0; CHANNELS],
+            pending: [0; CHANNELS],
+            seq: 0,
+        }
+    }
+
+    pub fn destination(&self) -> Option<Endpoint> {
+        self.destination
+    }
+
+    /// Start streaming with the next sample, or stop
+    pub fn set_destination(&mut self, destination: Option<Endpoint>, histories: &[History]) {
+        self.destination = destination;
+        for (next, history) in self.next.iter_mut().zip(histories) {
+            *next = history.next();
+        }
+    }
+
+    /// Fill `packet` with records that have not been sent yet,
+    /// returns the length or 0 if there is nothing to send. The
+    /// records count as sent only after `sent()`, so that a packet
+    /// that does not fit into the socket buffer is filled again on
+    /// the next poll.
+    pub fn fill_packet(&mut self, histories: &[History], packet: &mut [u8; PACKET_SIZE]) -> usize {
+        let mut count = 0;
+        self.pending = self.next;
+        for (channel, (next, history)) in self.pending.iter_mut().zip(histories).enumerate() {
+            // Skip records that have been overwritten already
+            *next = (*next).max(history.oldest());
+            while count < MAX_RECORDS {
+                let record = match history.get(*next) {
+                    Some(record) => record,
+                    None => break,
+                };
+                let offset = HEADER_SIZE + count * TELEMETRY_RECORD_SIZE;
+                packet[offset] = channel as u8;
+                packet[offset + 1..offset + TELEMETRY_RECORD_SIZE].copy_from_slice(&record.to_bytes());
+                *next += 1;
+                count += 1;
+            }
+        }
+        if count == 0 {
+            return 0;
+        }
+
+        packet[0..2].copy_from_slice(MAGIC);
+        packet[2] = VERSION;
+        packet[3] = count as u8;
+        LittleEndian::write_u32(&mut packet[4..8], self.seq);
+        HEADER_SIZE + count * TELEMETRY_RECORD_SIZE
+    }
+
+    /// The packet filled last has been sent
+    pub fn sent(&mut self) {
+        self.next = self.pending;
+        self.seq = self.seq.wrapping_add(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::history::Record;
+
+    #[test]
+    fn packets() {
+        let mut histories = [History::new(); CHANNELS];
+        let mut telemetry = Telemetry::new();
+        let endpoint = Endpoint { addr: [10, 0, 0, 1], port: 2300 };
+        let first = Record { time: 1, raw: 0, temperature: 0.0, pwm: 0, flags: 0 };
+        histories[0].push(first);
+        telemetry.set_destination(Some(endpoint), &histories);
+        histories[0].push(Record { time: 2, ..first });
+        histories[1].push(Record { time: 3, ..first });
+
+        let mut packet = [0; PACKET_SIZE];
+        let len = telemetry.fill_packet(&histories, &mut packet);
+        assert_eq!(len, HEADER_SIZE + 2 * TELEMETRY_RECORD_SIZE);
+        assert_eq!(&packet[0..4], b"TP\x01\x02");
+        assert_eq!(LittleEndian::read_u32(&packet[4..8]), 0);
+        assert_eq!(packet[HEADER_SIZE], 0);
+        assert_eq!(LittleEndian::read_u64(&packet[HEADER_SIZE + 1..HEADER_SIZE + 9]), 2);
+        assert_eq!(packet[HEADER_SIZE + TELEMETRY_RECORD_SIZE], 1);
+
+        // Not sent, the same records again
+        assert_eq!(telemetry.fill_packet(&histories, &mut packet), len);
+        assert_eq!(LittleEndian::read_u32(&packet[4..8]), 0);
+        telemetry.sent();
+        assert_eq!(telemetry.fill_packet(&histories, &mut packet), 0);
+    }
+}
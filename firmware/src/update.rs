@@ -0,0 +1,376 @@
+// Firmware upload into slot B
+//
+// A client connects to `PORT` and sends a 44-byte header: magic
+// `TPUP`, image length and CRC-32 (u32, little-endian) and the
+// password, zero-padded to 32 bytes. The image follows, as linked for
+// slot A. The device answers with one line, `OK` or `ERROR <reason>`,
+// and restarts into the bootloader after `OK`.
+//
+// The new image then runs on trial and confirms itself once it has
+// shown that it works, see `TrialCheck`.
+
+use core::fmt;
+use byteorder::{ByteOrder, LittleEndian};
+use super::boot::{self, SECTOR_SIZE, SLOT_SIZE};
+use super::command_parser::PASSWORD_LEN;
+use super::password::Credentials;
+
+pub const PORT: u16 = 2400;
+const MAGIC: &[u8; 4] = b"TPUP";
+pub const HEADER_LEN: usize = 12 + PASSWORD_LEN;
+/// Running time before a healthy trial image confirms itself, µs
+const CONFIRM_DELAY: u64 = 10_000_000;
+/// Running time after which a trial image that has not been
+/// confirmed is rolled back, µs
+const TRIAL_DEADLINE: u64 = 120_000_000;
+
+/// Slot B, addressed by offset
+pub trait Slot {
+    type Error: fmt::Debug;
+    fn erase_sector(&mut self, offset: u32) -> Result<(), Self::Error>;
+    fn program(&mut self, offset: u32, word: u32) -> Result<(), Self::Error>;
+    fn contents(&self) -> &[u8];
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error<E> {
+    BadHeader,
+    Unauthorized,
+    TooLarge(u32),
+    Flash(E),
+    /// Expected, actual
+    Crc(u32, u32),
+    /// Not linked for slot A
+    NotAnImage,
+}
+
+impl<E: fmt::Debug> fmt::Display for Error<E> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::BadHeader =>
+                write!(fmt, "bad header"),
+            Error::Unauthorized =>
+                write!(fmt, "wrong password"),
+            Error::TooLarge(length) =>
+                write!(fmt, "image of {} bytes, slot holds {}", length, SLOT_SIZE),
+            Error::Flash(e) =>
+                write!(fmt, "flash: {:?}", e),
+            Error::Crc(expected, actual) =>
+                write!(fmt, "CRC {:08x}, expected {:08x}", actual, expected),
+            Error::NotAnImage =>
+                write!(fmt, "not an image for slot A"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Progress {
+    Receiving,
+    /// Written and verified
+    Complete { length: u32, crc: u32 },
+}
+
+pub struct Upload {
+    header: [u8; HEADER_LEN],
+    header_len: usize,
+    length: u32,
+    crc: u32,
+    /// Bytes of the image written to the slot
+    written: u32,
+    /// Partial word
+    word: [u8; 4],
+    word_len: usize,
+}
+
+impl Upload {
+    pub fn new() -> Self {
+        Upload {
+            header: [0; HEADER_LEN],
+            header_len: 0,
+            length: 0,
+            crc: 0,
+            written: 0,
+            word: [0; 4],
+            word_len: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Upload::new();
+    }
+
+    /// Bytes of the image received
+    pub fn received(&self) -> u32 {
+        self.written + self.word_len as u32
+    }
+
+    /// Process received data. The password is checked against
+    /// `credentials` when one is set.
+    pub fn receive<S: Slot>(
+        &mut self, data: &[u8], credentials: &Credentials, slot: &mut S
+    ) -> Result<Progress, Error<S::Error>> {
+        let mut data = data;
+        if self.header_len < HEADER_LEN {
+            let len = data.len().min(HEADER_LEN - self.header_len);
+            self.header[self.header_len..self.header_len + len].copy_from_slice(&data[..len]);
+            self.header_len += len;
+            data = &data[len..];
+            if self.header_len < HEADER_LEN {
+                return Ok(Progress::Receiving);
+            }
+            self.parse_header(credentials)?;
+        }
+
+        let remaining = (self.length - self.received()) as usize;
+        for byte in &data[..data.len().min(remaining)] {
+            self.word[self.word_len] = *byte;
+            self.word_len += 1;
+            if self.word_len == 4 {
+                self.program_word(slot)?;
+            }
+        }
+        if self.received() < self.length {
+            return Ok(Progress::Receiving);
+        }
+        if self.word_len > 0 {
+            // Pad the last word
+            for byte in self.word[self.word_len..].iter_mut() {
+                *byte = 0xff;
+            }
+            self.program_word(slot)?;
+        }
+        self.verify(slot)
+    }
+
+    fn parse_header<E>(&mut self, credentials: &Credentials) -> Result<(), Error<E>> {
+        if &self.header[..4] != MAGIC {
+            return Err(Error::BadHeader);
+        }
+        self.length = LittleEndian::read_u32(&self.header[4..]);
+        self.crc = LittleEndian::read_u32(&self.header[8..]);
+        if self.length > SLOT_SIZE {
+            return Err(Error::TooLarge(self.length));
+        }
+        if self.length < 8 {
+            return Err(Error::NotAnImage);
+        }
+        let password = &self.header[12..];
+        let password_len = password.iter().position(|c| *c == 0)
+            .unwrap_or(PASSWORD_LEN);
+        if credentials.permits(&password[..password_len]) {
+            Ok(())
+        } else {
+            Err(Error::Unauthorized)
+        }
+    }
+
+    fn program_word<S: Slot>(&mut self, slot: &mut S) -> Result<(), Error<S::Error>> {
+        let offset = self.written;
+        if offset % SECTOR_SIZE == 0 {
+            slot.erase_sector(offset).map_err(Error::Flash)?;
+        }
+        slot.program(offset, LittleEndian::read_u32(&self.word))
+            .map_err(Error::Flash)?;
+        self.written += 4;
+        self.word_len = 0;
+        Ok(())
+    }
+
+    fn verify<S: Slot>(&self, slot: &S) -> Result<Progress, Error<S::Error>> {
+        let image = &slot.contents()[..self.length as usize];
+        let crc = boot::crc32(image);
+        if crc != self.crc {
+            Err(Error::Crc(self.crc, crc))
+        } else if !boot::is_image(image) {
+            Err(Error::NotAnImage)
+        } else {
+            Ok(Progress::Complete { length: self.length, crc })
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    Wait,
+    Confirm,
+    /// Reset, so that the bootloader swaps the previous image back
+    RollBack,
+}
+
+/// Health check of an image on trial
+///
+/// The image is healthy once every channel has delivered an
+/// error-free conversion since the last ADC error and a network
+/// client has been served. It confirms itself when healthy after
+/// `CONFIRM_DELAY`, and is rolled back at `TRIAL_DEADLINE` unless
+/// confirmed by then, by itself or with the `confirm` command.
+pub struct TrialCheck {
+    channels: usize,
+    /// Bit per channel with an error-free conversion
+    converted: u32,
+    served: bool,
+}
+
+impl TrialCheck {
+    pub fn new(channels: usize) -> Self {
+        TrialCheck { channels, converted: 0, served: false }
+    }
+
+    pub fn conversion(&mut self, channel: usize, ok: bool) {
+        if ok {
+            self.converted |= 1 << channel;
+        } else {
+            self.converted = 0;
+        }
+    }
+
+    pub fn adc_error(&mut self) {
+        self.converted = 0;
+    }
+
+    /// A TCP client has been connected
+    pub fn served(&mut self) {
+        self.served = true;
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.converted == (1 << self.channels) - 1 && self.served
+    }
+
+    pub fn poll(&self, now: u64) -> Verdict {
+        if now >= CONFIRM_DELAY && self.is_healthy() {
+            Verdict::Confirm
+        } else if now >= TRIAL_DEADLINE {
+            Verdict::RollBack
+        } else {
+            Verdict::Wait
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::boot::SLOT_A;
+    use super::super::password::SALT_LEN;
+
+    struct RamSlot {
+        data: [u8; 2 * SECTOR_SIZE as usize],
+        erased: u32,
+    }
+
+    impl RamSlot {
+        fn new() -> Self {
+            RamSlot { data: [0; 2 * SECTOR_SIZE as usize], erased: 0 }
+        }
+    }
+
+    impl Slot for RamSlot {
+        type Error = ();
+
+        fn erase_sector(&mut self, offset: u32) -> Result<(), ()> {
+            let offset = offset as usize;
+            for byte in self.data[offset..offset + SECTOR_SIZE as usize].iter_mut() {
+                *byte = 0xff;
+            }
+            self.erased += 1;
+            Ok(())
+        }
+
+        fn program(&mut self, offset: u32, word: u32) -> Result<(), ()> {
+            LittleEndian::write_u32(&mut self.data[offset as usize..], word);
+            Ok(())
+        }
+
+        fn contents(&self) -> &[u8] {
+            &self.data
+        }
+    }
+
+    fn image() -> [u8; 0x4002] {
+        let mut image = [0x5a; 0x4002];
+        LittleEndian::write_u32(&mut image[0..], 0x2004_0000);
+        LittleEndian::write_u32(&mut image[4..], SLOT_A + 0x401);
+        image
+    }
+
+    fn header(image: &[u8], password: &[u8]) -> [u8; HEADER_LEN] {
+        let mut header = [0; HEADER_LEN];
+        header[..4].copy_from_slice(MAGIC);
+        LittleEndian::write_u32(&mut header[4..], image.len() as u32);
+        LittleEndian::write_u32(&mut header[8..], boot::crc32(image));
+        header[12..12 + password.len()].copy_from_slice(password);
+        header
+    }
+
+    #[test]
+    fn upload() {
+        let image = image();
+        let header = header(&image, b"");
+        let mut upload = Upload::new();
+        let mut slot = RamSlot::new();
+        assert_eq!(upload.receive(&header[..10], &Credentials::Unset, &mut slot), Ok(Progress::Receiving));
+        assert_eq!(upload.receive(&header[10..], &Credentials::Unset, &mut slot), Ok(Progress::Receiving));
+        // Chunks not aligned to words
+        for chunk in image[..0x4001].chunks(1023) {
+            assert_eq!(upload.receive(chunk, &Credentials::Unset, &mut slot), Ok(Progress::Receiving));
+        }
+        let progress = upload.receive(&image[0x4001..], &Credentials::Unset, &mut slot);
+        assert_eq!(progress, Ok(Progress::Complete { length: image.len() as u32, crc: boot::crc32(&image) }));
+        assert_eq!(slot.erased, 2);
+        assert_eq!(&slot.data[..image.len()], &image[..]);
+    }
+
+    #[test]
+    fn corrupt_upload() {
+        let image = image();
+        let header = header(&image, b"");
+        let mut upload = Upload::new();
+        let mut slot = RamSlot::new();
+        upload.receive(&header, &Credentials::Unset, &mut slot).unwrap();
+        upload.receive(&image[..100], &Credentials::Unset, &mut slot).unwrap();
+        let result = upload.receive(&[0; 0x4002 - 100], &Credentials::Unset, &mut slot);
+        match result {
+            Err(Error::Crc(..)) => {}
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn password() {
+        let image = image();
+        let credentials = Credentials::new(b"secret", [0; SALT_LEN]);
+        let mut upload = Upload::new();
+        let mut slot = RamSlot::new();
+        assert_eq!(upload.receive(&header(&image, b"wrong"), &credentials, &mut slot), Err(Error::Unauthorized));
+        upload.reset();
+        assert_eq!(upload.receive(&header(&image, b"secret"), &credentials, &mut slot), Ok(Progress::Receiving));
+    }
+
+    #[test]
+    fn bad_headers() {
+        let image = image();
+        let mut header = header(&image, b"");
+        let mut slot = RamSlot::new();
+        LittleEndian::write_u32(&mut header[4..], SLOT_SIZE + 4);
+        assert_eq!(Upload::new().receive(&header, &Credentials::Unset, &mut slot), Err(Error::TooLarge(SLOT_SIZE + 4)));
+        header[0] = b'X';
+        assert_eq!(Upload::new().receive(&header, &Credentials::Unset, &mut slot), Err(Error::BadHeader));
+    }
+
+    #[test]
+    fn trial() {
+        let mut check = TrialCheck::new(2);
+        check.conversion(0, true);
+        check.served();
+        assert_eq!(check.poll(CONFIRM_DELAY), Verdict::Wait);
+        check.conversion(1, true);
+        assert_eq!(check.poll(CONFIRM_DELAY - 1), Verdict::Wait);
+        assert_eq!(check.poll(CONFIRM_DELAY), Verdict::Confirm);
+
+        check.adc_error();
+        check.conversion(1, true);
+        assert!(!check.is_healthy());
+        assert_eq!(check.poll(TRIAL_DEADLINE), Verdict::RollBack);
+    }
+}
@@ -0,0 +1,163 @@
+use core::fmt;
+use crate::command_parser::{PwmSetup, PwmMode};
+use crate::pid;
+use crate::steinhart_hart as sh;
+
+/// PID output is a PWM width
+const OUTPUT_MAX: f32 = 65535.0;
+/// Highest PID target, K
+const TARGET_MAX: f32 = 400.0;
+
+/// Rejected setting, with a code that stays stable for scripts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValidationError {
+    NotFinite = 1,
+    PwmTotalZero = 2,
+    PwmWidthAboveTotal = 3,
+    OutputOutOfRange = 4,
+    OutputLimits = 5,
+    IntegralLimits = 6,
+    TargetOutOfRange = 7,
+    ResistanceNotPositive = 8,
+    RateNotPositive = 9,
+}
+
+impl ValidationError {
+    pub fn code(&self) -> u8 {
+        *self as u8
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let message = match self {
+            ValidationError::NotFinite =>
+                "value must be a finite number",
+            ValidationError::PwmTotalZero =>
+                "PWM total must not be 0",
+            ValidationError::PwmWidthAboveTotal =>
+                "PWM width must not exceed total",
+            ValidationError::OutputOutOfRange =>
+                "output limits must be within 0-65535",
+            ValidationError::OutputLimits =>
+                "output_min must not exceed output_max",
+            ValidationError::IntegralLimits =>
+                "integral_min must not exceed integral_max",
+            ValidationError::TargetOutOfRange =>
+                "target must be within 0-400 K",
+            ValidationError::ResistanceNotPositive =>
+                "parallel_r must be positive",
+            ValidationError::RateNotPositive =>
+                "rate must be positive",
+        };
+        write!(fmt, "error {}: {}", self.code(), message)
+    }
+}
+
+fn finite(values: &[f32]) -> Result<(), ValidationError> {
+    if values.iter().all(|value| value.is_finite()) {
+        Ok(())
+    } else {
+        Err(ValidationError::NotFinite)
+    }
+}
+
+pub fn check_pwm(setup: &PwmSetup) -> Result<(), ValidationError> {
+    let config = match setup {
+        PwmSetup::ISet(PwmMode::Pid) =>
+            return Ok(()),
+        PwmSetup::ISet(PwmMode::Manual(config)) |
+        PwmSetup::MaxIPos(config) |
+        PwmSetup::MaxINeg(config) |
+        PwmSetup::MaxV(config) =>
+            config,
+    };
+    if config.total == 0 {
+        Err(ValidationError::PwmTotalZero)
+    } else if config.width > config.total {
+        Err(ValidationError::PwmWidthAboveTotal)
+    } else {
+        Ok(())
+    }
+}
+
+/// Check a controller with changed parameters before it replaces the
+/// one in use
+pub fn check_pid(pid: &pid::Controller) -> Result<(), ValidationError> {
+    let target = pid.get_target();
+    let p = pid.get_parameters();
+    finite(&[
+        target, p.kp, p.ki, p.kd,
+        p.output_min, p.output_max, p.integral_min, p.integral_max,
+    ])?;
+    if !(0.0..=TARGET_MAX).contains(&target) {
+        Err(ValidationError::TargetOutOfRange)
+    } else if !(0.0..=OUTPUT_MAX).contains(&p.output_min) ||
+              !(0.0..=OUTPUT_MAX).contains(&p.output_max) {
+        Err(ValidationError::OutputOutOfRange)
+    } else if p.output_min > p.output_max {
+        Err(ValidationError::OutputLimits)
+    } else if p.integral_min > p.integral_max {
+        Err(ValidationError::IntegralLimits)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn check_sh(sh: &sh::Parameters) -> Result<(), ValidationError> {
+    finite(&[sh.a, sh.b, sh.c, sh.parallel_r])?;
+    if sh.parallel_r <= 0.0 {
+        Err(ValidationError::ResistanceNotPositive)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn check_postfilter_rate(rate: f32) -> Result<(), ValidationError> {
+    finite(&[rate])?;
+    if rate <= 0.0 {
+        Err(ValidationError::RateNotPositive)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::command_parser::PwmConfig;
+
+    const PARAMETERS: pid::Parameters = pid::Parameters {
+        kp: 1.0,
+        ki: 0.1,
+        kd: 0.0,
+        output_min: 0.0,
+        output_max: 65535.0,
+        integral_min: 0.0,
+        integral_max: 65535.0,
+    };
+
+    #[test]
+    fn pwm_width_above_total() {
+        let setup = PwmSetup::MaxV(PwmConfig { width: 3, total: 2 });
+        assert_eq!(check_pwm(&setup), Err(ValidationError::PwmWidthAboveTotal));
+    }
+
+    #[test]
+    fn pid_output_limits() {
+        let mut pid = pid::Controller::new(PARAMETERS);
+        assert_eq!(check_pid(&pid), Ok(()));
+        pid.update_parameters(|parameters| parameters.output_min = 70000.0);
+        assert_eq!(check_pid(&pid), Err(ValidationError::OutputOutOfRange));
+        pid.update_parameters(|parameters| parameters.output_min = 100.0);
+        pid.update_parameters(|parameters| parameters.output_max = 10.0);
+        assert_eq!(check_pid(&pid), Err(ValidationError::OutputLimits));
+    }
+
+    #[test]
+    fn pid_not_finite() {
+        let mut pid = pid::Controller::new(PARAMETERS);
+        pid.set_target(core::f32::NAN);
+        assert_eq!(check_pid(&pid), Err(ValidationError::NotFinite));
+    }
+}
@@ -0,0 +1,102 @@
+#!/usr/bin/env python3
+"""Upload a firmware image over Ethernet.
+
+The image is the raw binary of the firmware ELF:
+
+    arm-none-eabi-objcopy -O binary \\
+        target/thumbv7em-none-eabihf/release/firmware firmware.bin
+    ./update.py thermostat-lab1.local firmware.bin [password]
+
+After the upload the device restarts into the new image on trial,
+which is confirmed over telnet unless it has confirmed itself. An
+image that is not confirmed is rolled back.
+"""
+
+import socket
+import struct
+import sys
+import time
+import zlib
+
+PORT = 2400
+TELNET_PORT = 23
+PASSWORD_LEN = 32
+# Restart and swapping the slots
+CONFIRM_TIMEOUT = 60
+
+
+def upload(host, image, password=b""):
+    if len(password) > PASSWORD_LEN:
+        raise ValueError("password longer than {} bytes".format(PASSWORD_LEN))
+    header = b"TPUP" + struct.pack("<II", len(image), zlib.crc32(image))
+    header += password.ljust(PASSWORD_LEN, b"\0")
+    with socket.create_connection((host, PORT)) as connection:
+        connection.sendall(header + image)
+        return connection.makefile().readline().strip()
+
+
+def command(host, lines):
+    """Send lines over telnet, returns the response lines"""
+    with socket.create_connection((host, TELNET_PORT), timeout=5) as connection:
+        connection.sendall(b"".join(line + b"\n" for line in lines + [b"quit"]))
+        response = b""
+        while True:
+            data = connection.recv(1024)
+            if not data:
+                break
+            response += data
+    # Drop telnet negotiation
+    response = bytes(c for c in response if 0x20 <= c < 0x7f or c == 0x0a)
+    return [line.strip() for line in response.decode().splitlines()]
+
+
+def boot_state(host, lines):
+    """`firmware=` of `show update`, None without an answer"""
+    response = command(host, lines + [b"show update"])
+    for line in response:
+        if line.startswith("firmware="):
+            return line[len("firmware="):]
+    return None
+
+
+def confirm(host, password=b""):
+    """Wait for the new image and confirm it, returns its boot state"""
+    lines = [b"auth " + password if password else b"auth"]
+    deadline = time.monotonic() + CONFIRM_TIMEOUT
+    while time.monotonic() < deadline:
+        try:
+            state = boot_state(host, lines)
+            # The previous image, about to restart
+            if state is None or state.startswith("pending"):
+                pass
+            # Not healthy yet, or nobody connected before
+            elif state == "trial":
+                if "Firmware confirmed" in command(host, lines + [b"confirm"]):
+                    return "confirmed"
+            # Confirmed by itself, or rolled back
+            else:
+                return state
+        except OSError:
+            pass
+        time.sleep(1)
+    return "no answer"
+
+
+def main():
+    if len(sys.argv) not in (3, 4):
+        print(__doc__, file=sys.stderr)
+        sys.exit(2)
+    with open(sys.argv[2], "rb") as f:
+        image = f.read()
+    password = sys.argv[3].encode() if len(sys.argv) == 4 else b""
+    response = upload(sys.argv[1], image, password)
+    print(response)
+    if response != "OK":
+        sys.exit(1)
+    state = confirm(sys.argv[1], password)
+    print("firmware={}".format(state))
+    sys.exit(0 if state == "confirmed" else 1)
+
+
+if __name__ == "__main__":
+    main()